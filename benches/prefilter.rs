@@ -0,0 +1,87 @@
+//! Compares checking each rule's regex on its own against one pass of a `RegexSet`, and times
+//! `MessageHandler::handle_msg`, for configs with dozens of rules. Run with `cargo bench`.
+
+use std::{hint::black_box, sync::Arc, time::Instant};
+
+use async_trait::async_trait;
+use irc::client::prelude::Message;
+use irc_hook::{
+    message_handler::{MatchTarget, MessageHandler, SearchPattern},
+    publisher::Publisher,
+};
+use regex::{Regex, RegexSetBuilder};
+
+const ITERATIONS: u32 = 20_000;
+
+struct NullPublisher;
+
+#[async_trait]
+impl Publisher for NullPublisher {
+    async fn publish(
+        &self,
+        _channel: &str,
+        _groups: Vec<Vec<String>>,
+        _vars: &[(&str, String)],
+        _matched_at: Instant,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Rules shaped like a typical alerting config: a keyword followed by some captures.
+fn rules(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|i| format!(r"(?i)\bservice-{}\b.*(error|fail(ed|ure)?): (\w+)", i))
+        .collect()
+}
+
+fn time(label: &str, mut f: impl FnMut()) {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let per_iter = start.elapsed() / ITERATIONS;
+    println!("{:<40} {:>10?}/msg", label, per_iter);
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    // Most lines in a busy channel match nothing.
+    let content = "deploy of build 4821 finished in 42s, nothing to report";
+
+    for count in [10, 50, 100] {
+        println!("{} rules", count);
+        let rules = rules(count);
+
+        let regexes: Vec<Regex> = rules.iter().map(|r| Regex::new(r).unwrap()).collect();
+        time("  each regex", || {
+            black_box(regexes.iter().filter(|re| re.is_match(content)).count());
+        });
+
+        // Sized the way the handler sizes its prefilter.
+        let set = RegexSetBuilder::new(&rules)
+            .dfa_size_limit((count * 256 * 1024).max(2 << 20))
+            .build()
+            .unwrap();
+        time("  regex set", || {
+            black_box(set.matches(content).iter().count());
+        });
+
+        let search_patterns: Vec<_> = rules.iter().map(SearchPattern::new).collect();
+        let handler = MessageHandler::new(
+            &search_patterns,
+            Arc::new(NullPublisher),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+        let msg = Message::new(Some("ci"), "PRIVMSG", vec!["#ops", content]).unwrap();
+        time("  handle_msg", || {
+            runtime.block_on(handler.handle_msg(msg.clone()));
+        });
+    }
+}
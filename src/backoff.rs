@@ -0,0 +1,175 @@
+use rand::Rng;
+use std::{str::FromStr, time::Duration};
+
+/// Produces the delays between successive retries of one operation.
+pub trait Backoff: Send {
+    /// The delay before the next retry.
+    fn next_delay(&mut self) -> Duration;
+}
+
+/// How the delay between retries grows with the attempt number.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RetryStrategy {
+    /// `base * 2^attempt`
+    #[default]
+    Exponential,
+    /// A random delay between zero and `base * 2^attempt` ("full jitter").
+    ExponentialJitter,
+    /// `base * attempt`
+    Linear,
+    /// `base`
+    Constant,
+    /// A random delay between `base` and three times the previous delay, which spreads out
+    /// clients that started retrying together.
+    DecorrelatedJitter,
+}
+
+impl FromStr for RetryStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exponential" => Ok(RetryStrategy::Exponential),
+            "exponential_jitter" => Ok(RetryStrategy::ExponentialJitter),
+            "linear" => Ok(RetryStrategy::Linear),
+            "constant" | "fixed" => Ok(RetryStrategy::Constant),
+            "decorrelated_jitter" => Ok(RetryStrategy::DecorrelatedJitter),
+            other => Err(format!("unknown retry strategy: {}", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryConfig {
+    /// Retries after the first attempt; 0 disables retrying.
+    pub max_attempts: u32,
+    pub strategy: RetryStrategy,
+    pub base: Duration,
+    pub max_delay: Duration,
+    /// Randomizes each delay to between half and all of its computed value. The strategies that
+    /// are already random ignore this.
+    pub jitter: bool,
+}
+
+impl RetryConfig {
+    /// A backoff for retrying one operation, starting from the first retry.
+    pub fn backoff(&self) -> Box<dyn Backoff> {
+        Box::new(StrategyBackoff {
+            config: self.clone(),
+            attempt: 0,
+            previous: self.base,
+        })
+    }
+}
+
+struct StrategyBackoff {
+    config: RetryConfig,
+    attempt: u32,
+    previous: Duration,
+}
+
+impl Backoff for StrategyBackoff {
+    fn next_delay(&mut self) -> Duration {
+        self.attempt += 1;
+        let RetryConfig {
+            base, max_delay, ..
+        } = self.config;
+        let exponential = base.saturating_mul(2u32.saturating_pow(self.attempt));
+
+        let delay = match self.config.strategy {
+            RetryStrategy::Exponential => exponential,
+            RetryStrategy::ExponentialJitter => random_between(Duration::ZERO, exponential),
+            RetryStrategy::Linear => base.saturating_mul(self.attempt),
+            RetryStrategy::Constant => base,
+            RetryStrategy::DecorrelatedJitter => {
+                random_between(base, self.previous.saturating_mul(3))
+            }
+        }
+        .min(max_delay);
+        self.previous = delay;
+
+        match self.config.strategy {
+            RetryStrategy::Exponential | RetryStrategy::Linear | RetryStrategy::Constant
+                if self.config.jitter =>
+            {
+                delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+            }
+            _ => delay,
+        }
+    }
+}
+
+fn random_between(low: Duration, high: Duration) -> Duration {
+    if high <= low {
+        return low;
+    }
+    rand::thread_rng().gen_range(low..=high)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retry_config(strategy: RetryStrategy) -> RetryConfig {
+        RetryConfig {
+            max_attempts: 5,
+            strategy,
+            base: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            jitter: false,
+        }
+    }
+
+    fn delays(retry: &RetryConfig) -> Vec<u128> {
+        let mut backoff = retry.backoff();
+        (1..=5).map(|_| backoff.next_delay().as_millis()).collect()
+    }
+
+    #[test]
+    fn test_strategies() {
+        assert_eq!(
+            delays(&retry_config(RetryStrategy::Exponential)),
+            vec![200, 400, 800, 1000, 1000]
+        );
+        assert_eq!(
+            delays(&retry_config(RetryStrategy::Linear)),
+            vec![100, 200, 300, 400, 500]
+        );
+        assert_eq!(
+            delays(&retry_config(RetryStrategy::Constant)),
+            vec![100, 100, 100, 100, 100]
+        );
+    }
+
+    #[test]
+    fn test_jitter_stays_within_bounds() {
+        let retry = RetryConfig {
+            jitter: true,
+            ..retry_config(RetryStrategy::Constant)
+        };
+
+        for _ in 0..100 {
+            let delay = retry.backoff().next_delay();
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn test_random_strategies_stay_within_bounds() {
+        for _ in 0..100 {
+            let mut full = retry_config(RetryStrategy::ExponentialJitter).backoff();
+            for cap in [200, 400, 800, 1000, 1000] {
+                assert!(full.next_delay() <= Duration::from_millis(cap));
+            }
+
+            let mut decorrelated = retry_config(RetryStrategy::DecorrelatedJitter).backoff();
+            let mut previous = Duration::from_millis(100);
+            for _ in 0..5 {
+                let delay = decorrelated.next_delay();
+                assert!(delay >= Duration::from_millis(100));
+                assert!(delay <= (previous * 3).min(Duration::from_millis(1000)));
+                previous = delay;
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+    task,
+};
+
+/// Lines waiting to be written before new ones are dropped.
+const QUEUE_SIZE: usize = 1024;
+
+/// Appends a JSON line for every processed message to a file for diagnosing why a pattern did or
+/// didn't fire. Writing happens on a background task so the handler never waits on disk. When the
+/// file reaches `max_bytes` it's renamed to `<path>.1`, shifting older files up to `<path>.<keep>`.
+pub struct CaptureLog {
+    lines: mpsc::Sender<String>,
+    writer: task::JoinHandle<()>,
+}
+
+impl CaptureLog {
+    pub async fn open(path: impl Into<PathBuf>, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let path = path.into();
+        let file = open_append(&path).await?;
+        let (lines, rx) = mpsc::channel(QUEUE_SIZE);
+
+        let writer = task::spawn(async move {
+            if let Err(e) = write_lines(rx, file, &path, max_bytes, keep).await {
+                tracing::error!("capture log error: {}", e);
+            }
+        });
+
+        Ok(CaptureLog { lines, writer })
+    }
+
+    /// Queues a record of `line`, the patterns it matched, and the channel it was sent to.
+    pub fn record(&self, channel: Option<&str>, line: &str, matched: &[&str]) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let record = serde_json::json!({
+            "timestamp": timestamp,
+            "channel": channel,
+            "matched": matched,
+            "line": line,
+        });
+
+        if self.lines.try_send(record.to_string()).is_err() {
+            tracing::debug!("capture log queue is full, dropping line");
+        }
+    }
+
+    /// Writes out any queued lines and stops the writer.
+    pub async fn close(self) {
+        drop(self.lines);
+        let _ = self.writer.await;
+    }
+}
+
+async fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+async fn write_lines(
+    mut rx: mpsc::Receiver<String>,
+    file: File,
+    path: &Path,
+    max_bytes: u64,
+    keep: usize,
+) -> io::Result<()> {
+    let mut size = file.metadata().await?.len();
+    let mut out = BufWriter::new(file);
+
+    while let Some(line) = rx.recv().await {
+        out.write_all(line.as_bytes()).await?;
+        out.write_all(b"\n").await?;
+        size += line.len() as u64 + 1;
+
+        if size >= max_bytes {
+            out.flush().await?;
+            rotate(path, keep).await?;
+            out = BufWriter::new(open_append(path).await?);
+            size = 0;
+        } else if rx.is_empty() {
+            out.flush().await?;
+        }
+    }
+
+    out.flush().await
+}
+
+async fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+    let numbered = |n: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    };
+
+    for n in (1..keep).rev() {
+        if fs::try_exists(numbered(n)).await? {
+            fs::rename(numbered(n), numbered(n + 1)).await?;
+        }
+    }
+    if keep > 0 {
+        fs::rename(path, numbered(1)).await
+    } else {
+        fs::remove_file(path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rotates_by_size() {
+        let dir = std::env::temp_dir().join(format!("irc_hook_capture_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("capture.log");
+
+        // Each record is around 80 bytes, so the file rotates after every third one.
+        let log = CaptureLog::open(&path, 200, 2).await.unwrap();
+        for i in 0..10 {
+            log.record(Some("#rust"), &format!("message {}", i), &[]);
+        }
+        log.close().await;
+
+        let last = std::fs::read_to_string(&path).unwrap();
+        let rotated = std::fs::read_to_string(dir.join("capture.log.1")).unwrap();
+        assert!(dir.join("capture.log.2").exists());
+        assert!(!dir.join("capture.log.3").exists());
+        assert!(last.contains(r#""line":"message 9""#));
+        assert!(rotated.contains(r##""channel":"#rust""##));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
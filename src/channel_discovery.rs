@@ -0,0 +1,66 @@
+use regex::Regex;
+use std::collections::HashSet;
+
+/// Picks channels to join from the replies to a `LIST` command. Channels are joined when their
+/// name matches `pattern`, up to `max_channels` in total.
+pub struct ChannelDiscovery {
+    pattern: Regex,
+    max_channels: usize,
+    joined: HashSet<String>,
+}
+
+impl ChannelDiscovery {
+    pub fn new(pattern: Regex, max_channels: usize) -> Self {
+        ChannelDiscovery {
+            pattern,
+            max_channels,
+            joined: HashSet::new(),
+        }
+    }
+
+    /// Returns true if `channel`, taken from an `RPL_LIST` reply, should be joined.
+    pub fn should_join(&mut self, channel: &str) -> bool {
+        // Channel names are case insensitive.
+        let key = channel.to_lowercase();
+        if self.joined.contains(&key) || !self.pattern.is_match(channel) {
+            return false;
+        }
+
+        if self.joined.len() >= self.max_channels {
+            tracing::warn!(
+                channel,
+                max_channels = self.max_channels,
+                "not joining discovered channel, auto-join limit reached"
+            );
+            return false;
+        }
+
+        self.joined.insert(key);
+        true
+    }
+
+    /// The channels joined so far, lowercased.
+    pub fn joined(&self) -> impl Iterator<Item = &str> {
+        self.joined.iter().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_join() {
+        let mut discovery = ChannelDiscovery::new(Regex::new("^#project-").unwrap(), 2);
+
+        assert!(discovery.should_join("#project-a"));
+        assert!(!discovery.should_join("#project-A"));
+        assert!(!discovery.should_join("#other"));
+        assert!(discovery.should_join("#project-b"));
+        assert!(!discovery.should_join("#project-c"));
+
+        let mut joined: Vec<&str> = discovery.joined().collect();
+        joined.sort();
+        assert_eq!(joined, vec!["#project-a", "#project-b"]);
+    }
+}
@@ -0,0 +1,199 @@
+use irc::client::prelude::*;
+use std::{collections::HashMap, str::FromStr};
+
+/// The channel status a message's sender needs for a search pattern to be checked against it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SenderStatus {
+    #[default]
+    Any,
+    /// Voiced or an operator.
+    Voice,
+    /// An operator, or a founder or admin on servers that have them.
+    Op,
+}
+
+impl FromStr for SenderStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(SenderStatus::Any),
+            "voice" => Ok(SenderStatus::Voice),
+            "op" => Ok(SenderStatus::Op),
+            other => Err(format!("unknown sender status: {}", other)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Flags {
+    op: bool,
+    voice: bool,
+}
+
+/// Tracks who is an operator or voiced in each joined channel, from NAMES replies and the JOIN,
+/// PART, KICK, QUIT, NICK, and MODE messages that follow. Channels and nicks are compared
+/// case-insensitively.
+#[derive(Default)]
+pub struct ChannelStatus {
+    channels: HashMap<String, HashMap<String, Flags>>,
+}
+
+impl ChannelStatus {
+    pub fn update(&mut self, msg: &Message) {
+        let source = msg.source_nickname().map(str::to_lowercase);
+
+        match &msg.command {
+            // RPL_NAMREPLY is "<client> <symbol> <channel> :[prefix]<nick>{ [prefix]<nick>}".
+            Command::Response(Response::RPL_NAMREPLY, args) if args.len() >= 4 => {
+                let users = self.channels.entry(args[2].to_lowercase()).or_default();
+                for name in args[3].split_whitespace() {
+                    let nick = name.trim_start_matches(['~', '&', '@', '%', '+']);
+                    let prefixes = &name[..name.len() - nick.len()];
+                    users.insert(
+                        nick.to_lowercase(),
+                        Flags {
+                            op: prefixes.contains(['~', '&', '@']),
+                            voice: prefixes.contains('+'),
+                        },
+                    );
+                }
+            }
+            Command::JOIN(channels, _, _) => {
+                if let Some(nick) = source {
+                    for channel in channels.split(',') {
+                        self.channels
+                            .entry(channel.to_lowercase())
+                            .or_default()
+                            .insert(nick.clone(), Flags::default());
+                    }
+                }
+            }
+            Command::PART(channels, _) => {
+                if let Some(nick) = source {
+                    for channel in channels.split(',') {
+                        self.remove(channel, &nick);
+                    }
+                }
+            }
+            Command::KICK(channels, nick, _) => {
+                for channel in channels.split(',') {
+                    self.remove(channel, &nick.to_lowercase());
+                }
+            }
+            Command::QUIT(_) => {
+                if let Some(nick) = source {
+                    for users in self.channels.values_mut() {
+                        users.remove(&nick);
+                    }
+                }
+            }
+            Command::NICK(new_nick) => {
+                if let Some(nick) = source {
+                    for users in self.channels.values_mut() {
+                        if let Some(flags) = users.remove(&nick) {
+                            users.insert(new_nick.to_lowercase(), flags);
+                        }
+                    }
+                }
+            }
+            Command::ChannelMODE(channel, modes) => {
+                let Some(users) = self.channels.get_mut(&channel.to_lowercase()) else {
+                    return;
+                };
+                for mode in modes {
+                    let (mode, nick, set) = match mode {
+                        Mode::Plus(mode, Some(nick)) => (mode, nick, true),
+                        Mode::Minus(mode, Some(nick)) => (mode, nick, false),
+                        _ => continue,
+                    };
+                    let flags = users.entry(nick.to_lowercase()).or_default();
+                    match mode {
+                        ChannelMode::Founder | ChannelMode::Admin | ChannelMode::Oper => {
+                            flags.op = set
+                        }
+                        ChannelMode::Voice => flags.voice = set,
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns true if `nick` has at least the `required` status in `channel`.
+    pub fn has_status(&self, channel: &str, nick: &str, required: SenderStatus) -> bool {
+        let flags = self
+            .channels
+            .get(&channel.to_lowercase())
+            .and_then(|users| users.get(&nick.to_lowercase()))
+            .copied()
+            .unwrap_or_default();
+
+        match required {
+            SenderStatus::Any => true,
+            SenderStatus::Voice => flags.op || flags.voice,
+            SenderStatus::Op => flags.op,
+        }
+    }
+
+    fn remove(&mut self, channel: &str, nick: &str) {
+        if let Some(users) = self.channels.get_mut(&channel.to_lowercase()) {
+            users.remove(nick);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(prefix: &str, command: &str, args: Vec<&str>) -> Message {
+        Message::new(Some(prefix), command, args).unwrap()
+    }
+
+    #[test]
+    fn test_names_and_modes() {
+        let mut status = ChannelStatus::default();
+        status.update(&msg(
+            "irc.example.com",
+            "353",
+            vec!["me", "=", "#Rust", "@Alice +bob carol"],
+        ));
+
+        assert!(status.has_status("#rust", "alice", SenderStatus::Op));
+        assert!(status.has_status("#rust", "bob", SenderStatus::Voice));
+        assert!(!status.has_status("#rust", "bob", SenderStatus::Op));
+        assert!(!status.has_status("#rust", "carol", SenderStatus::Voice));
+        assert!(status.has_status("#rust", "carol", SenderStatus::Any));
+
+        status.update(&msg(
+            "alice!a@host",
+            "MODE",
+            vec!["#rust", "+o-v", "carol", "bob"],
+        ));
+        assert!(status.has_status("#rust", "carol", SenderStatus::Op));
+        assert!(!status.has_status("#rust", "bob", SenderStatus::Voice));
+    }
+
+    #[test]
+    fn test_membership_changes() {
+        let mut status = ChannelStatus::default();
+        status.update(&msg(
+            "irc.example.com",
+            "353",
+            vec!["me", "=", "#rust", "@alice @bob"],
+        ));
+
+        status.update(&msg("alice!a@host", "NICK", vec!["alicia"]));
+        assert!(!status.has_status("#rust", "alice", SenderStatus::Op));
+        assert!(status.has_status("#rust", "alicia", SenderStatus::Op));
+
+        status.update(&msg("bob!b@host", "PART", vec!["#rust"]));
+        assert!(!status.has_status("#rust", "bob", SenderStatus::Op));
+
+        // A user who rejoins has lost their status.
+        status.update(&msg("bob!b@host", "JOIN", vec!["#rust"]));
+        assert!(!status.has_status("#rust", "bob", SenderStatus::Voice));
+    }
+}
@@ -0,0 +1,131 @@
+use std::{sync::Mutex, time::Duration};
+use tokio::sync::Notify;
+
+/// Bounds for the adaptive in-flight request cap. Requests that complete successfully within
+/// `latency_threshold` raise the cap by one; errors and slow requests halve it (AIMD).
+#[derive(Clone)]
+pub struct ConcurrencyConfig {
+    pub initial: usize,
+    pub min: usize,
+    pub max: usize,
+    pub latency_threshold: Duration,
+}
+
+pub struct ConcurrencyLimiter {
+    config: ConcurrencyConfig,
+    state: Mutex<LimiterState>,
+    released: Notify,
+}
+
+struct LimiterState {
+    limit: usize,
+    in_flight: usize,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(config: ConcurrencyConfig) -> Self {
+        let limit = config.initial.clamp(config.min, config.max);
+
+        ConcurrencyLimiter {
+            config,
+            state: Mutex::new(LimiterState {
+                limit,
+                in_flight: 0,
+            }),
+            released: Notify::new(),
+        }
+    }
+
+    /// Waits until the number of in-flight requests is below the current cap and claims a slot.
+    pub async fn acquire(&self) {
+        loop {
+            // Register for wakeups before checking, so a release between the check and the await
+            // isn't missed.
+            let released = self.released.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.in_flight < state.limit {
+                    state.in_flight += 1;
+                    return;
+                }
+            }
+            released.await;
+        }
+    }
+
+    /// Frees a slot claimed by `acquire` and adjusts the cap based on how the request went.
+    pub fn release(&self, latency: Duration, success: bool) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.in_flight -= 1;
+
+            if success && latency <= self.config.latency_threshold {
+                state.limit = (state.limit + 1).min(self.config.max);
+            } else {
+                state.limit = (state.limit / 2).max(self.config.min);
+            }
+            tracing::debug!(limit = state.limit, "adjusted webhook concurrency limit");
+        }
+        self.released.notify_waiters();
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    fn limiter(initial: usize) -> ConcurrencyLimiter {
+        ConcurrencyLimiter::new(ConcurrencyConfig {
+            initial,
+            min: 1,
+            max: 4,
+            latency_threshold: Duration::from_millis(100),
+        })
+    }
+
+    #[test]
+    fn test_acquire_blocks_at_limit() {
+        let limiter = limiter(2);
+
+        assert!(limiter.acquire().now_or_never().is_some());
+        assert!(limiter.acquire().now_or_never().is_some());
+        assert!(limiter.acquire().now_or_never().is_none());
+
+        limiter.release(Duration::from_millis(10), true);
+        assert!(limiter.acquire().now_or_never().is_some());
+    }
+
+    #[test]
+    fn test_limit_increases_additively() {
+        let limiter = limiter(2);
+
+        for _ in 0..3 {
+            limiter.acquire().now_or_never().unwrap();
+            limiter.release(Duration::from_millis(10), true);
+        }
+
+        assert_eq!(limiter.limit(), 4);
+    }
+
+    #[test]
+    fn test_limit_decreases_multiplicatively() {
+        let limiter = limiter(4);
+
+        limiter.acquire().now_or_never().unwrap();
+        limiter.release(Duration::from_millis(500), true);
+        assert_eq!(limiter.limit(), 2);
+
+        limiter.acquire().now_or_never().unwrap();
+        limiter.release(Duration::from_millis(10), false);
+        assert_eq!(limiter.limit(), 1);
+
+        limiter.acquire().now_or_never().unwrap();
+        limiter.release(Duration::from_millis(10), false);
+        assert_eq!(limiter.limit(), 1);
+    }
+}
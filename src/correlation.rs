@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// The most events waiting for their completion per rule, so starts that never complete can't
+/// grow the map without bound between expiries.
+const MAX_PENDING: usize = 10_000;
+
+/// Pairs a line announcing an event with a later one reporting its outcome, e.g. `job 42
+/// started` and `job 42 result: ok`, and publishes them together. Both patterns capture the key
+/// that ties them together in `key_group`, and the pair must be in the same channel.
+#[derive(Clone, Debug)]
+pub struct CorrelationRule {
+    pub start: String,
+    pub complete: String,
+    pub key_group: usize,
+    pub timeout: Duration,
+    pub on_timeout: TimeoutAction,
+}
+
+/// What happens to an event whose completion doesn't arrive within the timeout.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TimeoutAction {
+    #[default]
+    Drop,
+    /// Publish the start on its own.
+    Publish,
+}
+
+impl FromStr for TimeoutAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(TimeoutAction::Drop),
+            "publish" => Ok(TimeoutAction::Publish),
+            _ => Err(format!("invalid correlation timeout action: {}", s)),
+        }
+    }
+}
+
+/// Started events waiting for their completion, by key. A start with a key that's already
+/// pending replaces it, so a restarted job is timed from its latest start.
+pub struct Pending<T> {
+    timeout: Duration,
+    started: HashMap<String, (Instant, T)>,
+}
+
+impl<T> Pending<T> {
+    pub fn new(timeout: Duration) -> Self {
+        Pending {
+            timeout,
+            started: HashMap::new(),
+        }
+    }
+
+    /// Changes the timeout, e.g. on a config reload, keeping the events already pending.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Records the start of `key`, returning false if too many events are already pending.
+    pub fn start(&mut self, key: String, value: T, now: Instant) -> bool {
+        if self.started.len() >= MAX_PENDING && !self.started.contains_key(&key) {
+            return false;
+        }
+        self.started.insert(key, (now, value));
+        true
+    }
+
+    /// Removes and returns the start of `key` and how long ago it was, unless there isn't one or
+    /// it has timed out. Timed out starts are left for `expired`.
+    pub fn complete(&mut self, key: &str, now: Instant) -> Option<(T, Duration)> {
+        let elapsed = now.saturating_duration_since(self.started.get(key)?.0);
+        if elapsed >= self.timeout {
+            return None;
+        }
+        self.started.remove(key).map(|(_, value)| (value, elapsed))
+    }
+
+    /// Removes and returns the starts that have waited the timeout for their completion.
+    pub fn expired(&mut self, now: Instant) -> Vec<T> {
+        let timeout = self.timeout;
+        let expired: Vec<String> = self
+            .started
+            .iter()
+            .filter(|(_, (at, _))| now.saturating_duration_since(*at) >= timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|key| self.started.remove(&key).map(|(_, value)| value))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_completes_within_timeout() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut pending = Pending::new(Duration::from_secs(60));
+
+        assert!(pending.start("job-1".to_string(), "a", at(0)));
+        assert!(pending.start("job-2".to_string(), "b", at(0)));
+        assert_eq!(pending.complete("job-3", at(1)), None);
+        assert_eq!(
+            pending.complete("job-1", at(10)),
+            Some(("a", Duration::from_secs(10)))
+        );
+        // Each start completes once.
+        assert_eq!(pending.complete("job-1", at(11)), None);
+
+        // A late completion doesn't count; the start times out instead.
+        assert_eq!(pending.complete("job-2", at(60)), None);
+        assert_eq!(pending.expired(at(60)), ["b"]);
+        assert!(pending.expired(at(120)).is_empty());
+    }
+}
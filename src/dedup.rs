@@ -0,0 +1,66 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    time::{Duration, Instant},
+};
+
+/// Remembers the keys of recent events so repeats within `window` of the first one can be
+/// dropped. A key can be seen again once the window since it was first recorded has passed.
+pub struct Dedup {
+    window: Duration,
+    seen: HashMap<String, Instant>,
+}
+
+impl Dedup {
+    pub fn new(window: Duration) -> Self {
+        Dedup {
+            window,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Changes the window, e.g. on a config reload, keeping the keys already recorded.
+    pub fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    /// Records `key`, returning false if it was already recorded within the window.
+    pub fn first_seen(&mut self, key: String) -> bool {
+        self.first_seen_at(key, Instant::now())
+    }
+
+    fn first_seen_at(&mut self, key: String, now: Instant) -> bool {
+        let window = self.window;
+        self.seen
+            .retain(|_, at| now.saturating_duration_since(*at) < window);
+
+        match self.seen.entry(key) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_seen_within_window() {
+        let start = Instant::now();
+        let mut dedup = Dedup::new(Duration::from_secs(60));
+
+        assert!(dedup.first_seen_at("build-1".to_string(), start));
+        assert!(dedup.first_seen_at("build-2".to_string(), start));
+
+        let later = start + Duration::from_secs(30);
+        assert!(!dedup.first_seen_at("build-1".to_string(), later));
+
+        // The window runs from the first sighting, not the latest repeat.
+        let after_window = start + Duration::from_secs(60);
+        assert!(dedup.first_seen_at("build-1".to_string(), after_window));
+        assert_eq!(dedup.seen.len(), 1);
+    }
+}
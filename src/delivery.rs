@@ -0,0 +1,110 @@
+use std::time::Duration;
+
+use crate::backoff::{RetryConfig, RetryStrategy};
+
+/// How a webhook is delivered: whether and how failed requests are retried, and how long each
+/// request may take.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeliveryConfig {
+    pub retry: Option<RetryConfig>,
+    pub timeout: Option<Duration>,
+}
+
+/// Delivery settings given for one level of config, e.g. the `defaults` block or a single rule.
+/// Anything left unset is inherited from the level above.
+#[derive(Clone, Debug, Default)]
+pub struct DeliveryOverrides {
+    /// 0 disables retrying.
+    pub max_attempts: Option<u32>,
+    pub strategy: Option<RetryStrategy>,
+    pub base: Option<Duration>,
+    pub max_delay: Option<Duration>,
+    pub jitter: Option<bool>,
+    /// A zero timeout removes any inherited one.
+    pub timeout: Option<Duration>,
+}
+
+/// Used for the retry settings a rule doesn't give when nothing above it enables retries.
+const DEFAULT_RETRY: RetryConfig = RetryConfig {
+    max_attempts: 0,
+    strategy: RetryStrategy::Exponential,
+    base: Duration::from_millis(500),
+    max_delay: Duration::from_secs(30),
+    jitter: true,
+};
+
+impl DeliveryOverrides {
+    /// Returns `inherited` with these overrides applied.
+    pub fn apply(&self, inherited: &DeliveryConfig) -> DeliveryConfig {
+        let mut retry = inherited.retry.clone().unwrap_or(DEFAULT_RETRY);
+        retry.max_attempts = self.max_attempts.unwrap_or(retry.max_attempts);
+        retry.strategy = self.strategy.unwrap_or(retry.strategy);
+        retry.base = self.base.unwrap_or(retry.base);
+        retry.max_delay = self.max_delay.unwrap_or(retry.max_delay);
+        retry.jitter = self.jitter.unwrap_or(retry.jitter);
+
+        DeliveryConfig {
+            retry: (retry.max_attempts > 0).then_some(retry),
+            timeout: match self.timeout {
+                Some(timeout) if timeout.is_zero() => None,
+                Some(timeout) => Some(timeout),
+                None => inherited.timeout,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overrides_inherit_unset_fields() {
+        let defaults = DeliveryOverrides {
+            max_attempts: Some(3),
+            base: Some(Duration::from_secs(1)),
+            timeout: Some(Duration::from_secs(10)),
+            ..DeliveryOverrides::default()
+        }
+        .apply(&DeliveryConfig::default());
+        let retry = defaults.retry.clone().unwrap();
+        assert_eq!(retry.max_attempts, 3);
+        assert_eq!(retry.base, Duration::from_secs(1));
+        assert_eq!(retry.max_delay, Duration::from_secs(30));
+        assert_eq!(defaults.timeout, Some(Duration::from_secs(10)));
+
+        // A rule with nothing set gets the defaults as they are.
+        assert_eq!(DeliveryOverrides::default().apply(&defaults), defaults);
+
+        // A critical rule retries harder but keeps the default timeout.
+        let critical = DeliveryOverrides {
+            max_attempts: Some(10),
+            strategy: Some(RetryStrategy::Constant),
+            ..DeliveryOverrides::default()
+        }
+        .apply(&defaults);
+        let retry = critical.retry.unwrap();
+        assert_eq!(retry.max_attempts, 10);
+        assert_eq!(retry.strategy, RetryStrategy::Constant);
+        assert_eq!(retry.base, Duration::from_secs(1));
+        assert_eq!(critical.timeout, Some(Duration::from_secs(10)));
+
+        // A noisy rule fails fast.
+        let noisy = DeliveryOverrides {
+            max_attempts: Some(0),
+            timeout: Some(Duration::from_secs(2)),
+            ..DeliveryOverrides::default()
+        }
+        .apply(&defaults);
+        assert!(noisy.retry.is_none());
+        assert_eq!(noisy.timeout, Some(Duration::from_secs(2)));
+
+        let untimed = DeliveryOverrides {
+            timeout: Some(Duration::ZERO),
+            ..DeliveryOverrides::default()
+        }
+        .apply(&defaults);
+        assert_eq!(untimed.timeout, None);
+        assert_eq!(untimed.retry, defaults.retry);
+    }
+}
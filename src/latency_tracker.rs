@@ -0,0 +1,61 @@
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+/// Keeps the most recent `capacity` latencies so percentiles reflect current conditions rather
+/// than the whole run.
+pub struct LatencyTracker {
+    capacity: usize,
+    recent: Mutex<VecDeque<Duration>>,
+}
+
+impl LatencyTracker {
+    pub fn new(capacity: usize) -> Self {
+        LatencyTracker {
+            capacity,
+            recent: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == self.capacity {
+            recent.pop_front();
+        }
+        recent.push_back(latency);
+    }
+
+    /// The latency at percentile `p` (0 to 100) of the recorded ones, or `None` if there are none.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let mut sorted: Vec<Duration> = self.recent.lock().unwrap().iter().copied().collect();
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort();
+
+        let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+        Some(sorted[rank.clamp(1, sorted.len()) - 1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles_of_recent_latencies() {
+        let tracker = LatencyTracker::new(100);
+        assert_eq!(tracker.percentile(50.0), None);
+
+        for ms in (1..=100).rev() {
+            tracker.record(Duration::from_millis(ms));
+        }
+        assert_eq!(tracker.percentile(50.0), Some(Duration::from_millis(50)));
+        assert_eq!(tracker.percentile(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(tracker.percentile(0.0), Some(Duration::from_millis(1)));
+
+        // Only the last 100 are kept.
+        for _ in 0..100 {
+            tracker.record(Duration::from_secs(1));
+        }
+        assert_eq!(tracker.percentile(50.0), Some(Duration::from_secs(1)));
+    }
+}
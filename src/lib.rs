@@ -1,2 +1,21 @@
+pub mod backoff;
+pub mod capture_log;
+pub mod channel_discovery;
+pub mod channel_status;
+pub mod concurrency_limiter;
+pub mod correlation;
+pub mod dedup;
+pub mod delivery;
+pub mod latency_tracker;
+pub mod line_buffer;
+pub mod log_coalescer;
+pub mod match_queue;
 pub mod message_handler;
+pub mod outgoing;
+pub mod own_nick;
+pub mod publisher;
+pub mod rate_monitor;
+pub mod reservoir;
+pub mod retry_budget;
+pub mod schedule;
 pub mod webhook_publisher;
@@ -0,0 +1,161 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    time::{Duration, Instant},
+};
+
+/// The most lines joined into one run, so a sender that never pauses can't grow a buffer without
+/// bound.
+const MAX_LINES: usize = 100;
+
+/// Consecutive lines from one sender in one target, joined with newlines.
+#[derive(Debug, PartialEq)]
+pub struct Run<T> {
+    /// The last message of the run, which stands in for the whole run.
+    pub last: T,
+    pub target: String,
+    pub content: String,
+}
+
+struct Buffer<T> {
+    last: T,
+    lines: Vec<String>,
+    updated: Instant,
+}
+
+/// Buffers lines per sender and target so a sender's multi-line output can be matched as one,
+/// even with other senders' lines interleaved. A run ends once the sender has been quiet for
+/// `timeout`, or when it reaches `MAX_LINES`.
+pub struct LineBuffers<T> {
+    timeout: Duration,
+    buffers: HashMap<(String, String), Buffer<T>>,
+}
+
+impl<T> LineBuffers<T> {
+    pub fn new(timeout: Duration) -> Self {
+        LineBuffers {
+            timeout,
+            buffers: HashMap::new(),
+        }
+    }
+
+    /// Changes the timeout, e.g. on a config reload, keeping the lines already buffered.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Adds `line` from `nick` in `target`, returning any runs that have ended: the sender's
+    /// previous one if it timed out, or the current one once it's full.
+    pub fn push(
+        &mut self,
+        target: &str,
+        nick: &str,
+        line: String,
+        msg: T,
+        now: Instant,
+    ) -> Vec<Run<T>> {
+        let key = (target.to_string(), nick.to_string());
+        let mut ended = Vec::new();
+        if self
+            .buffers
+            .get(&key)
+            .is_some_and(|b| now.saturating_duration_since(b.updated) >= self.timeout)
+        {
+            let buffer = self.buffers.remove(&key).unwrap();
+            ended.push(Self::run(key.0.clone(), buffer));
+        }
+
+        let buffer = match self.buffers.entry(key.clone()) {
+            Entry::Occupied(entry) => {
+                let buffer = entry.into_mut();
+                buffer.last = msg;
+                buffer.lines.push(line);
+                buffer.updated = now;
+                buffer
+            }
+            Entry::Vacant(entry) => entry.insert(Buffer {
+                last: msg,
+                lines: vec![line],
+                updated: now,
+            }),
+        };
+        if buffer.lines.len() >= MAX_LINES {
+            let buffer = self.buffers.remove(&key).unwrap();
+            ended.push(Self::run(key.0, buffer));
+        }
+        ended
+    }
+
+    /// Removes and returns the runs whose sender has been quiet for the timeout.
+    pub fn expired(&mut self, now: Instant) -> Vec<Run<T>> {
+        let expired: Vec<_> = self
+            .buffers
+            .iter()
+            .filter(|(_, b)| now.saturating_duration_since(b.updated) >= self.timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        expired
+            .into_iter()
+            .map(|key| {
+                let buffer = self.buffers.remove(&key).unwrap();
+                Self::run(key.0, buffer)
+            })
+            .collect()
+    }
+
+    fn run(target: String, buffer: Buffer<T>) -> Run<T> {
+        Run {
+            last: buffer.last,
+            target,
+            content: buffer.lines.join("\n"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runs_per_sender() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut buffers = LineBuffers::new(Duration::from_millis(500));
+
+        let line = |s: &str| s.to_string();
+        assert!(buffers
+            .push("#ci", "bot", line("panic at main.rs:3"), 1, at(0))
+            .is_empty());
+        assert!(buffers
+            .push("#ci", "alice", line("is it down?"), 2, at(100))
+            .is_empty());
+        assert!(buffers
+            .push("#ci", "bot", line("  0: main"), 3, at(200))
+            .is_empty());
+        assert!(buffers.expired(at(599)).is_empty());
+
+        let mut expired = buffers.expired(at(700));
+        expired.sort_by_key(|run| run.last);
+        assert_eq!(
+            expired,
+            [
+                Run {
+                    last: 2,
+                    target: "#ci".to_string(),
+                    content: "is it down?".to_string()
+                },
+                Run {
+                    last: 3,
+                    target: "#ci".to_string(),
+                    content: "panic at main.rs:3\n  0: main".to_string()
+                },
+            ]
+        );
+
+        // A line after the timeout ends the previous run before any tick does.
+        buffers.push("#ci", "bot", line("first"), 4, at(1000));
+        let ended = buffers.push("#ci", "bot", line("second"), 5, at(2000));
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].content, "first");
+    }
+}
@@ -0,0 +1,101 @@
+use std::time::{Duration, Instant};
+
+/// Collapses runs of identical error messages. The first occurrence is logged, repeats are
+/// counted, and a "same error repeated N times" summary is logged at most once per `interval`.
+pub struct LogCoalescer {
+    interval: Duration,
+    last: Option<String>,
+    repeats: u64,
+    last_logged: Instant,
+}
+
+impl LogCoalescer {
+    pub fn new(interval: Duration) -> Self {
+        LogCoalescer {
+            interval,
+            last: None,
+            repeats: 0,
+            last_logged: Instant::now(),
+        }
+    }
+
+    pub fn error(&mut self, message: &str) {
+        for line in self.record(message, Instant::now()) {
+            tracing::error!("{}", line);
+        }
+    }
+
+    pub fn warn(&mut self, message: &str) {
+        for line in self.record(message, Instant::now()) {
+            tracing::warn!("{}", line);
+        }
+    }
+
+    /// Records an occurrence of `message` at `now` and returns the lines that should be logged.
+    fn record(&mut self, message: &str, now: Instant) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        if self.last.as_deref() == Some(message) {
+            self.repeats += 1;
+            if now.duration_since(self.last_logged) >= self.interval {
+                lines.push(self.summary());
+                self.last_logged = now;
+            }
+            return lines;
+        }
+
+        if self.repeats > 0 {
+            lines.push(self.summary());
+        }
+        lines.push(message.to_string());
+        self.last = Some(message.to_string());
+        self.last_logged = now;
+
+        lines
+    }
+
+    fn summary(&mut self) -> String {
+        let repeats = std::mem::take(&mut self.repeats);
+        format!(
+            "same error repeated {} times: {}",
+            repeats,
+            self.last.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats_are_summarized_periodically() {
+        let mut coalescer = LogCoalescer::new(Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert_eq!(coalescer.record("refused", start), vec!["refused"]);
+        for secs in 1..=3 {
+            assert!(coalescer
+                .record("refused", start + Duration::from_secs(secs))
+                .is_empty());
+        }
+        assert_eq!(
+            coalescer.record("refused", start + Duration::from_secs(60)),
+            vec!["same error repeated 4 times: refused"]
+        );
+    }
+
+    #[test]
+    fn test_new_error_flushes_pending_repeats() {
+        let mut coalescer = LogCoalescer::new(Duration::from_secs(60));
+        let start = Instant::now();
+
+        coalescer.record("refused", start);
+        coalescer.record("refused", start + Duration::from_secs(1));
+
+        assert_eq!(
+            coalescer.record("timed out", start + Duration::from_secs(2)),
+            vec!["same error repeated 1 times: refused", "timed out"]
+        );
+    }
+}
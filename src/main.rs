@@ -1,102 +1,2333 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
 use config::Config;
 use futures::prelude::*;
 use irc::{client::prelude as irc_client, error};
-use irc_hook::{message_handler, webhook_publisher};
-use std::{collections::HashMap, pin::Pin, str::FromStr};
+use irc_hook::{
+    backoff, capture_log, channel_discovery, channel_status, concurrency_limiter, correlation,
+    delivery, log_coalescer, match_queue, message_handler, outgoing, own_nick, retry_budget,
+    schedule, webhook_publisher,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc;
 use tracing_subscriber::FmtSubscriber;
 
 /// Joins IRC channels and POSTs webhooks based on regex matching.
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Cli {
-    #[arg(short, long, env = "IRC_HOOK_CONFIG_FILE")]
-    config_file: String,
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    #[arg(short, long, env = "IRC_HOOK_CONFIG_FILE", required = true)]
+    config_file: Option<String>,
 
     #[arg(short, long, default_value = "warn")]
     log_level: tracing::Level,
+
+    /// Ignore IRC_HOOK_* environment variables so the config file is the only source of settings.
+    #[arg(long)]
+    no_env: bool,
+
+    /// POST the configured `test_body` to this URL with the configured headers, print the
+    /// response, and exit.
+    #[arg(long)]
+    test_webhook: Option<http::Uri>,
+
+    /// Connect to this server instead of the configured one.
+    #[arg(long)]
+    server: Option<String>,
+
+    /// Use this nick instead of the configured one.
+    #[arg(long)]
+    nick: Option<String>,
+
+    /// Join this channel instead of the configured ones. Can be given more than once.
+    #[arg(long = "channel")]
+    channels: Vec<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Renders a body template with synthetic capture groups and prints the result.
+    RenderTemplate(RenderTemplateArgs),
+    /// Checks that a search pattern compiles and lists its capture groups.
+    ValidateRegex {
+        /// The search pattern to check.
+        pattern: String,
+    },
+    /// Prints the config merged from the config file and environment as TOML, with secrets
+    /// redacted, after checking that it's valid.
+    DumpConfig,
+}
+
+#[derive(Args, Debug)]
+#[command(group(ArgGroup::new("template_source").required(true).args(["template", "template_file"])))]
+struct RenderTemplateArgs {
+    /// The template to render.
+    #[arg(long)]
+    template: Option<String>,
+
+    /// A file to read the template from.
+    #[arg(long)]
+    template_file: Option<String>,
+
+    /// Capture groups as a JSON array, starting with the full match.
+    #[arg(long, default_value = "[]")]
+    groups: String,
+
+    /// Named capture groups as a JSON object.
+    #[arg(long)]
+    named_groups: Option<String>,
+
+    /// The channel substituted for `${channel}`.
+    #[arg(long, default_value = "")]
+    channel: String,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Raw)]
+    output_format: OutputFormat,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum OutputFormat {
+    Raw,
+    JsonEscaped,
+}
+
+fn validate_regex(pattern: &str) -> Result<()> {
+    let re = regex::Regex::new(pattern)?;
+
+    println!("capture groups: {}", re.captures_len() - 1);
+    for (idx, name) in re.capture_names().enumerate() {
+        match (idx, name) {
+            (0, _) => println!("  ${{0}} (full match)"),
+            (_, Some(name)) => println!("  ${{{}}} or ${{{}}}", idx, name),
+            (_, None) => println!("  ${{{}}}", idx),
+        }
+    }
+
+    Ok(())
+}
+
+fn render_template(args: RenderTemplateArgs) -> Result<()> {
+    let template = match (args.template, args.template_file) {
+        (Some(template), _) => template,
+        (None, Some(path)) => std::fs::read_to_string(path)?,
+        (None, None) => unreachable!("clap requires a template source"),
+    };
+
+    let groups: Vec<String> = serde_json::from_str(&args.groups)?;
+    let named_groups: HashMap<String, String> = match args.named_groups {
+        Some(named_groups) => serde_json::from_str(&named_groups)?,
+        None => HashMap::new(),
+    };
+
+    let vars: Vec<(&str, String)> = named_groups
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.clone()))
+        .collect();
+    let rendered = webhook_publisher::render(&template, &args.channel, &groups, &vars);
+
+    match args.output_format {
+        OutputFormat::Raw => println!("{}", rendered),
+        OutputFormat::JsonEscaped => println!("{}", serde_json::to_string(&rendered)?),
+    }
+
+    Ok(())
+}
+
+/// Config keys whose values are redacted by `dump-config`.
+const SECRET_KEYS: &[&str] = &[
+    "password",
+    "irc_server_password",
+    "bouncer_password",
+    "signing_secret",
+    "bearer_token",
+];
+
+fn dump_config(settings: Config, conf: &ResolvedConfig) -> Result<()> {
+    let redacted = || toml::Value::String("[REDACTED]".to_string());
+
+    let mut merged: toml::value::Table = settings.try_deserialize()?;
+    // `--server` and `--nick` are applied after loading, so they aren't in the settings.
+    merged.insert("server".into(), toml::Value::String(conf.server.clone()));
+    merged.insert("nick".into(), toml::Value::String(conf.nickname.clone()));
+    for key in SECRET_KEYS {
+        if let Some(value) = merged.get_mut(*key) {
+            *value = redacted();
+        }
+    }
+    for table in ["headers", "append_headers"] {
+        if let Some(toml::Value::Table(headers)) = merged.get_mut(table) {
+            for (name, value) in headers.iter_mut() {
+                if name.eq_ignore_ascii_case("authorization") {
+                    *value = redacted();
+                }
+            }
+        }
+    }
+
+    print!("{}", toml::to_string(&toml::Value::Table(merged))?);
+    Ok(())
 }
 
 struct ResolvedConfig {
     nickname: String,
+    /// Joined in order once registration completes.
+    channels: Vec<String>,
+    /// Skip messages the bot sent itself.
+    ignore_self: bool,
+    /// When set, only messages from these nicks are matched.
+    allow_nicks: Option<Vec<String>>,
+    /// Messages from these nicks are never matched.
+    deny_nicks: Vec<String>,
+    /// Lines the bot sends are split to fit in this many bytes.
+    max_line_length: usize,
     nick_password: String,
+    password_file: Option<String>,
+    irc_server_password: Option<String>,
     server: String,
-    search_pattern: String,
-    webhook_url: http::Uri,
+    /// The irc crate picks the standard port for `use_tls` when unset.
+    port: Option<u16>,
+    use_tls: bool,
+    /// Seconds between client PINGs and seconds to wait for the PONG; the irc crate's defaults
+    /// are used when unset.
+    ping_time: Option<u32>,
+    ping_timeout: Option<u32>,
+    /// Retries for establishing the connection.
+    connect_retry: backoff::RetryConfig,
+    /// Reconnects after the connection drops. Each one also gets `connect_retry`'s retries.
+    reconnect_retry: backoff::RetryConfig,
+    auth_failure_policy: AuthFailurePolicy,
+    vhost: Option<VhostConfig>,
+    search_patterns: Vec<message_handler::SearchPattern>,
+    /// The delivery settings for each of `search_patterns`, with inherited ones filled in.
+    pattern_delivery: Vec<delivery::DeliveryConfig>,
+    /// Where each of `search_patterns` publishes to and with what body, if not the defaults.
+    pattern_webhooks: Vec<PatternWebhook>,
+    match_target: message_handler::MatchTarget,
+    content_format: message_handler::ContentFormat,
+    watch_numerics: Vec<u16>,
+    /// Matches are published to each in turn.
+    webhook_urls: Vec<http::Uri>,
     body_template: String,
     headers: HashMap<&'static str, String>,
+    publisher_options: webhook_publisher::PublisherOptions,
+    cooldown: Option<message_handler::CooldownConfig>,
+    discovery: Option<DiscoveryConfig>,
+    keepalive: Option<KeepaliveConfig>,
+    part_message: String,
+    shutdown_report: Option<http::Uri>,
+    reload_failure_report: Option<ReloadFailureReport>,
+    debug_sample_size: Option<usize>,
+    sample_rates: Option<message_handler::SampleRates>,
+    /// How long repeats are dropped for, and the group identifying them if not the content.
+    dedup: Option<(Duration, Option<usize>)>,
+    mark_first_match: bool,
+    /// When set, lines from one sender are joined until they've been quiet this long.
+    concat_window: Option<Duration>,
+    correlations: Vec<correlation::CorrelationRule>,
+    schedule: Option<schedule::Schedule>,
+    schedule_queue: Option<ScheduleQueueConfig>,
+    /// How many matches that failed to publish are kept to retry. 0 drops them.
+    failed_queue_size: usize,
+    capture_log: Option<CaptureLogConfig>,
+    /// How often to check the config file for changes where SIGHUP isn't available.
+    config_poll_interval: Option<Duration>,
+    heartbeat_interval: Option<Duration>,
+    /// Posted to on every heartbeat, for a dead man's switch that alerts when they stop.
+    heartbeat_url: Option<http::Uri>,
+    /// The pause between consecutive JOINs, for networks that limit how fast channels are joined.
+    channel_join_delay: Duration,
+    test_body: String,
+}
+
+/// A `patterns` entry's own `webhook_url` and `body_template`.
+#[derive(Clone, Debug, Default)]
+struct PatternWebhook {
+    url: Option<http::Uri>,
+    body_template: Option<String>,
+}
+
+struct DiscoveryConfig {
+    pattern: regex::Regex,
+    max_channels: usize,
+    interval: Duration,
+}
+
+struct CaptureLogConfig {
+    path: String,
+    max_bytes: u64,
+    keep: usize,
+}
+
+/// Matches waiting to be published. They're kept outside the worker so the handler a reconnect or
+/// reload replaces it with picks them up. The queues are sized from the config at startup.
+#[derive(Clone)]
+struct Spool {
+    schedule: Option<Arc<Mutex<match_queue::MatchQueue>>>,
+    failed: Option<Arc<Mutex<match_queue::MatchQueue>>>,
+}
+
+impl Spool {
+    fn new(conf: &ResolvedConfig) -> Self {
+        let queue = |capacity| Arc::new(Mutex::new(match_queue::MatchQueue::new(capacity)));
+        Spool {
+            schedule: conf.schedule_queue.as_ref().map(|q| queue(q.capacity)),
+            failed: (conf.failed_queue_size > 0).then(|| queue(conf.failed_queue_size)),
+        }
+    }
+}
+
+/// What outlives a connection: the handler's counters and match state, the publishers' and
+/// dispatcher's counters, the stats and reload listeners, and when the process started. Like
+/// `Spool`, it's created once at startup so a reconnect picks up where the last connection left
+/// off.
+struct Session {
+    state: Arc<message_handler::HandlerState>,
+    counters: Arc<webhook_publisher::Counters>,
+    /// Work the dispatcher dropped because it was too far behind.
+    dispatch_dropped: Arc<AtomicU64>,
+    started: std::time::Instant,
+    stats_requests: mpsc::Receiver<()>,
+    reload_requests: mpsc::Receiver<()>,
+}
+
+impl Session {
+    fn new(conf: &ResolvedConfig, config_file: &str) -> Result<Self> {
+        Ok(Session {
+            state: Default::default(),
+            counters: Default::default(),
+            dispatch_dropped: Default::default(),
+            started: std::time::Instant::now(),
+            stats_requests: stats_requests()?,
+            reload_requests: reload_requests(Path::new(config_file), conf.config_poll_interval),
+        })
+    }
+}
+
+/// Posted to the status webhook when a reload is rejected, so a bad config push doesn't go
+/// unnoticed. The body is `template` with `${error}` and `${config_file}` filled in, or a JSON
+/// object with those fields if there's no template.
+#[derive(Clone)]
+struct ReloadFailureReport {
+    url: http::Uri,
+    template: Option<String>,
+}
+
+/// Queues matches that arrive outside the schedule instead of dropping them.
+struct ScheduleQueueConfig {
+    capacity: usize,
+    format: match_queue::FlushFormat,
+}
+
+struct KeepaliveConfig {
+    path: String,
+    interval: Duration,
+}
+
+/// What to do when the server rejects the bot's credentials or bans it.
+#[derive(Clone, Copy)]
+enum AuthFailurePolicy {
+    /// Exit with an error straight away.
+    Abort,
+    /// Reconnect up to `max_retries` times, waiting as between connection attempts, then exit.
+    Retry { max_retries: u32 },
 }
 
+/// The server refused the connection in a way reconnecting won't fix by itself.
+#[derive(Debug)]
+struct AuthFailure(String);
+
+impl std::fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "authentication failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for AuthFailure {}
+
+/// The connection to the server ended without us asking it to, or couldn't be established again
+/// after it did.
+#[derive(Debug)]
+struct Disconnected(String);
+
+impl std::fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "disconnected: {}", self.0)
+    }
+}
+
+impl std::error::Error for Disconnected {}
+
+/// Returns the server's explanation if `message` says the password was wrong, SASL failed, or
+/// the bot is banned.
+fn auth_failure(message: &irc::proto::Message) -> Option<AuthFailure> {
+    match &message.command {
+        irc_client::Command::Response(
+            response @ (irc_client::Response::ERR_PASSWDMISMATCH
+            | irc_client::Response::ERR_YOUREBANNEDCREEP
+            | irc_client::Response::ERR_SASLFAIL),
+            args,
+        ) => Some(AuthFailure(format!(
+            "{:?}: {}",
+            response,
+            args.last().map(String::as_str).unwrap_or_default()
+        ))),
+        _ => None,
+    }
+}
+
+/// A vanity host requested with `<command> <host>` once registration completes. Channels aren't
+/// joined until the server confirms it, so the bot never shows up with its real host.
+#[derive(Clone)]
+struct VhostConfig {
+    command: String,
+    host: String,
+}
+
+/// A connection that stays up this long resets the reconnect attempts and backoff, so only drops
+/// in quick succession count towards `reconnect_max_retries`.
+const RECONNECT_RESET_AFTER: Duration = Duration::from_secs(60);
+
+/// How often a connection error that keeps repeating across reconnects is logged again.
+const RECONNECT_LOG_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How long to hold off joining channels while waiting for the server to confirm a vhost.
+const VHOST_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to retry failed matches and check whether the schedule has opened and queued matches
+/// can be published.
+const QUEUE_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Work waiting for the dispatcher. Once this much is waiting, more is dropped and counted in the
+/// stats rather than holding up reading from the server.
+const DISPATCH_QUEUE_SIZE: usize = 1024;
+
+/// How often correlation starts are checked for timing out, which bounds how late past its
+/// timeout one is dropped or published.
+const CORRELATION_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
 impl ResolvedConfig {
     pub fn new(settings: Config) -> Result<ResolvedConfig> {
-        let headers = settings.get_table("headers").unwrap();
+        // Optional, but an error if it's there and isn't a table.
+        let headers = match settings.get_table("headers") {
+            Ok(headers) => headers,
+            Err(config::ConfigError::NotFound(_)) => Default::default(),
+            Err(e) => return Err(e.into()),
+        };
 
-        let headers =
+        let mut headers =
             headers
                 .into_iter()
                 .fold(HashMap::<&'static str, String>::new(), |mut acc, (k, v)| {
                     acc.insert(Box::leak(k.into_boxed_str()), v.to_string());
                     acc
                 });
+        if let Ok(header) = settings.get_string("instance_header") {
+            headers.insert(
+                Box::leak(header.into_boxed_str()),
+                "${instance}".to_string(),
+            );
+        }
+
+        // The top-level webhook_retry_* keys are overridden by the defaults block, which rules
+        // in turn override. Top-level retry_max_attempts and retry_base_ms are accepted too, as
+        // they're named in rules.
+        let legacy_delivery = delivery::DeliveryConfig {
+            retry: match settings
+                .get_int("webhook_retry_max_attempts")
+                .or_else(|_| settings.get_int("retry_max_attempts"))
+            {
+                Ok(attempts) if attempts > 0 => Some(resolve_retry(&settings, attempts)?),
+                _ => None,
+            },
+            timeout: match settings.get_int("webhook_timeout_ms") {
+                Ok(ms) if ms > 0 => Some(Duration::from_millis(ms.try_into()?)),
+                _ => None,
+            },
+        };
+        let default_delivery = match settings.get_table("defaults") {
+            Ok(mut table) => resolve_delivery(&mut table)?.apply(&legacy_delivery),
+            Err(_) => legacy_delivery,
+        };
+
+        let publisher_options = webhook_publisher::PublisherOptions {
+            signing: match settings.get_string("signing_secret") {
+                Ok(secret) => Some(resolve_signing(&settings, secret)?),
+                Err(_) => None,
+            },
+            concurrency: match settings.get_int("concurrency_max") {
+                Ok(max) => Some(resolve_concurrency(&settings, max)?),
+                Err(_) => None,
+            },
+            body_encoding: match settings
+                .get_string("webhook_body_encoding")
+                .or_else(|_| settings.get_string("body_encoding"))
+                .or_else(|_| settings.get_string("body_format"))
+            {
+                Ok(encoding) => encoding.parse().map_err(anyhow::Error::msg)?,
+                Err(_) => webhook_publisher::BodyEncoding::Raw,
+            },
+            on_missing_group: resolve_on_missing_group(&settings)?,
+            publish_deadline: match settings.get_int("publish_deadline_ms") {
+                Ok(ms) => Some(Duration::from_millis(ms.try_into()?)),
+                Err(_) => None,
+            },
+            retry: default_delivery.retry.clone(),
+            timeout: default_delivery.timeout,
+            retry_budget: match settings.get_float("retry_budget_per_sec") {
+                Ok(rate) => Some(Arc::new(retry_budget::RetryBudget::new(
+                    rate,
+                    settings.get_float("retry_budget_burst").unwrap_or(rate),
+                ))),
+                Err(_) => None,
+            },
+            warn_rate_threshold: settings.get_float("webhook_warn_rate_threshold").ok(),
+            resolve: match settings.get_array("resolve") {
+                Ok(entries) => resolve_overrides(entries)?,
+                Err(_) => Vec::new(),
+            },
+            instance: Some(
+                settings
+                    .get_string("instance_id")
+                    .unwrap_or_else(|_| hostname()),
+            ),
+            allowed_hosts: match settings.get_array("allowed_webhook_hosts") {
+                Ok(hosts) => Some(
+                    hosts
+                        .into_iter()
+                        .map(|host| host.into_string())
+                        .collect::<Result<_, _>>()?,
+                ),
+                Err(_) => None,
+            },
+            multi_match: match settings.get_string("multi_match_mode") {
+                Ok(mode) => mode.parse().map_err(anyhow::Error::msg)?,
+                Err(_) => webhook_publisher::MultiMatchMode::PerMatch,
+            },
+            appended_headers: match settings.get_table("append_headers") {
+                Ok(table) => resolve_appended_headers(table)?,
+                Err(_) => Vec::new(),
+            },
+            capture_headers: if settings.get_bool("captures_as_headers").unwrap_or(false) {
+                Some(resolve_capture_headers(&settings)?)
+            } else {
+                None
+            },
+            expected_status: resolve_expected_status(&settings)?,
+            multipart_parts: match settings.get_array("multipart_parts") {
+                Ok(parts) => resolve_multipart_parts(parts)?,
+                Err(_) => Vec::new(),
+            },
+            json_types: match settings.get_table("json_types") {
+                Ok(table) => resolve_json_types(table)?,
+                Err(_) => HashMap::new(),
+            },
+        };
+        // An array body is JSON, so its elements have to be JSON too.
+        if publisher_options.multi_match == webhook_publisher::MultiMatchMode::SingleArray
+            && matches!(
+                publisher_options.body_encoding,
+                webhook_publisher::BodyEncoding::Base64
+                    | webhook_publisher::BodyEncoding::Form
+                    | webhook_publisher::BodyEncoding::Multipart
+            )
+        {
+            anyhow::bail!("multi_match_mode single_array needs the raw or json body encoding");
+        }
+        if publisher_options.body_encoding == webhook_publisher::BodyEncoding::Multipart
+            && publisher_options.multipart_parts.is_empty()
+        {
+            anyhow::bail!("the multipart body encoding needs multipart_parts");
+        }
+
+        let cooldown = match settings.get_int("cooldown_secs") {
+            Ok(secs) => Some(resolve_cooldown(&settings, secs)?),
+            Err(_) => None,
+        };
+
+        let discovery = match settings.get_string("discover_channels_matching") {
+            Ok(pattern) => Some(DiscoveryConfig {
+                pattern: regex::Regex::new(&pattern)?,
+                max_channels: settings
+                    .get_int("max_auto_join_channels")
+                    .unwrap_or(20)
+                    .try_into()?,
+                interval: Duration::from_secs(
+                    settings
+                        .get_int("discover_interval_secs")
+                        .unwrap_or(3600)
+                        .try_into()?,
+                ),
+            }),
+            Err(_) => None,
+        };
+
+        // Keepalive pings add background traffic, so they're only sent when an interval is set.
+        let keepalive = match settings.get_int("keepalive_ping_secs") {
+            Ok(secs) => Some(KeepaliveConfig {
+                path: settings
+                    .get_string("keepalive_path")
+                    .unwrap_or_else(|_| "/".to_string()),
+                interval: Duration::from_secs(secs.try_into()?),
+            }),
+            Err(_) => None,
+        };
+
+        let webhook_urls = match settings.get_array("webhook_urls") {
+            Ok(urls) => urls
+                .into_iter()
+                .map(|url| Ok(http::Uri::from_str(&url.into_string()?)?))
+                .collect::<Result<Vec<_>>>()?,
+            Err(_) => vec![http::Uri::from_str(&settings.get_string("webhook_url")?)?],
+        };
+        if webhook_urls.is_empty() {
+            anyhow::bail!("webhook_urls must not be empty");
+        }
+
+        // The shutdown and reload failure reports go to status_webhook_url, or the first webhook
+        // if that's unset.
+        let status_url = match settings.get_string("status_webhook_url") {
+            Ok(url) => http::Uri::from_str(&url)?,
+            Err(_) => webhook_urls[0].clone(),
+        };
+        let shutdown_report = settings
+            .get_bool("shutdown_report")
+            .unwrap_or(false)
+            .then(|| status_url.clone());
+        let reload_failure_report = settings
+            .get_bool("report_reload_failures")
+            .unwrap_or(false)
+            .then(|| ReloadFailureReport {
+                url: status_url.clone(),
+                template: settings.get_string("reload_failure_template").ok(),
+            });
+        let status_urls: Vec<&http::Uri> = shutdown_report
+            .iter()
+            .chain(reload_failure_report.as_ref().map(|r| &r.url))
+            .collect();
+
+        let check_urls = |urls: &mut dyn Iterator<Item = &http::Uri>| -> Result<()> {
+            // Off by default for compatibility, but plaintext URLs send any auth headers in the
+            // clear.
+            let require_https = settings.get_bool("require_https_webhooks").unwrap_or(false);
+            for url in urls {
+                if require_https && url.scheme_str() != Some("https") {
+                    anyhow::bail!("require_https_webhooks is set but {} isn't https", url);
+                }
+                if let Some(allowed) = &publisher_options.allowed_hosts {
+                    if !webhook_publisher::host_allowed(allowed, url.host()) {
+                        anyhow::bail!("{} is not in allowed_webhook_hosts", url);
+                    }
+                }
+            }
+            Ok(())
+        };
+        check_urls(&mut webhook_urls.iter().chain(status_urls.iter().copied()))?;
+
+        let heartbeat_interval = match settings.get_int("heartbeat_interval_secs").unwrap_or(300) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs.try_into()?)),
+        };
+        let heartbeat_url = match settings.get_string("heartbeat_url") {
+            Ok(url) => Some(http::Uri::from_str(&url)?),
+            Err(_) => None,
+        };
+        if heartbeat_url.is_some() && heartbeat_interval.is_none() {
+            anyhow::bail!("heartbeat_url needs a non-zero heartbeat_interval_secs");
+        }
+
+        let password_file = settings.get_string("password_file").ok();
+
+        // search_pattern_types applies to search_pattern and search_pattern_file; entries in
+        // patterns can set their own types.
+        let default_kinds = match settings.get_array("search_pattern_types") {
+            Ok(types) => resolve_message_kinds(types)?,
+            Err(_) => vec![message_handler::MessageKind::Privmsg],
+        };
+        let with_default_kinds = |pattern| message_handler::SearchPattern {
+            kinds: Some(default_kinds.clone()),
+            ..message_handler::SearchPattern::new(pattern)
+        };
+
+        // A full_line_pattern is matched against the raw IRC line instead of the message text,
+        // so it's checked against every line unless search_pattern_types is set.
+        let (mut search_patterns, match_target) = match settings.get_string("full_line_pattern") {
+            Ok(pattern) => (
+                vec![message_handler::SearchPattern {
+                    pattern,
+                    kinds: match settings.get_array("search_pattern_types") {
+                        Ok(_) => Some(default_kinds.clone()),
+                        Err(_) => None,
+                    },
+                    require_status: channel_status::SenderStatus::Any,
+                    mapping: None,
+                    occurrences: None,
+                    replacement: None,
+                    channels: None,
+                    server: None,
+                }],
+                message_handler::MatchTarget::FullLine,
+            ),
+            Err(_) => (
+                settings
+                    .get_string("search_pattern")
+                    .into_iter()
+                    .map(with_default_kinds)
+                    .collect(),
+                message_handler::MatchTarget::Content,
+            ),
+        };
+        if let Ok(path) = settings.get_string("search_pattern_file") {
+            search_patterns.extend(
+                message_handler::parse_pattern_file(&std::fs::read_to_string(path)?)
+                    .into_iter()
+                    .map(with_default_kinds),
+            );
+        }
+        let mut pattern_delivery = vec![default_delivery.clone(); search_patterns.len()];
+        let mut pattern_webhooks = vec![PatternWebhook::default(); search_patterns.len()];
+        if let Ok(patterns) = settings.get_array("patterns") {
+            for pattern in patterns {
+                let mut table = pattern.into_table()?;
+                pattern_delivery.push(resolve_delivery(&mut table)?.apply(&default_delivery));
+                pattern_webhooks.push(PatternWebhook {
+                    url: match table.remove("webhook_url") {
+                        Some(url) => Some(http::Uri::from_str(&url.into_string()?)?),
+                        None => None,
+                    },
+                    body_template: table
+                        .remove("body_template")
+                        .map(|t| t.into_string())
+                        .transpose()?,
+                });
+                search_patterns.push(message_handler::SearchPattern {
+                    pattern: table
+                        .remove("pattern")
+                        .or_else(|| table.remove("search_pattern"))
+                        .ok_or_else(|| anyhow::anyhow!("patterns entries need a pattern"))?
+                        .into_string()?,
+                    kinds: Some(match table.remove("types") {
+                        Some(types) => resolve_message_kinds(types.into_array()?)?,
+                        None => default_kinds.clone(),
+                    }),
+                    require_status: match table.remove("require_sender_status") {
+                        Some(status) => {
+                            status.into_string()?.parse().map_err(anyhow::Error::msg)?
+                        }
+                        None => channel_status::SenderStatus::Any,
+                    },
+                    mapping: match table.remove("mappings") {
+                        Some(values) => Some(resolve_mapping(&mut table, values)?),
+                        None => None,
+                    },
+                    occurrences: match table.remove("occurrence_filter") {
+                        Some(filter) => {
+                            Some(filter.into_string()?.parse().map_err(anyhow::Error::msg)?)
+                        }
+                        None => None,
+                    },
+                    replacement: table
+                        .remove("regex_replacement")
+                        .map(|r| r.into_string())
+                        .transpose()?,
+                    channels: match table.remove("channel").or_else(|| table.remove("channels")) {
+                        Some(channels) => Some(match channels.clone().into_array() {
+                            Ok(channels) => channels
+                                .into_iter()
+                                .map(|c| c.into_string())
+                                .collect::<Result<_, _>>()?,
+                            Err(_) => vec![channels.into_string()?],
+                        }),
+                        None => None,
+                    },
+                    server: table
+                        .remove("server")
+                        .map(|s| s.into_string())
+                        .transpose()?,
+                });
+            }
+        }
+        check_urls(&mut pattern_webhooks.iter().filter_map(|w| w.url.as_ref()))?;
+        // Checked here so a bad pattern fails startup with an error, and a reload before it
+        // replaces anything.
+        for pattern in &search_patterns {
+            if let Err(e) = regex::Regex::new(&pattern.pattern) {
+                anyhow::bail!("invalid pattern {:?}: {}", pattern.pattern, e);
+            }
+        }
+        let correlations = match settings.get_array("correlations") {
+            Ok(rules) => resolve_correlations(rules)?,
+            Err(_) => Vec::new(),
+        };
+        // Numeric replies (e.g. 005 or 353) are only published when listed here.
+        let watch_numerics: Vec<u16> = settings.get("watch_numerics").unwrap_or_default();
+        if search_patterns.is_empty() && correlations.is_empty() && watch_numerics.is_empty() {
+            anyhow::bail!(
+                "one of search_pattern, search_pattern_file, full_line_pattern, patterns, correlations, or watch_numerics is required"
+            );
+        }
+
+        let schedule_queue = match settings.get_int("schedule_queue_size") {
+            Ok(size) => {
+                if settings.get_array("schedule").is_err() {
+                    anyhow::bail!("schedule_queue_size needs a schedule");
+                }
+                Some(ScheduleQueueConfig {
+                    capacity: size.try_into()?,
+                    format: match settings.get_string("schedule_flush_format") {
+                        Ok(format) => format.parse().map_err(anyhow::Error::msg)?,
+                        Err(_) => match_queue::FlushFormat::default(),
+                    },
+                })
+            }
+            Err(_) => None,
+        };
+
+        let concat_window = match settings.get_int("concat_window_ms") {
+            Ok(ms) => Some(Duration::from_millis(ms.try_into()?)),
+            Err(_) => None,
+        };
+        if concat_window.is_some() && match_target == message_handler::MatchTarget::FullLine {
+            anyhow::bail!("concat_window_ms only applies to content, not full_line_pattern");
+        }
 
         Ok(ResolvedConfig {
             nickname: settings.get_string("nick")?,
-            nick_password: settings.get_string("password")?,
+            channels: resolve_channels(&settings, discovery.is_some())?,
+            ignore_self: settings.get_bool("ignore_self").unwrap_or(false),
+            allow_nicks: resolve_nicks(&settings, "allow_nicks")?,
+            deny_nicks: resolve_nicks(&settings, "deny_nicks")?.unwrap_or_default(),
+            max_line_length: match settings.get_int("max_line_length") {
+                Ok(len) => len.try_into()?,
+                Err(_) => outgoing::DEFAULT_MAX_LINE_LENGTH,
+            },
+            nick_password: settings
+                .get_string("password")
+                .or_else(|e| match password_file {
+                    Some(_) => Ok(String::new()),
+                    None => Err(e),
+                })?,
+            password_file,
+            irc_server_password: resolve_server_password(&settings)?,
             server: settings.get_string("server")?,
-            search_pattern: settings.get_string("search_pattern")?,
-            webhook_url: http::Uri::from_str(&settings.get_string("webhook_url")?)?,
+            port: match settings.get_int("port") {
+                Ok(port) => Some(u16::try_from(port)?),
+                Err(_) => None,
+            },
+            use_tls: settings.get_bool("use_tls").unwrap_or(true),
+            ping_time: match settings.get_int("ping_time_secs") {
+                Ok(secs) => Some(u32::try_from(secs)?),
+                Err(_) => None,
+            },
+            ping_timeout: match settings.get_int("ping_timeout_secs") {
+                Ok(secs) => Some(u32::try_from(secs)?),
+                Err(_) => None,
+            },
+            vhost: settings
+                .get_string("irc_vhost")
+                .ok()
+                .map(|host| VhostConfig {
+                    command: settings
+                        .get_string("irc_vhost_command")
+                        .unwrap_or_else(|_| "VHOST".to_string()),
+                    host,
+                }),
+            connect_retry: resolve_connection_retry(&settings, "connect", 5)?,
+            reconnect_retry: resolve_connection_retry(&settings, "reconnect", 10)?,
+            auth_failure_policy: match settings.get_string("auth_failure_policy") {
+                Ok(policy) if policy == "retry" => AuthFailurePolicy::Retry {
+                    max_retries: settings
+                        .get_int("auth_failure_max_retries")
+                        .unwrap_or(3)
+                        .try_into()?,
+                },
+                Ok(policy) if policy != "abort" => {
+                    anyhow::bail!("unknown auth_failure_policy: {}", policy)
+                }
+                _ => AuthFailurePolicy::Abort,
+            },
+            search_patterns,
+            pattern_delivery,
+            pattern_webhooks,
+            match_target,
+            content_format: match settings.get_string("content_format") {
+                Ok(format) => format.parse().map_err(anyhow::Error::msg)?,
+                Err(_) => message_handler::ContentFormat::default(),
+            },
+            watch_numerics,
+            webhook_urls,
             body_template: settings.get_string("body_template")?,
             headers,
+            publisher_options,
+            cooldown,
+            discovery,
+            keepalive,
+            part_message: settings
+                .get_string("part_message")
+                .unwrap_or_else(|_| "irc_hook leaving".to_string()),
+            shutdown_report,
+            reload_failure_report,
+            schedule: match settings.get_array("schedule") {
+                Ok(windows) => Some(resolve_schedule(&settings, windows)?),
+                Err(_) => None,
+            },
+            schedule_queue,
+            failed_queue_size: settings
+                .get_int("failed_queue_size")
+                .unwrap_or(1000)
+                .try_into()?,
+            sample_rates: resolve_sample_rates(&settings)?,
+            dedup: match settings.get_int("dedup_window_secs") {
+                Ok(secs) => Some((
+                    Duration::from_secs(secs.try_into()?),
+                    match settings.get_int("dedup_key_group") {
+                        Ok(group) => Some(group.try_into()?),
+                        Err(_) => None,
+                    },
+                )),
+                Err(_) => None,
+            },
+            debug_sample_size: match settings.get_int("debug_sample_size") {
+                Ok(size) => Some(size.try_into()?),
+                Err(_) => None,
+            },
+            mark_first_match: settings.get_bool("mark_first_match").unwrap_or(false),
+            concat_window,
+            correlations,
+            capture_log: match settings.get_string("capture_log") {
+                Ok(path) => Some(CaptureLogConfig {
+                    path,
+                    max_bytes: settings
+                        .get_int("capture_log_max_bytes")
+                        .unwrap_or(10 * 1024 * 1024)
+                        .try_into()?,
+                    keep: settings
+                        .get_int("capture_log_keep")
+                        .unwrap_or(3)
+                        .try_into()?,
+                }),
+                Err(_) => None,
+            },
+            config_poll_interval: match settings.get_int("config_poll_interval_secs") {
+                Ok(secs) => Some(Duration::from_secs(secs.try_into()?)),
+                Err(_) => None,
+            },
+            heartbeat_interval,
+            heartbeat_url,
+            channel_join_delay: Duration::from_millis(
+                settings
+                    .get_int("channel_join_delay_ms")
+                    .unwrap_or(0)
+                    .try_into()?,
+            ),
+            test_body: settings
+                .get_string("test_body")
+                .unwrap_or_else(|_| r#"{"test": true}"#.to_string()),
         })
     }
 }
 
-struct Worker {
-    stream: Pin<Box<dyn Stream<Item = Result<irc::proto::Message, error::Error>>>>,
-    handler: message_handler::MessageHandler,
+fn resolve_signing(settings: &Config, secret: String) -> Result<webhook_publisher::SigningConfig> {
+    let signature_header = settings
+        .get_string("signature_header")
+        .unwrap_or_else(|_| "X-Signature".to_string());
+    let timestamped = settings.get_bool("signing_timestamp").unwrap_or(false);
+
+    let timestamp_header = if timestamped {
+        let header = settings
+            .get_string("timestamp_header")
+            .unwrap_or_else(|_| "X-Timestamp".to_string());
+        Some(http::header::HeaderName::from_str(&header)?)
+    } else {
+        None
+    };
+
+    let default_payload = if timestamped {
+        "${timestamp}.${body}"
+    } else {
+        "${body}"
+    };
+
+    Ok(webhook_publisher::SigningConfig {
+        secret,
+        signature_header: http::header::HeaderName::from_str(&signature_header)?,
+        timestamp_header,
+        payload_template: settings
+            .get_string("signing_payload")
+            .unwrap_or_else(|_| default_payload.to_string()),
+    })
+}
+
+/// The machine's hostname, used as the default `instance_id`.
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn resolve_overrides(entries: Vec<config::Value>) -> Result<Vec<(String, std::net::SocketAddr)>> {
+    entries
+        .into_iter()
+        .map(|entry| {
+            let (host, addr) = webhook_publisher::parse_resolve(&entry.into_string()?)
+                .map_err(anyhow::Error::msg)?;
+            tracing::info!(host, %addr, "resolving webhook host to a static address");
+            Ok((host, addr))
+        })
+        .collect()
+}
+
+/// The delivery settings in the `defaults` block or a `patterns` entry. Each one left out is
+/// inherited.
+fn resolve_delivery(
+    table: &mut HashMap<String, config::Value>,
+) -> Result<delivery::DeliveryOverrides> {
+    let mut millis = |key: &str| -> Result<Option<Duration>> {
+        match table.remove(key) {
+            Some(ms) => Ok(Some(Duration::from_millis(ms.into_int()?.try_into()?))),
+            None => Ok(None),
+        }
+    };
+    let (base, max_delay, timeout) = (
+        millis("retry_base_ms")?,
+        millis("retry_max_delay_ms")?,
+        millis("timeout_ms")?,
+    );
+
+    Ok(delivery::DeliveryOverrides {
+        max_attempts: match table.remove("retry_max_attempts") {
+            Some(attempts) => Some(attempts.into_int()?.try_into()?),
+            None => None,
+        },
+        strategy: match table.remove("retry_strategy") {
+            Some(strategy) => Some(
+                strategy
+                    .into_string()?
+                    .parse()
+                    .map_err(anyhow::Error::msg)?,
+            ),
+            None => None,
+        },
+        base,
+        max_delay,
+        jitter: table
+            .remove("retry_jitter")
+            .map(|j| j.into_bool())
+            .transpose()?,
+        timeout,
+    })
+}
+
+/// `sample_rates` maps values of group `sample_rate_group` (1 if unset) to the fraction of their
+/// matches to publish; other matches are published at `sample_rate`, or all of them if it's unset.
+fn resolve_sample_rates(settings: &Config) -> Result<Option<message_handler::SampleRates>> {
+    let default = settings.get_float("sample_rate").ok();
+    let rates = match settings.get_table("sample_rates") {
+        Ok(table) => table
+            .into_iter()
+            .map(|(value, rate)| Ok((value, rate.into_float()?)))
+            .collect::<Result<HashMap<_, _>>>()?,
+        Err(_) if default.is_none() => return Ok(None),
+        Err(_) => HashMap::new(),
+    };
+    let default = default.unwrap_or(1.0);
+    if !rates
+        .values()
+        .chain([&default])
+        .all(|r| (0.0..=1.0).contains(r))
+    {
+        anyhow::bail!("sample rates must be between 0 and 1");
+    }
+
+    Ok(Some(message_handler::SampleRates {
+        group: settings
+            .get_int("sample_rate_group")
+            .unwrap_or(1)
+            .try_into()?,
+        rates,
+        default,
+    }))
 }
 
-impl Worker {
-    async fn new(conf: &ResolvedConfig) -> Self {
-        let publisher = webhook_publisher::WebhookPublisher::new(
-            conf.webhook_url.clone(),
-            conf.body_template.clone(),
-            conf.headers.clone(),
+fn resolve_capture_headers(settings: &Config) -> Result<webhook_publisher::CaptureHeaders> {
+    let prefix = settings
+        .get_string("capture_header_prefix")
+        .unwrap_or_else(|_| "X-Capture-".to_string());
+    if http::header::HeaderName::from_bytes(format!("{}0", prefix).as_bytes()).is_err() {
+        anyhow::bail!(
+            "capture_header_prefix isn't a valid header name: {}",
+            prefix
         );
+    }
 
-        let handler = message_handler::MessageHandler::new(&conf.search_pattern, publisher);
+    Ok(webhook_publisher::CaptureHeaders {
+        prefix,
+        max: settings
+            .get_int("max_capture_headers")
+            .unwrap_or(10)
+            .try_into()?,
+    })
+}
 
-        Worker {
-            stream: irc_stream(conf).await,
-            handler,
+/// Each `multipart_parts` entry is a table with the part's `name` and the `template` its value is
+/// rendered from.
+fn resolve_multipart_parts(parts: Vec<config::Value>) -> Result<Vec<(String, String)>> {
+    parts
+        .into_iter()
+        .map(|part| {
+            let mut table = part.into_table()?;
+            let field = |table: &mut HashMap<String, config::Value>, key| {
+                table
+                    .remove(key)
+                    .ok_or_else(|| anyhow::anyhow!("multipart_parts entries need a {}", key))?
+                    .into_string()
+                    .map_err(anyhow::Error::from)
+            };
+            let name = field(&mut table, "name")?;
+            if name.is_empty() || name.contains(['"', '\r', '\n']) {
+                anyhow::bail!("invalid multipart part name: {:?}", name);
+            }
+            Ok((name, field(&mut table, "template")?))
+        })
+        .collect()
+}
+
+/// `expected_status` is one status code or a list of them.
+fn resolve_expected_status(settings: &Config) -> Result<Option<Vec<http::StatusCode>>> {
+    let value = match settings.get::<config::Value>("expected_status") {
+        Ok(value) => value,
+        Err(config::ConfigError::NotFound(_)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let codes = match value.clone().into_array() {
+        Ok(values) => values,
+        Err(_) => vec![value],
+    };
+
+    let statuses = codes
+        .into_iter()
+        .map(|code| {
+            let code: u16 = code.into_int()?.try_into()?;
+            Ok(http::StatusCode::from_u16(code)?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if statuses.is_empty() {
+        anyhow::bail!("expected_status is empty");
+    }
+    Ok(Some(statuses))
+}
+
+/// `json_types` maps group indexes to the JSON type they're sent as, e.g. `{ 1 = "number" }`.
+fn resolve_json_types(
+    table: HashMap<String, config::Value>,
+) -> Result<HashMap<usize, webhook_publisher::JsonType>> {
+    table
+        .into_iter()
+        .map(|(idx, json_type)| {
+            let idx = idx
+                .parse()
+                .map_err(|_| anyhow::anyhow!("json_types keys must be group indexes: {}", idx))?;
+            let json_type = json_type
+                .into_string()?
+                .parse()
+                .map_err(anyhow::Error::msg)?;
+            Ok((idx, json_type))
+        })
+        .collect()
+}
+
+/// `append_headers` entries are added alongside any header of the same name. A value can be a
+/// list to send the header several times.
+fn resolve_appended_headers(
+    table: HashMap<String, config::Value>,
+) -> Result<Vec<(&'static str, String)>> {
+    let mut appended = Vec::new();
+    for (name, value) in table {
+        // Names are sent lowercase, which is also what HeaderMap needs for static names.
+        let name = name.to_lowercase();
+        if http::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+            anyhow::bail!("invalid header name in append_headers: {}", name);
+        }
+        let name: &'static str = Box::leak(name.into_boxed_str());
+        match value.clone().into_array() {
+            Ok(values) => {
+                for value in values {
+                    appended.push((name, value.into_string()?));
+                }
+            }
+            Err(_) => appended.push((name, value.into_string()?)),
+        }
+    }
+    Ok(appended)
+}
+
+/// Each `correlations` entry is a table with `start` and `complete` patterns, the `key_group`
+/// they share (1 if unset), `timeout_secs` (300 if unset), and `on_timeout`, `drop` or `publish`.
+fn resolve_correlations(rules: Vec<config::Value>) -> Result<Vec<correlation::CorrelationRule>> {
+    rules
+        .into_iter()
+        .map(|rule| {
+            let mut table = rule.into_table()?;
+            let mut pattern = |key| -> Result<String> {
+                let pattern = table
+                    .remove(key)
+                    .ok_or_else(|| anyhow::anyhow!("correlations entries need a {}", key))?
+                    .into_string()?;
+                if let Err(e) = regex::Regex::new(&pattern) {
+                    anyhow::bail!("invalid pattern {:?}: {}", pattern, e);
+                }
+                Ok(pattern)
+            };
+            Ok(correlation::CorrelationRule {
+                start: pattern("start")?,
+                complete: pattern("complete")?,
+                key_group: match table.remove("key_group") {
+                    Some(group) => group.into_int()?.try_into()?,
+                    None => 1,
+                },
+                timeout: match table.remove("timeout_secs") {
+                    Some(secs) => Duration::from_secs(secs.into_int()?.try_into()?),
+                    None => Duration::from_secs(300),
+                },
+                on_timeout: match table.remove("on_timeout") {
+                    Some(action) => action.into_string()?.parse().map_err(anyhow::Error::msg)?,
+                    None => correlation::TimeoutAction::default(),
+                },
+            })
+        })
+        .collect()
+}
+
+/// A `patterns` entry's `mappings` table applies to the group given by `map_group` (1 if unset),
+/// with unmapped values replaced by `map_default` if it's set.
+fn resolve_mapping(
+    table: &mut HashMap<String, config::Value>,
+    values: config::Value,
+) -> Result<message_handler::GroupMapping> {
+    Ok(message_handler::GroupMapping {
+        group: match table.remove("map_group") {
+            Some(group) => group.into_int()?.try_into()?,
+            None => 1,
+        },
+        values: values
+            .into_table()?
+            .into_iter()
+            .map(|(k, v)| Ok((k, v.into_string()?)))
+            .collect::<Result<_>>()?,
+        default: table
+            .remove("map_default")
+            .map(|d| d.into_string())
+            .transpose()?,
+    })
+}
+
+fn resolve_message_kinds(types: Vec<config::Value>) -> Result<Vec<message_handler::MessageKind>> {
+    types
+        .into_iter()
+        .map(|t| t.into_string()?.parse().map_err(anyhow::Error::msg))
+        .collect()
+}
+
+/// Each `schedule` entry is a table with `start` and `end` times (`HH:MM`) and optional `days`,
+/// all in `schedule_timezone` (UTC by default).
+fn resolve_schedule(settings: &Config, windows: Vec<config::Value>) -> Result<schedule::Schedule> {
+    let windows = windows
+        .into_iter()
+        .map(|window| {
+            let mut table = window.into_table()?;
+            let mut time = |key: &str| -> Result<String> {
+                Ok(table
+                    .remove(key)
+                    .ok_or_else(|| anyhow::anyhow!("schedule entries need a {}", key))?
+                    .into_string()?)
+            };
+            let (start, end) = (time("start")?, time("end")?);
+            let days = match table.remove("days") {
+                Some(days) => Some(
+                    days.into_array()?
+                        .into_iter()
+                        .map(|d| d.into_string())
+                        .collect::<Result<Vec<_>, _>>()?,
+                ),
+                None => None,
+            };
+            schedule::Window::parse(&start, &end, days.as_deref()).map_err(anyhow::Error::msg)
+        })
+        .collect::<Result<_>>()?;
+    let timezone = match settings.get_string("schedule_timezone") {
+        Ok(tz) => tz.parse().map_err(anyhow::Error::msg)?,
+        Err(_) => chrono_tz::UTC,
+    };
+
+    Ok(schedule::Schedule::new(windows, timezone))
+}
+
+/// `on_missing_group` takes precedence over the older `strict_template` flag, whose
+/// `strict_template_on_missing` is `abort` (the default) or `empty`.
+fn resolve_on_missing_group(settings: &Config) -> Result<webhook_publisher::MissingGroup> {
+    if let Ok(missing) = settings.get_string("on_missing_group") {
+        return missing.parse().map_err(anyhow::Error::msg);
+    }
+    if !settings.get_bool("strict_template").unwrap_or(false) {
+        return Ok(webhook_publisher::MissingGroup::Leave);
+    }
+
+    match settings.get_string("strict_template_on_missing").as_deref() {
+        Ok("abort") | Err(_) => Ok(webhook_publisher::MissingGroup::Error),
+        Ok("empty") => Ok(webhook_publisher::MissingGroup::Empty),
+        Ok(other) => anyhow::bail!("unknown strict_template_on_missing: {}", other),
+    }
+}
+
+fn resolve_cooldown(settings: &Config, secs: i64) -> Result<message_handler::CooldownConfig> {
+    let cooldown = Duration::from_secs(secs.try_into()?);
+    let max_cooldown = match settings.get_int("max_cooldown_secs") {
+        Ok(max) => Duration::from_secs(max.try_into()?),
+        Err(_) => cooldown * 10,
+    };
+    let min_cooldown = match settings.get_int("min_cooldown_secs") {
+        Ok(min) => Duration::from_secs(min.try_into()?),
+        Err(_) => cooldown / 10,
+    };
+    // A zero minimum would never double back up.
+    if !cooldown.is_zero() && (min_cooldown.is_zero() || min_cooldown > cooldown) {
+        anyhow::bail!("min_cooldown_secs must be between 1 and cooldown_secs");
+    }
+
+    Ok(message_handler::CooldownConfig {
+        cooldown,
+        min_cooldown,
+        max_cooldown,
+        low_rate_threshold: settings.get_float("low_rate_threshold").ok(),
+        high_rate_threshold: settings.get_float("high_rate_threshold").ok(),
+        ema_alpha: settings.get_float("ema_alpha").unwrap_or(0.1),
+    })
+}
+
+fn resolve_retry(settings: &Config, max_attempts: i64) -> Result<backoff::RetryConfig> {
+    Ok(backoff::RetryConfig {
+        max_attempts: max_attempts.try_into()?,
+        strategy: match settings.get_string("retry_strategy") {
+            Ok(strategy) => strategy.parse().map_err(anyhow::Error::msg)?,
+            Err(_) => backoff::RetryStrategy::Exponential,
+        },
+        base: Duration::from_millis(
+            settings
+                .get_int("webhook_retry_base_ms")
+                .or_else(|_| settings.get_int("retry_base_ms"))
+                .unwrap_or(500)
+                .try_into()?,
+        ),
+        max_delay: Duration::from_millis(
+            settings
+                .get_int("webhook_retry_max_delay_ms")
+                .unwrap_or(30_000)
+                .try_into()?,
+        ),
+        jitter: settings.get_bool("webhook_retry_jitter").unwrap_or(true),
+    })
+}
+
+/// The server password, either given directly as `irc_server_password` or composed from
+/// `bouncer_user`, `bouncer_network`, and `bouncer_password` in the `user/network:password` form
+/// ZNC and similar bouncers expect.
+fn resolve_server_password(settings: &Config) -> Result<Option<String>> {
+    let server_password = settings.get_string("irc_server_password").ok();
+    let Ok(user) = settings.get_string("bouncer_user") else {
+        if settings.get_string("bouncer_network").is_ok()
+            || settings.get_string("bouncer_password").is_ok()
+        {
+            anyhow::bail!("bouncer_network and bouncer_password need a bouncer_user");
+        }
+        return Ok(server_password);
+    };
+    if server_password.is_some() {
+        anyhow::bail!("irc_server_password can't be combined with bouncer_user");
+    }
+
+    let password = settings.get_string("bouncer_password")?;
+    if user.is_empty() || user.contains(['/', ':']) || user.contains(char::is_whitespace) {
+        anyhow::bail!("bouncer_user must be non-empty without '/', ':', or spaces");
+    }
+    if password.is_empty() {
+        anyhow::bail!("bouncer_password must not be empty");
+    }
+    match settings.get_string("bouncer_network") {
+        Ok(network) => {
+            if network.is_empty() || network.contains(':') || network.contains(char::is_whitespace)
+            {
+                anyhow::bail!("bouncer_network must be non-empty without ':' or spaces");
+            }
+            Ok(Some(format!("{}/{}:{}", user, network, password)))
+        }
+        Err(_) => Ok(Some(format!("{}:{}", user, password))),
+    }
+}
+
+/// Connection and reconnection retries take the same strategies as webhook retries, with their
+/// own settings under `prefix`, e.g. `connect_max_retries` and `connect_retry_base_ms`.
+fn resolve_connection_retry(
+    settings: &Config,
+    prefix: &str,
+    default_max_retries: i64,
+) -> Result<backoff::RetryConfig> {
+    let key = |name: &str| format!("{}_{}", prefix, name);
+    Ok(backoff::RetryConfig {
+        max_attempts: settings
+            .get_int(&key("max_retries"))
+            .unwrap_or(default_max_retries)
+            .try_into()?,
+        strategy: match settings.get_string(&key("retry_strategy")) {
+            Ok(strategy) => strategy.parse().map_err(anyhow::Error::msg)?,
+            Err(_) => backoff::RetryStrategy::Exponential,
+        },
+        base: Duration::from_millis(
+            settings
+                .get_int(&key("retry_base_ms"))
+                .unwrap_or(1000)
+                .try_into()?,
+        ),
+        max_delay: Duration::from_millis(
+            settings
+                .get_int(&key("retry_max_delay_ms"))
+                .unwrap_or(60_000)
+                .try_into()?,
+        ),
+        jitter: settings.get_bool(&key("retry_jitter")).unwrap_or(true),
+    })
+}
+
+fn resolve_concurrency(
+    settings: &Config,
+    max: i64,
+) -> Result<concurrency_limiter::ConcurrencyConfig> {
+    let max: usize = max.try_into()?;
+    let min: usize = settings
+        .get_int("concurrency_min")
+        .unwrap_or(1)
+        .try_into()?;
+    let initial = match settings.get_int("concurrency_initial") {
+        Ok(initial) => initial.try_into()?,
+        Err(_) => max,
+    };
+    if min == 0 || min > max {
+        anyhow::bail!("concurrency_min must be between 1 and concurrency_max");
+    }
+    if initial < min || initial > max {
+        anyhow::bail!("concurrency_initial must be between concurrency_min and concurrency_max");
+    }
+
+    Ok(concurrency_limiter::ConcurrencyConfig {
+        initial,
+        min,
+        max,
+        latency_threshold: Duration::from_millis(
+            settings
+                .get_int("concurrency_latency_threshold_ms")
+                .unwrap_or(1000)
+                .try_into()?,
+        ),
+    })
+}
+
+struct Worker<'a> {
+    stream: IrcStream,
+    sender: irc_client::Sender,
+    handler: Arc<message_handler::MessageHandler>,
+    /// Runs the handler's work, which waits on webhooks, off the read loop.
+    dispatcher: Dispatcher,
+    /// The handler's publisher, for stats and the shutdown report.
+    publisher: Arc<webhook_publisher::WebhookPublisher>,
+    discovery: Option<Discovery>,
+    part_message: String,
+    shutdown_report: Option<http::Uri>,
+    reload_failure_report: Option<ReloadFailureReport>,
+    session: &'a mut Session,
+    config_file: String,
+    /// Whether reloads read IRC_HOOK_* environment variables as well as the config file.
+    config_env: bool,
+    heartbeat: Option<tokio::time::Interval>,
+    heartbeat_url: Option<http::Uri>,
+    queue_flush: tokio::time::Interval,
+    /// Ticks every concat window to match lines no later line has ended.
+    buffer_flush: Option<tokio::time::Interval>,
+    /// Ticks while correlations are configured, to time out starts that weren't completed.
+    correlation_expiry: Option<tokio::time::Interval>,
+    last_message: std::time::Instant,
+    vhost: Option<VhostConfig>,
+    /// Set while waiting for the server to confirm the vhost.
+    vhost_deadline: Option<tokio::time::Instant>,
+    /// False until any vhost is confirmed; discovered channels aren't joined before then.
+    joins_ready: bool,
+    /// Channels waiting to be joined, in order.
+    joins: mpsc::UnboundedSender<String>,
+    /// Stopped when the worker is dropped, so a replacement worker doesn't ping twice as often.
+    keepalive: Option<tokio::task::JoinHandle<()>>,
+    /// The nick the server knows us by, which it may change.
+    nick: own_nick::OwnNick,
+    channels: Vec<String>,
+    spool: Spool,
+}
+
+impl Drop for Worker<'_> {
+    fn drop(&mut self) {
+        if let Some(keepalive) = &self.keepalive {
+            keepalive.abort();
+        }
+    }
+}
+
+struct Discovery {
+    channels: channel_discovery::ChannelDiscovery,
+    interval: tokio::time::Interval,
+}
+
+fn new_publisher(conf: &ResolvedConfig) -> webhook_publisher::WebhookPublisher {
+    webhook_publisher::WebhookPublisher::new(
+        conf.webhook_urls[0].clone(),
+        conf.body_template.clone(),
+        conf.headers.clone(),
+        conf.publisher_options.clone(),
+    )
+    .with_round_robin(conf.webhook_urls.clone())
+}
+
+async fn test_webhook(conf: &ResolvedConfig, url: &http::Uri) -> bool {
+    let publisher = new_publisher(conf);
+
+    match publisher.send_test(url, conf.test_body.clone()).await {
+        Ok(response) => {
+            let status = response.status();
+            println!("status: {}", status);
+            println!("{}", response.text().await.unwrap_or_default());
+            status.is_success()
+        }
+        Err(e) => {
+            println!("request failed: {}", e);
+            false
+        }
+    }
+}
+
+/// Replaces the server and nick from the config file with any given on the command line.
+/// Channels are overridden when the settings are loaded, since they're required.
+fn apply_overrides(conf: &mut ResolvedConfig, server: Option<String>, nick: Option<String>) {
+    if let Some(server) = server {
+        tracing::info!(server, configured = conf.server, "overriding server");
+        conf.server = server;
+    }
+    if let Some(nick) = nick {
+        tracing::info!(nick, configured = conf.nickname, "overriding nick");
+        conf.nickname = nick;
+    }
+}
+
+/// `channels` is one channel or a list of them, and can only be left out when channels are
+/// discovered.
+fn resolve_channels(settings: &Config, discovery: bool) -> Result<Vec<String>> {
+    let channels = match settings.get::<config::Value>("channels") {
+        Ok(value) => match value.clone().into_array() {
+            Ok(values) => values
+                .into_iter()
+                .map(|v| v.into_string())
+                .collect::<Result<Vec<_>, _>>()?,
+            Err(_) => vec![value.into_string()?],
+        },
+        Err(config::ConfigError::NotFound(_)) if discovery => return Ok(Vec::new()),
+        Err(config::ConfigError::NotFound(_)) => {
+            anyhow::bail!("no channels configured, set `channels` to the channels to join")
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if channels.is_empty() {
+        anyhow::bail!("`channels` is empty, list at least one channel to join");
+    }
+    Ok(channels)
+}
+
+/// `allow_nicks` and `deny_nicks` are one nick or a list of them.
+fn resolve_nicks(settings: &Config, key: &str) -> Result<Option<Vec<String>>> {
+    let nicks = match settings.get::<config::Value>(key) {
+        Ok(value) => match value.clone().into_array() {
+            Ok(values) => values
+                .into_iter()
+                .map(|v| v.into_string())
+                .collect::<Result<Vec<_>, _>>()?,
+            Err(_) => vec![value.into_string()?],
+        },
+        Err(config::ConfigError::NotFound(_)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    if nicks.is_empty() {
+        anyhow::bail!("`{}` is empty, list at least one nick or leave it out", key);
+    }
+    Ok(Some(nicks))
+}
+
+/// Queues `channels` to be joined, in order.
+fn join_channels(channels: &[String], joins: &mpsc::UnboundedSender<String>) -> Result<()> {
+    for channel in channels {
+        joins.send(channel.clone())?;
+    }
+    Ok(())
+}
+
+/// Builds the handler for the matching settings in `conf`, publishing through `publisher`. These
+/// and the publishing settings are what a reload applies; the connection settings only take
+/// effect on restart.
+async fn new_handler(
+    conf: &ResolvedConfig,
+    sender: &irc_client::Sender,
+    publisher: Arc<webhook_publisher::WebhookPublisher>,
+    nick: &own_nick::OwnNick,
+    spool: &Spool,
+    state: Arc<message_handler::HandlerState>,
+) -> Result<message_handler::MessageHandler> {
+    let mut handler = message_handler::MessageHandler::new(
+        &conf.search_patterns,
+        publisher.clone(),
+        conf.match_target,
+        conf.cooldown.clone(),
+        &conf.watch_numerics,
+        Some(Arc::new(sender.clone())),
+    )?;
+    handler.share_state(state);
+    handler.connected_to(&conf.server);
+    let default_delivery = delivery::DeliveryConfig {
+        retry: conf.publisher_options.retry.clone(),
+        timeout: conf.publisher_options.timeout,
+    };
+    // Rules with their own delivery settings, webhook, or body, or a regex replacement for the
+    // body, get their own publisher.
+    let rules = conf
+        .search_patterns
+        .iter()
+        .zip(conf.pattern_delivery.iter().zip(&conf.pattern_webhooks));
+    for (index, (pattern, (delivery, webhook))) in rules.enumerate() {
+        if *delivery == default_delivery
+            && pattern.replacement.is_none()
+            && webhook.url.is_none()
+            && webhook.body_template.is_none()
+        {
+            continue;
+        }
+        let mut rule_publisher = publisher.with_delivery(delivery.clone());
+        if pattern.replacement.is_some() {
+            rule_publisher = rule_publisher.with_template("${0}".to_string());
+        }
+        if let Some(url) = &webhook.url {
+            rule_publisher = rule_publisher.with_round_robin(vec![url.clone()]);
+        }
+        if let Some(template) = &webhook.body_template {
+            let template = match &conf.publisher_options.instance {
+                Some(instance) => template.replace("${instance}", instance),
+                None => template.clone(),
+            };
+            rule_publisher = rule_publisher.with_template(template);
         }
+        handler.publish_pattern_with(index, Arc::new(rule_publisher), &format!("{:?}", webhook));
+    }
+    if conf.ignore_self {
+        handler.add_filter(Box::new(message_handler::IgnoreSelfFilter::new(
+            nick.clone(),
+        )));
+    }
+    if let Some(nicks) = &conf.allow_nicks {
+        handler.add_filter(Box::new(message_handler::NickAllowlistFilter::new(nicks)));
+    }
+    if !conf.deny_nicks.is_empty() {
+        handler.add_filter(Box::new(message_handler::NickDenylistFilter::new(
+            &conf.deny_nicks,
+        )));
+    }
+    handler.parse_content_as(conf.content_format);
+    if let Some(schedule) = &conf.schedule {
+        handler.publish_only_during(schedule.clone());
+    }
+    if let (Some(config), Some(queue)) = (&conf.schedule_queue, &spool.schedule) {
+        handler.queue_outside_schedule(queue.clone(), config.format);
+    }
+    if let Some(failed) = &spool.failed {
+        handler.keep_failed(failed.clone());
+    }
+    handler.set_max_line_length(conf.max_line_length);
+    if let Some(window) = conf.concat_window {
+        handler.concat_sender_lines(window);
+    }
+    handler.correlate(&conf.correlations)?;
+    if conf.mark_first_match {
+        handler.mark_first_matches();
+    }
+    if let Some((window, key_group)) = conf.dedup {
+        handler.dedup_within(window, key_group);
+    }
+    if let Some(rates) = &conf.sample_rates {
+        handler.sample_by(rates.clone());
+    }
+    if let Some(size) = conf.debug_sample_size {
+        tracing::warn!(size, "sampling matches instead of publishing them");
+        handler.sample_matches(size);
+    }
+    if let Some(capture) = &conf.capture_log {
+        handler.capture_to(
+            capture_log::CaptureLog::open(&capture.path, capture.max_bytes, capture.keep).await?,
+        );
+    }
+
+    Ok(handler)
+}
+
+impl<'a> Worker<'a> {
+    async fn new(
+        conf: &ResolvedConfig,
+        config_file: &str,
+        config_env: bool,
+        spool: &Spool,
+        session: &'a mut Session,
+    ) -> Result<Self> {
+        let (stream, sender) = irc_stream(conf, config_env).await?;
+
+        let publisher = Arc::new(new_publisher(conf).with_counters(session.counters.clone()));
+        let nick = own_nick::OwnNick::new(&conf.nickname);
+        let handler = new_handler(
+            conf,
+            &sender,
+            publisher.clone(),
+            &nick,
+            spool,
+            session.state.clone(),
+        )
+        .await?;
+        let keepalive = match &conf.keepalive {
+            Some(keepalive) => {
+                Some(publisher.spawn_keepalive(&keepalive.path, keepalive.interval)?)
+            }
+            None => None,
+        };
+
+        // The first LIST is sent once registration completes, so the timer starts one interval out.
+        let discovery = conf.discovery.as_ref().map(|d| Discovery {
+            channels: channel_discovery::ChannelDiscovery::new(d.pattern.clone(), d.max_channels),
+            interval: tokio::time::interval_at(
+                tokio::time::Instant::now() + d.interval,
+                d.interval,
+            ),
+        });
+
+        let joins = spawn_joiner(sender.clone(), conf.channel_join_delay);
+
+        Ok(Worker {
+            stream,
+            sender,
+            handler: Arc::new(handler),
+            dispatcher: Dispatcher::spawn(session.dispatch_dropped.clone()),
+            publisher,
+            discovery,
+            part_message: conf.part_message.clone(),
+            shutdown_report: conf.shutdown_report.clone(),
+            reload_failure_report: conf.reload_failure_report.clone(),
+            session,
+            config_file: config_file.to_string(),
+            config_env,
+            queue_flush: tokio::time::interval(QUEUE_FLUSH_INTERVAL),
+            heartbeat_url: conf.heartbeat_url.clone(),
+            buffer_flush: conf.concat_window.map(tokio::time::interval),
+            correlation_expiry: (!conf.correlations.is_empty())
+                .then(|| tokio::time::interval(CORRELATION_EXPIRY_INTERVAL)),
+            heartbeat: conf.heartbeat_interval.map(|interval| {
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval)
+            }),
+            last_message: std::time::Instant::now(),
+            vhost: conf.vhost.clone(),
+            vhost_deadline: None,
+            joins_ready: conf.vhost.is_none(),
+            joins,
+            keepalive,
+            nick,
+            channels: conf.channels.clone(),
+            spool: spool.clone(),
+        })
     }
 
     pub async fn run(&mut self) -> Result<(), anyhow::Error> {
-        while let Some(message) = self.stream.next().await.transpose()? {
-            self.handler.handle_msg(message).await;
+        let Worker {
+            stream,
+            sender,
+            handler,
+            dispatcher,
+            publisher,
+            discovery,
+            part_message,
+            shutdown_report,
+            reload_failure_report,
+            session,
+            config_file,
+            config_env,
+            heartbeat,
+            heartbeat_url,
+            queue_flush,
+            buffer_flush,
+            correlation_expiry,
+            last_message,
+            vhost,
+            vhost_deadline,
+            joins_ready,
+            joins,
+            keepalive: _,
+            nick,
+            channels,
+            spool,
+        } = self;
+        let Session {
+            state,
+            counters,
+            dispatch_dropped: _,
+            started,
+            stats_requests,
+            reload_requests,
+        } = &mut **session;
+
+        loop {
+            tokio::select! {
+                message = stream.next() => {
+                    let message = match message.transpose() {
+                        Ok(Some(message)) => message,
+                        Ok(None) => {
+                            return Err(Disconnected("connection closed by the server".to_string()).into())
+                        }
+                        Err(e) => return Err(Disconnected(e.to_string()).into()),
+                    };
+                    if let Some(failure) = auth_failure(&message) {
+                        return Err(failure.into());
+                    }
+                    if let Some(new_nick) = nick.update(&message) {
+                        tracing::info!(nick = new_nick, "server changed our nick");
+                    }
+
+                    match (&message.command, vhost.as_ref()) {
+                        (
+                            irc_client::Command::Response(
+                                irc_client::Response::RPL_ENDOFMOTD
+                                | irc_client::Response::ERR_NOMOTD,
+                                _,
+                            ),
+                            Some(vhost),
+                        ) if !*joins_ready => {
+                            sender.send(irc_client::Command::Raw(
+                                vhost.command.clone(),
+                                vec![vhost.host.clone()],
+                            ))?;
+                            *vhost_deadline = Some(tokio::time::Instant::now() + VHOST_ACK_TIMEOUT);
+                        }
+                        (
+                            irc_client::Command::Response(irc_client::Response::RPL_HOSTHIDDEN, _),
+                            Some(vhost),
+                        ) if !*joins_ready => {
+                            tracing::info!(host = vhost.host, "vhost set");
+                            *vhost_deadline = None;
+                            *joins_ready = true;
+                            join_channels(channels, joins)?;
+                            if discovery.is_some() {
+                                sender.send(irc_client::Command::LIST(None, None))?;
+                            }
+                        }
+                        (
+                            irc_client::Command::Response(irc_client::Response::RPL_WELCOME, _),
+                            _,
+                        ) if *joins_ready => join_channels(channels, joins)?,
+                        _ => {}
+                    }
+                    if let Some(discovery) = discovery {
+                        discover_channels(discovery, sender, joins, &message, *joins_ready)?;
+                    }
+                    *last_message = std::time::Instant::now();
+                    dispatcher.dispatch(handler, Dispatch::Message(message));
+                }
+                _ = async { discovery.as_mut().unwrap().interval.tick().await }, if discovery.is_some() => {
+                    sender.send(irc_client::Command::LIST(None, None))?;
+                }
+                _ = queue_flush.tick() => {
+                    dispatcher.dispatch(handler, Dispatch::FlushQueues);
+                }
+                _ = async { buffer_flush.as_mut().unwrap().tick().await }, if buffer_flush.is_some() => {
+                    dispatcher.dispatch(handler, Dispatch::FlushBuffered);
+                }
+                _ = async { correlation_expiry.as_mut().unwrap().tick().await }, if correlation_expiry.is_some() => {
+                    dispatcher.dispatch(handler, Dispatch::ExpireCorrelations);
+                }
+                _ = async { heartbeat.as_mut().unwrap().tick().await }, if heartbeat.is_some() => {
+                    let processed = handler.processed_count();
+                    let matched: u64 = handler.match_counts().iter().map(|(_, n)| n).sum();
+                    let timestamp = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    // The loop only runs while the stream is open, so this is always connected;
+                    // last_message_secs shows whether the connection is still carrying traffic.
+                    tracing::info!(
+                        timestamp,
+                        connection = "connected",
+                        last_message_secs = last_message.elapsed().as_secs(),
+                        "irc_hook heartbeat: {} messages processed, {} matched",
+                        processed,
+                        matched
+                    );
+                    // Only sent from here, so the switch also fires when the connection drops.
+                    if let Some(url) = heartbeat_url {
+                        publisher.spawn_heartbeat(url);
+                    }
+                }
+                _ = async { tokio::time::sleep_until(vhost_deadline.unwrap()).await }, if vhost_deadline.is_some() => {
+                    tracing::warn!("vhost wasn't confirmed, joining channels anyway");
+                    *vhost_deadline = None;
+                    *joins_ready = true;
+                    join_channels(channels, joins)?;
+                    if discovery.is_some() {
+                        sender.send(irc_client::Command::LIST(None, None))?;
+                    }
+                }
+                Some(()) = stats_requests.recv() => {
+                    let stats = stats(handler, publisher, dispatcher, started.elapsed());
+                    tracing::info!(stats = %stats, "stats");
+                    if let Some((samples, matches)) = handler.match_samples() {
+                        tracing::info!(matches, "sampled {} matches", samples.len());
+                        for sample in samples {
+                            tracing::info!(sample, "sampled match");
+                        }
+                    }
+                }
+                Some(()) = reload_requests.recv() => {
+                    let reloaded = async {
+                        // Channels aren't rejoined on reload, so the joined ones stand in for any
+                        // given on the command line.
+                        let settings = load_settings(config_file, *config_env, channels)?;
+                        let conf = ResolvedConfig::new(settings)?;
+                        // Only the config is replaced; counters and match state carry on.
+                        let publisher =
+                            Arc::new(new_publisher(&conf).with_counters(counters.clone()));
+                        let handler = new_handler(
+                            &conf,
+                            sender,
+                            publisher.clone(),
+                            nick,
+                            spool,
+                            state.clone(),
+                        )
+                        .await?;
+                        anyhow::Ok((Arc::new(handler), publisher))
+                    };
+                    match reloaded.await {
+                        Ok(reloaded) => {
+                            (*handler, *publisher) = reloaded;
+                            tracing::info!("reloaded config");
+                        }
+                        Err(e) => {
+                            tracing::error!("config reload failed, keeping the current config: {}", e);
+                            if let Some(report) = reload_failure_report {
+                                send_reload_failure_report(publisher, report, config_file, &e).await;
+                            }
+                        }
+                    }
+                }
+                _ = tokio::signal::ctrl_c() => {
+                    tracing::info!("shutting down");
+                    // Configured channels are only joined once any vhost is confirmed.
+                    let mut parting: Vec<&str> = Vec::new();
+                    if *joins_ready {
+                        parting.extend(channels.iter().map(String::as_str));
+                    }
+                    if let Some(discovery) = discovery {
+                        for channel in discovery.channels.joined() {
+                            if !parting.contains(&channel) {
+                                parting.push(channel);
+                            }
+                        }
+                    }
+                    for channel in parting {
+                        sender.send(irc_client::Command::PART(
+                            channel.to_string(),
+                            Some(part_message.clone()),
+                        ))?;
+                    }
+                    // Let what's already been read be handled, so it's published and in the report.
+                    dispatcher.drain().await;
+                    if let Some(url) = shutdown_report {
+                        send_shutdown_report(handler, publisher, dispatcher, url, started.elapsed())
+                            .await;
+                    }
+                    break;
+                }
+            }
         }
 
         Ok(())
     }
 }
 
+/// Sends a message on the returned channel each time the process gets SIGUSR1, so operators can
+/// ask for a stats dump without restarting. Nothing is ever sent on platforms without signals.
+fn stats_requests() -> Result<mpsc::Receiver<()>> {
+    let (tx, rx) = mpsc::channel(1);
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut usr1 = signal(SignalKind::user_defined1())?;
+        tokio::spawn(async move {
+            while usr1.recv().await.is_some() {
+                // A dump is already pending if the channel is full.
+                let _ = tx.try_send(());
+            }
+        });
+    }
+    #[cfg(not(unix))]
+    drop(tx);
+
+    Ok(rx)
+}
+
+/// Sends a message on the returned channel each time the config should be reloaded: on SIGHUP, or
+/// where that isn't available, when polling every `poll_interval` finds the contents of
+/// `config_file` have changed.
+fn reload_requests(config_file: &Path, poll_interval: Option<Duration>) -> mpsc::Receiver<()> {
+    let (tx, rx) = mpsc::channel(1);
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::hangup()) {
+            Ok(mut hangup) => {
+                if poll_interval.is_some() {
+                    tracing::info!("reloading config on SIGHUP instead of polling");
+                }
+                tokio::spawn(async move {
+                    while hangup.recv().await.is_some() {
+                        let _ = tx.try_send(());
+                    }
+                });
+                return rx;
+            }
+            Err(e) => tracing::warn!("can't listen for SIGHUP: {}", e),
+        }
+    }
+
+    if let Some(interval) = poll_interval {
+        tokio::spawn(poll_config(config_file.to_path_buf(), interval, tx));
+    }
+
+    rx
+}
+
+async fn poll_config(path: PathBuf, interval: Duration, changes: mpsc::Sender<()>) {
+    let mut last = config_hash(&path).await.ok();
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+
+    loop {
+        ticks.tick().await;
+        match config_hash(&path).await {
+            Ok(hash) if last.as_ref() != Some(&hash) => {
+                last = Some(hash);
+                if changes.send(()).await.is_err() {
+                    return;
+                }
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("can't read config file for changes: {}", e),
+        }
+    }
+}
+
+async fn config_hash(path: &Path) -> std::io::Result<Vec<u8>> {
+    Ok(Sha256::digest(tokio::fs::read(path).await?).to_vec())
+}
+
+/// Reads `config_file`, overridden by IRC_HOOK_* environment variables when `env` is set, and by
+/// `channels` unless it's empty.
+fn load_settings(config_file: &str, env: bool, channels: &[String]) -> Result<Config> {
+    let mut builder = Config::builder().add_source(config::File::with_name(config_file));
+    if env {
+        builder = builder.add_source(config::Environment::with_prefix("IRC_HOOK"));
+    }
+    if !channels.is_empty() {
+        builder = builder.set_override("channels", channels.to_vec())?;
+    }
+    Ok(builder.build()?)
+}
+
+fn stats(
+    handler: &message_handler::MessageHandler,
+    publisher: &webhook_publisher::WebhookPublisher,
+    dispatcher: &Dispatcher,
+    uptime: Duration,
+) -> serde_json::Value {
+    let match_counts = handler.match_counts();
+    let patterns: Vec<_> = match_counts
+        .iter()
+        .map(|(pattern, matches)| serde_json::json!({ "pattern": pattern, "matches": matches }))
+        .collect();
+
+    serde_json::json!({
+        "uptime_secs": uptime.as_secs(),
+        "messages": handler.processed_count(),
+        "matches": match_counts.iter().map(|(_, n)| n).sum::<u64>(),
+        "published": publisher.published_count(),
+        "failed": publisher.failed_count(),
+        "shed": publisher.shed_count(),
+        "dispatch_dropped": dispatcher.dropped.load(Ordering::Relaxed),
+        "delivery_latency_ms": {
+            "p50": publisher.delivery_latency(50.0).map(|d| d.as_millis() as u64),
+            "p99": publisher.delivery_latency(99.0).map(|d| d.as_millis() as u64),
+        },
+        "patterns": patterns,
+    })
+}
+
+async fn send_reload_failure_report(
+    publisher: &webhook_publisher::WebhookPublisher,
+    report: &ReloadFailureReport,
+    config_file: &str,
+    error: &anyhow::Error,
+) {
+    let body = match &report.template {
+        Some(template) => webhook_publisher::render(
+            template,
+            "",
+            &[],
+            &[
+                ("error", error.to_string()),
+                ("config_file", config_file.to_string()),
+            ],
+        ),
+        None => serde_json::json!({
+            "event": "reload_failed",
+            "config_file": config_file,
+            "error": error.to_string(),
+        })
+        .to_string(),
+    };
+
+    match publisher.send_test(&report.url, body).await {
+        Ok(r) => tracing::info!(status = %r.status(), "sent reload failure report"),
+        Err(e) => tracing::error!("reload failure report error: {}", e),
+    }
+}
+
+async fn send_shutdown_report(
+    handler: &message_handler::MessageHandler,
+    publisher: &webhook_publisher::WebhookPublisher,
+    dispatcher: &Dispatcher,
+    url: &http::Uri,
+    uptime: Duration,
+) {
+    let report = stats(handler, publisher, dispatcher, uptime);
+
+    match publisher.send_test(url, report.to_string()).await {
+        Ok(r) => tracing::info!(status = %r.status(), "sent shutdown report"),
+        Err(e) => tracing::error!("shutdown report error: {}", e),
+    }
+}
+
+fn discover_channels(
+    discovery: &mut Discovery,
+    sender: &irc_client::Sender,
+    joins: &mpsc::UnboundedSender<String>,
+    message: &irc::proto::Message,
+    joins_ready: bool,
+) -> Result<()> {
+    // While joins are held back, the first LIST is sent once they're allowed.
+    if !joins_ready {
+        return Ok(());
+    }
+
+    match &message.command {
+        irc_client::Command::Response(irc_client::Response::RPL_WELCOME, _) => {
+            sender.send(irc_client::Command::LIST(None, None))?;
+        }
+        irc_client::Command::Response(irc_client::Response::RPL_LIST, args) => {
+            // RPL_LIST is "<client> <channel> <visible users> :<topic>".
+            if let Some(channel) = args.get(1) {
+                if discovery.channels.should_join(channel) {
+                    tracing::info!(channel, "joining discovered channel");
+                    joins.send(channel.clone())?;
+                }
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Starts a task that joins the channels sent to it one at a time, in the order they're sent,
+/// waiting `delay` between each. With no delay they go out as fast as they're queued.
+fn spawn_joiner(sender: irc_client::Sender, delay: Duration) -> mpsc::UnboundedSender<String> {
+    let (joins, mut rx) = mpsc::unbounded_channel::<String>();
+
+    tokio::spawn(async move {
+        let mut first = true;
+        while let Some(channel) = rx.recv().await {
+            if !first && !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            first = false;
+            if let Err(e) = sender.send_join(&channel) {
+                tracing::error!(channel, "failed to join channel: {}", e);
+            }
+        }
+    });
+
+    joins
+}
+
+/// Work for the dispatcher to run on a handler.
+enum Dispatch {
+    Message(irc::proto::Message),
+    /// Retry failed matches and publish queued ones if the schedule has opened.
+    FlushQueues,
+    FlushBuffered,
+    ExpireCorrelations,
+}
+
+/// Runs the handler's work one at a time, in the order it's dispatched. Handling a message can
+/// wait on webhook retries and backoff, so it's done on a task of its own rather than in the read
+/// loop, which has to keep reading for PINGs to be answered.
+struct Dispatcher {
+    /// Work goes along with the handler to run it on, so work queued before a reload still runs
+    /// on the handler it was meant for.
+    queue: mpsc::Sender<(Arc<message_handler::MessageHandler>, Dispatch)>,
+    task: tokio::task::JoinHandle<()>,
+    /// Work dropped because the queue was full, counted across connections for the stats.
+    dropped: Arc<AtomicU64>,
+}
+
+impl Dispatcher {
+    fn spawn(dropped: Arc<AtomicU64>) -> Self {
+        let (queue, mut rx) =
+            mpsc::channel::<(Arc<message_handler::MessageHandler>, Dispatch)>(DISPATCH_QUEUE_SIZE);
+
+        let task = tokio::spawn(async move {
+            while let Some((handler, work)) = rx.recv().await {
+                match work {
+                    Dispatch::Message(message) => handler.handle_msg(message).await,
+                    Dispatch::FlushQueues => {
+                        handler.retry_failed().await;
+                        handler.flush_queued().await;
+                    }
+                    Dispatch::FlushBuffered => handler.flush_buffered().await,
+                    Dispatch::ExpireCorrelations => handler.expire_correlations().await,
+                }
+            }
+        });
+
+        Dispatcher {
+            queue,
+            task,
+            dropped,
+        }
+    }
+
+    /// Queues `work` on `handler`, dropping and counting it if the dispatcher is too far behind.
+    fn dispatch(&self, handler: &Arc<message_handler::MessageHandler>, work: Dispatch) {
+        if self.queue.try_send((handler.clone(), work)).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            tracing::warn!(
+                dropped,
+                "dispatch queue is full, dropping work until webhooks catch up"
+            );
+        }
+    }
+
+    /// Waits for the work already queued to be done. Anything dispatched after this is dropped.
+    async fn drain(&mut self) {
+        // Replacing the sender closes the queue, which ends the task once it's empty.
+        self.queue = mpsc::channel(1).0;
+        if let Err(e) = (&mut self.task).await {
+            tracing::error!("dispatcher failed: {}", e);
+        }
+    }
+}
+
+/// Reads the NickServ password when connecting rather than at startup, so a rotated secret in
+/// `password_file` or `IRC_HOOK_PASSWORD` takes effect on the next connection without a restart.
+/// The environment is only consulted when `config_env` is set.
+fn current_nick_password(conf: &ResolvedConfig, config_env: bool) -> Result<String> {
+    if let Some(path) = &conf.password_file {
+        return Ok(std::fs::read_to_string(path)?.trim().to_string());
+    }
+
+    match std::env::var("IRC_HOOK_PASSWORD") {
+        Ok(password) if config_env => Ok(password),
+        _ => Ok(conf.nick_password.clone()),
+    }
+}
+
+type IrcStream = Pin<Box<dyn Stream<Item = Result<irc::proto::Message, error::Error>>>>;
+
+/// Connects and registers with the server, retrying failed attempts per `connect_retry`. Only
+/// returns an error once every attempt has failed; a connection that's later closed by the server
+/// shows up as the end of the stream.
 async fn irc_stream(
     conf: &ResolvedConfig,
-) -> Pin<Box<dyn Stream<Item = Result<irc::proto::Message, error::Error>>>> {
+    config_env: bool,
+) -> Result<(IrcStream, irc_client::Sender)> {
+    let mut attempt = 0;
+    let mut backoff = conf.connect_retry.backoff();
+    loop {
+        match connect(conf, config_env).await {
+            Ok(connected) => return Ok(connected),
+            Err(e) if attempt < conf.connect_retry.max_attempts => {
+                attempt += 1;
+                let delay = backoff.next_delay();
+                tracing::warn!(attempt, ?delay, "failed to connect to IRC server: {}", e);
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn connect(
+    conf: &ResolvedConfig,
+    config_env: bool,
+) -> Result<(IrcStream, irc_client::Sender)> {
     let irc_config = irc_client::Config {
         nickname: Some(conf.nickname.clone()),
-        nick_password: Some(conf.nick_password.clone()),
+        nick_password: Some(current_nick_password(conf, config_env)?),
+        // identify() sends PASS before NICK/USER; the NickServ password is sent after welcome.
+        password: conf.irc_server_password.clone(),
         server: Some(conf.server.clone()),
-        use_tls: Some(true),
+        port: conf.port,
+        use_tls: Some(conf.use_tls),
+        ping_time: conf.ping_time,
+        ping_timeout: conf.ping_timeout,
         ..irc_client::Config::default()
     };
 
-    let mut client = irc_client::Client::from_config(irc_config).await.unwrap();
-    client.identify().unwrap();
+    let mut client = irc_client::Client::from_config(irc_config).await?;
+    client.identify()?;
 
-    Box::pin(client.stream().unwrap())
+    Ok((Box::pin(client.stream()?), client.sender()))
 }
 
 #[tokio::main]
@@ -108,18 +2339,106 @@ async fn main() -> Result<(), anyhow::Error> {
         .finish();
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
-    let settings = Config::builder()
-        .add_source(config::File::with_name(&cli.config_file))
-        .add_source(config::Environment::with_prefix("IRC_HOOK"))
-        .build()
-        .unwrap();
+    let dump = match cli.command {
+        Some(Commands::RenderTemplate(args)) => return render_template(args),
+        Some(Commands::ValidateRegex { pattern }) => return validate_regex(&pattern),
+        Some(Commands::DumpConfig) => true,
+        None => false,
+    };
+
+    // Clap only requires a config file when there's no subcommand.
+    let config_file = cli
+        .config_file
+        .ok_or_else(|| anyhow::anyhow!("dump-config needs --config-file"))?;
+    let settings = load_settings(&config_file, !cli.no_env, &cli.channels)?;
+    tracing::info!(
+        file = config_file,
+        env = if cli.no_env { "disabled" } else { "IRC_HOOK_*" },
+        "config sources"
+    );
+
+    let mut conf = ResolvedConfig::new(settings.clone())?;
+    if !cli.channels.is_empty() {
+        tracing::info!(channels = ?cli.channels, "overriding channels");
+    }
+    apply_overrides(&mut conf, cli.server.clone(), cli.nick.clone());
+
+    if dump {
+        return dump_config(settings, &conf);
+    }
 
-    let conf = ResolvedConfig::new(settings).unwrap();
+    if let Some(url) = cli.test_webhook {
+        let success = test_webhook(&conf, &url).await;
+        std::process::exit(if success { 0 } else { 1 });
+    }
 
     tracing::info!("starting irc_hook");
 
-    let mut worker = Worker::new(&conf).await;
-    worker.run().await
+    let spool = Spool::new(&conf);
+    let mut session = Session::new(&conf, &config_file)?;
+    let mut auth_failures = 0;
+    let mut backoff = conf.connect_retry.backoff();
+    let mut reconnects = 0;
+    let mut reconnect_backoff = conf.reconnect_retry.backoff();
+    let mut connected = false;
+    // A server that's down for a while fails every reconnect the same way.
+    let mut connection_errors = log_coalescer::LogCoalescer::new(RECONNECT_LOG_INTERVAL);
+    loop {
+        let result = match Worker::new(&conf, &config_file, !cli.no_env, &spool, &mut session).await
+        {
+            Ok(mut worker) => {
+                connected = true;
+                let started = std::time::Instant::now();
+                let result = worker.run().await;
+                if started.elapsed() >= RECONNECT_RESET_AFTER {
+                    reconnects = 0;
+                    reconnect_backoff = conf.reconnect_retry.backoff();
+                }
+                result
+            }
+            // A failed first connection is more likely a bad config than an outage, so only
+            // reconnects are retried.
+            Err(e) if connected => Err(Disconnected(e.to_string()).into()),
+            Err(e) => return Err(e),
+        };
+
+        match (&result, conf.auth_failure_policy) {
+            (Err(e), AuthFailurePolicy::Retry { max_retries })
+                if e.is::<AuthFailure>() && auth_failures < max_retries =>
+            {
+                auth_failures += 1;
+                let delay = backoff.next_delay();
+                connection_errors.warn(&format!("{}, reconnecting", e));
+                tracing::debug!(attempt = auth_failures, ?delay, "reconnecting");
+                tokio::time::sleep(delay).await;
+            }
+            (Err(e), _)
+                if e.is::<Disconnected>() && reconnects < conf.reconnect_retry.max_attempts =>
+            {
+                reconnects += 1;
+                let delay = reconnect_backoff.next_delay();
+                connection_errors.warn(&format!("{}, reconnecting", e));
+                tracing::debug!(attempt = reconnects, ?delay, "reconnecting");
+                tokio::time::sleep(delay).await;
+            }
+            _ => return result,
+        }
+
+        // Reconnect with the config file as it is now, so changes reloaded into the last
+        // connection aren't lost.
+        let reloaded =
+            load_settings(&config_file, !cli.no_env, &cli.channels).and_then(ResolvedConfig::new);
+        match reloaded {
+            Ok(mut reloaded) => {
+                apply_overrides(&mut reloaded, cli.server.clone(), cli.nick.clone());
+                conf = reloaded;
+            }
+            Err(e) => tracing::error!(
+                "couldn't reload config, reconnecting with the last one: {}",
+                e
+            ),
+        }
+    }
 }
 
 // #[cfg(test)]
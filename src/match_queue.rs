@@ -0,0 +1,113 @@
+use std::{collections::VecDeque, str::FromStr};
+
+/// How queued matches are published when the schedule opens.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FlushFormat {
+    /// One request for all of them, with a summary line per match as `${0}` and the number of
+    /// matches as `${1}`.
+    #[default]
+    Digest,
+    /// Each match is published as it would have been when it arrived.
+    Each,
+}
+
+impl FromStr for FlushFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "digest" => Ok(FlushFormat::Digest),
+            "each" => Ok(FlushFormat::Each),
+            other => Err(format!("unknown flush format: {}", other)),
+        }
+    }
+}
+
+/// A match held back while the schedule was closed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueuedMatch {
+    /// Identifies the rule that matched, so it's published through that rule's publisher.
+    /// `None` for matches that don't come from a rule, which use the handler's publisher.
+    pub rule: Option<String>,
+    pub channel: String,
+    pub content: String,
+    pub groups: Vec<Vec<String>>,
+    pub vars: Vec<(String, String)>,
+}
+
+/// Matches that arrived outside the schedule, kept in memory until it opens. Once `capacity` are
+/// queued the oldest is dropped for each new one.
+pub struct MatchQueue {
+    capacity: usize,
+    items: VecDeque<QueuedMatch>,
+    dropped: u64,
+}
+
+impl MatchQueue {
+    pub fn new(capacity: usize) -> Self {
+        MatchQueue {
+            capacity,
+            items: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, item: QueuedMatch) {
+        if self.capacity == 0 {
+            self.dropped += 1;
+            return;
+        }
+        if self.items.len() == self.capacity {
+            self.items.pop_front();
+            self.dropped += 1;
+        }
+        self.items.push_back(item);
+    }
+
+    /// Empties the queue, returning the matches in the order they arrived and how many were
+    /// dropped to make room for them.
+    pub fn take(&mut self) -> (Vec<QueuedMatch>, u64) {
+        (
+            self.items.drain(..).collect(),
+            std::mem::take(&mut self.dropped),
+        )
+    }
+}
+
+/// One `<channel>: <content>` line per match.
+pub fn digest(items: &[QueuedMatch]) -> String {
+    items
+        .iter()
+        .map(|item| format!("{}: {}", item.channel, item.content))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued(channel: &str, content: &str) -> QueuedMatch {
+        QueuedMatch {
+            rule: None,
+            channel: channel.to_string(),
+            content: content.to_string(),
+            groups: vec![vec![content.to_string()]],
+            vars: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_drops_oldest_when_full() {
+        let mut queue = MatchQueue::new(2);
+        queue.push(queued("#ops", "disk full"));
+        queue.push(queued("#ops", "disk ok"));
+        queue.push(queued("#dev", "build failed"));
+
+        let (items, dropped) = queue.take();
+        assert_eq!(dropped, 1);
+        assert_eq!(digest(&items), "#ops: disk ok\n#dev: build failed");
+
+        assert_eq!(queue.take(), (Vec::new(), 0));
+    }
+}
@@ -1,82 +1,2259 @@
 use irc::client::prelude::*;
-use regex::Regex;
+use rand::Rng;
+use regex::{Regex, RegexSet, RegexSetBuilder};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use crate::webhook_publisher;
+use crate::{
+    capture_log::CaptureLog,
+    channel_status::{ChannelStatus, SenderStatus},
+    correlation::{CorrelationRule, Pending, TimeoutAction},
+    dedup::Dedup,
+    line_buffer::LineBuffers,
+    match_queue::{self, FlushFormat, MatchQueue, QueuedMatch},
+    outgoing,
+    own_nick::OwnNick,
+    publisher::Publisher,
+    reservoir::Reservoir,
+    schedule::Schedule,
+    webhook_publisher::PublishError,
+};
 
+/// Matches incoming messages and publishes webhooks for them. Mutable state is kept behind
+/// locks so a single handler can be shared between tasks with an `Arc`.
 pub struct MessageHandler {
-    message_publisher: webhook_publisher::WebhookPublisher,
+    message_publisher: Arc<dyn Publisher>,
+    patterns: Vec<Pattern>,
+    /// Every pattern in one set, so a message is scanned once to find the patterns that could
+    /// match it. `None` if the set is too large to compile, in which case each pattern is checked
+    /// on its own.
+    prefilter: Option<RegexSet>,
+    /// The patterns to check, in order, for each kind of message. `None` is any other line.
+    index: HashMap<Option<MessageKind>, Vec<usize>>,
+    /// Kept so patterns set later get their own cooldowns.
+    cooldown: Option<CooldownConfig>,
+    match_target: MatchTarget,
+    content_format: ContentFormat,
+    /// Only tracked when a pattern requires a sender status.
+    statuses: Option<Mutex<ChannelStatus>>,
+    /// The server the handler's connection is to, lowercased. Patterns scoped to a server only
+    /// match while this is set to it.
+    server: Option<String>,
+    watch_numerics: Vec<u16>,
+    filters: Vec<Box<dyn MessageFilter>>,
+    sender: Option<Arc<Sender>>,
+    state: Arc<HandlerState>,
+    /// When set, matches are sampled here instead of being published.
+    samples: Option<Mutex<Reservoir<String>>>,
+    capture: Option<CaptureLog>,
+    /// When set, matches outside the schedule aren't published.
+    schedule: Option<Schedule>,
+    /// When set, matches outside the schedule are kept here until it opens.
+    queue: Option<Arc<Mutex<MatchQueue>>>,
+    /// When set, matches that fail to publish are kept here to retry.
+    failed: Option<Arc<Mutex<MatchQueue>>>,
+    flush_format: FlushFormat,
+    /// Whether matches get a `${first}` variable.
+    mark_first: bool,
+    /// When set, only a random fraction of matches are published.
+    sample_rates: Option<SampleRates>,
+    /// When set, repeats of a match within the window are dropped, keyed on the group or the
+    /// whole content.
+    dedup: Option<(Duration, Option<usize>)>,
+    /// When set, consecutive lines from each sender are joined before matching, until the sender
+    /// has been quiet this long.
+    concat_timeout: Option<Duration>,
+    /// Messages the bot sends are split to keep each line within this many bytes.
+    max_line_length: usize,
+    correlations: Vec<Correlation>,
+}
+
+/// A compiled correlation rule and the events it has seen start.
+struct Correlation {
+    start: Regex,
+    complete: Regex,
+    key_group: usize,
+    timeout: Duration,
+    on_timeout: TimeoutAction,
+    pending: SharedPending,
+}
+
+/// A correlation rule's pending events, shared by the handlers that check the rule.
+type SharedPending = Arc<Mutex<Pending<Started>>>;
+
+/// An event waiting for its completion.
+struct Started {
+    channel: String,
+    groups: Vec<String>,
+    at: Instant,
+}
+
+/// What a handler counts and remembers between messages, kept apart from its config so it can
+/// outlive the handler. Giving the handler that replaces this one after a reconnect or reload the
+/// same state keeps `${seq}` counting and keeps `${first}`, dedup windows, cooldowns, pending
+/// correlations, buffered lines, and the counters. A pattern's state is kept by the whole rule,
+/// not just its regex, so only rules a reload changes start over, and rules that share a regex
+/// but differ otherwise don't share a state.
+pub struct HandlerState {
+    processed: AtomicU64,
+    /// The last `${seq}` handed out. Each matched message gets the next one.
+    seq: AtomicU64,
+    patterns: Mutex<HashMap<String, Arc<PatternState>>>,
+    /// Only used when deduplicating, with the handler's window.
+    dedup: Mutex<Dedup>,
+    /// Only used when joining lines, with the handler's timeout.
+    line_buffers: Mutex<LineBuffers<Message>>,
+    /// Keyed on the rule's start and complete patterns.
+    pending: Mutex<HashMap<(String, String), SharedPending>>,
+}
+
+impl Default for HandlerState {
+    fn default() -> Self {
+        HandlerState {
+            processed: AtomicU64::new(0),
+            seq: AtomicU64::new(0),
+            patterns: Mutex::new(HashMap::new()),
+            dedup: Mutex::new(Dedup::new(Duration::ZERO)),
+            line_buffers: Mutex::new(LineBuffers::new(Duration::ZERO)),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl HandlerState {
+    /// The state of the pattern with `key`, with its cooldown switched to `cooldown`.
+    fn pattern(&self, key: &str, cooldown: &Option<CooldownConfig>) -> Arc<PatternState> {
+        let state = self
+            .patterns
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        let mut current = state.cooldown.lock().unwrap();
+        *current = match (current.take(), cooldown) {
+            (Some(mut existing), Some(config)) => {
+                existing.reconfigure(config.clone());
+                Some(existing)
+            }
+            (None, Some(config)) => Some(Cooldown::new(config.clone())),
+            (_, None) => None,
+        };
+        drop(current);
+        state
+    }
+
+    /// The events pending for the rule with `start` and `complete`, timed out after `timeout`.
+    fn pending(&self, start: &str, complete: &str, timeout: Duration) -> SharedPending {
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .entry((start.to_string(), complete.to_string()))
+            .or_insert_with(|| Arc::new(Mutex::new(Pending::new(timeout))))
+            .clone();
+        pending.lock().unwrap().set_timeout(timeout);
+        pending
+    }
+}
+
+/// A search pattern's state, kept in `HandlerState`.
+#[derive(Default)]
+struct PatternState {
+    matches: AtomicU64,
+    /// Set once the pattern has published a match.
+    seen_any: AtomicBool,
+    /// Only set when the handler has a cooldown.
+    cooldown: Mutex<Option<Cooldown>>,
+}
+
+/// Who sent a message, where, and when. Matches publish it as `${nick}` and `${timestamp}`
+/// alongside `${channel}`, unless a named group of the pattern has the same name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MessageContext {
+    pub nick: Option<String>,
+    /// The channel or nick the message was sent to.
+    pub channel: Option<String>,
+    /// When the message was received, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl MessageContext {
+    pub fn new(msg: &Message) -> Self {
+        MessageContext {
+            nick: msg.source_nickname().map(str::to_string),
+            channel: get_target(msg).map(str::to_string),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+
+    /// The template variables for the context. `${channel}` comes from the channel a match is
+    /// published for instead.
+    fn vars(&self) -> Vec<(String, String)> {
+        let mut vars = Vec::new();
+        if let Some(nick) = &self.nick {
+            vars.push(("nick".to_string(), nick.clone()));
+        }
+        vars.push(("timestamp".to_string(), self.timestamp.to_string()));
+        vars
+    }
+}
+
+/// The fraction of matches to publish, by the value of one capture group (after any mapping),
+/// e.g. all `error` lines but 1% of `info` ones. Matches whose value isn't listed, or that don't
+/// have the group, are published at the `default` rate.
+#[derive(Clone, Debug)]
+pub struct SampleRates {
+    pub group: usize,
+    pub rates: HashMap<String, f64>,
+    pub default: f64,
+}
+
+impl SampleRates {
+    fn rate(&self, groups: &[Vec<String>]) -> f64 {
+        groups
+            .first()
+            .and_then(|g| g.get(self.group))
+            .and_then(|value| self.rates.get(value))
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    fn keep(&self, groups: &[Vec<String>]) -> bool {
+        rand::thread_rng().gen_bool(self.rate(groups).clamp(0.0, 1.0))
+    }
+}
+
+/// Decides whether a message is checked against the search patterns at all. Filters run in the
+/// order they were added and the first one to return false skips the message.
+pub trait MessageFilter: Send + Sync {
+    fn should_process(&self, msg: &Message) -> bool;
+}
+
+/// Only processes messages sent by one of the given nicks, compared case-insensitively.
+pub struct NickAllowlistFilter {
+    nicks: HashSet<String>,
+}
+
+impl NickAllowlistFilter {
+    pub fn new(nicks: &[impl AsRef<str>]) -> Self {
+        NickAllowlistFilter {
+            nicks: nicks.iter().map(|n| n.as_ref().to_lowercase()).collect(),
+        }
+    }
+}
+
+impl MessageFilter for NickAllowlistFilter {
+    fn should_process(&self, msg: &Message) -> bool {
+        msg.source_nickname()
+            .is_some_and(|nick| self.nicks.contains(&nick.to_lowercase()))
+    }
+}
+
+/// Skips messages sent by any of the given nicks, compared case-insensitively. Messages without
+/// a sender are processed.
+pub struct NickDenylistFilter {
+    nicks: HashSet<String>,
+}
+
+impl NickDenylistFilter {
+    pub fn new(nicks: &[impl AsRef<str>]) -> Self {
+        NickDenylistFilter {
+            nicks: nicks.iter().map(|n| n.as_ref().to_lowercase()).collect(),
+        }
+    }
+}
+
+impl MessageFilter for NickDenylistFilter {
+    fn should_process(&self, msg: &Message) -> bool {
+        msg.source_nickname()
+            .is_none_or(|nick| !self.nicks.contains(&nick.to_lowercase()))
+    }
+}
+
+/// Skips messages the bot sent itself, following its nick as it changes.
+pub struct IgnoreSelfFilter {
+    nick: OwnNick,
+}
+
+impl IgnoreSelfFilter {
+    pub fn new(nick: OwnNick) -> Self {
+        IgnoreSelfFilter { nick }
+    }
+}
+
+impl MessageFilter for IgnoreSelfFilter {
+    fn should_process(&self, msg: &Message) -> bool {
+        msg.source_nickname().is_none_or(|nick| !self.nick.is(nick))
+    }
+}
+
+/// Only processes messages sent to one of the given channels, compared case-insensitively.
+pub struct ChannelFilter {
+    channels: HashSet<String>,
+}
+
+impl ChannelFilter {
+    pub fn new(channels: &[impl AsRef<str>]) -> Self {
+        ChannelFilter {
+            channels: channels.iter().map(|c| c.as_ref().to_lowercase()).collect(),
+        }
+    }
+}
+
+impl MessageFilter for ChannelFilter {
+    fn should_process(&self, msg: &Message) -> bool {
+        get_target(msg).is_some_and(|target| self.channels.contains(&target.to_lowercase()))
+    }
+}
+
+/// Only processes messages whose command is one of the given ones, e.g. `PRIVMSG` or `NOTICE`.
+pub struct MessageTypeFilter {
+    commands: HashSet<String>,
+}
+
+impl MessageTypeFilter {
+    pub fn new(commands: &[impl AsRef<str>]) -> Self {
+        MessageTypeFilter {
+            commands: commands.iter().map(|c| c.as_ref().to_uppercase()).collect(),
+        }
+    }
+}
+
+impl MessageFilter for MessageTypeFilter {
+    fn should_process(&self, msg: &Message) -> bool {
+        self.commands.contains(&command_name(msg))
+    }
+}
+
+/// The kinds of chat message a search pattern can be limited to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    Privmsg,
+    Notice,
+    /// A CTCP ACTION, sent with `/me`.
+    Action,
+}
+
+impl FromStr for MessageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "privmsg" => Ok(MessageKind::Privmsg),
+            "notice" => Ok(MessageKind::Notice),
+            "action" => Ok(MessageKind::Action),
+            other => Err(format!("unknown message type: {}", other)),
+        }
+    }
+}
+
+/// A search pattern and the kinds of message it's checked against. `kinds` of `None` checks every
+/// line, including ones that aren't chat messages.
+#[derive(Clone, Debug)]
+pub struct SearchPattern {
+    pub pattern: String,
+    pub kinds: Option<Vec<MessageKind>>,
+    /// The status the sender needs in the channel the message was sent to.
+    pub require_status: SenderStatus,
+    pub mapping: Option<GroupMapping>,
+    /// When set, only these matches on a line are published.
+    pub occurrences: Option<OccurrenceFilter>,
+    /// When set, the match is published as a single group holding the content with each match
+    /// replaced by this, using the regex crate's replacement syntax (`$1`, `${name}`, `$$`)
+    /// rather than our `${N}` templating. Group mappings don't apply to it.
+    pub replacement: Option<String>,
+    /// When set, the pattern is only checked against messages sent to these channels, compared
+    /// case-insensitively.
+    pub channels: Option<Vec<String>>,
+    /// When set, the pattern is only checked while the handler is connected to this server,
+    /// compared case-insensitively.
+    pub server: Option<String>,
+}
+
+/// Which of the matches on a line are published, by position starting from 1, e.g. `2`, `2,3`,
+/// `2-4`, or `3-` for the third onwards. Positions past the last match are ignored: a line with
+/// fewer matches publishes only the selected ones it has, and is treated as not matching if it
+/// has none of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OccurrenceFilter {
+    positions: Vec<RangeInclusive<usize>>,
+}
+
+impl OccurrenceFilter {
+    fn contains(&self, position: usize) -> bool {
+        self.positions.iter().any(|range| range.contains(&position))
+    }
+}
+
+impl FromStr for OccurrenceFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let position = |p: &str| match p.trim().parse::<usize>() {
+            Ok(0) | Err(_) => Err(format!("invalid occurrence: {}", p)),
+            Ok(n) => Ok(n),
+        };
+        let positions = s
+            .split(',')
+            .map(|part| {
+                let range = match part.split_once('-') {
+                    Some((start, end)) if end.trim().is_empty() => position(start)?..=usize::MAX,
+                    Some((start, end)) => position(start)?..=position(end)?,
+                    None => position(part)?..=position(part)?,
+                };
+                if range.is_empty() {
+                    return Err(format!("occurrence range ends before it starts: {}", part));
+                }
+                Ok(range)
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(OccurrenceFilter { positions })
+    }
+}
+
+/// Translates the value captured by one group before it's templated, e.g. `OK` to
+/// `:white_check_mark:`. Values without a mapping are replaced with `default` if it's set and
+/// passed through unchanged otherwise.
+#[derive(Clone, Debug)]
+pub struct GroupMapping {
+    pub group: usize,
+    pub values: HashMap<String, String>,
+    pub default: Option<String>,
+}
+
+impl Pattern {
+    /// The match groups to publish for `content`, after any mapping.
+    fn groups(&self, content: &str) -> Vec<Vec<String>> {
+        let mut groups = match_groups(&self.re, content, self.occurrences.as_ref());
+        if let (Some(replacement), false) = (&self.replacement, groups.is_empty()) {
+            return vec![vec![self.replace(content, replacement)]];
+        }
+        if let Some(mapping) = &self.mapping {
+            mapping.apply(&mut groups);
+        }
+        groups
+    }
+
+    /// The values of the pattern's named groups in its first selected occurrence, after any
+    /// mapping, for `${name}` placeholders. Named groups that didn't take part in the match are
+    /// left out, so their placeholders stay unresolved.
+    fn named_groups(&self, content: &str) -> Vec<(String, String)> {
+        if self.replacement.is_some() || self.re.capture_names().flatten().next().is_none() {
+            return Vec::new();
+        }
+        let Some((_, caps)) = self.re.captures_iter(content).enumerate().find(|(idx, _)| {
+            self.occurrences
+                .as_ref()
+                .is_none_or(|o| o.contains(idx + 1))
+        }) else {
+            return Vec::new();
+        };
+
+        self.re
+            .capture_names()
+            .enumerate()
+            .filter_map(|(idx, name)| {
+                let value = caps.get(idx)?.as_str();
+                let value = match &self.mapping {
+                    Some(mapping) if mapping.group == idx => mapping.map(value),
+                    _ => value.to_string(),
+                };
+                Some((name?.to_string(), value))
+            })
+            .collect()
+    }
+
+    /// `Regex::replace_all`, leaving matches that aren't selected occurrences as they were.
+    fn replace(&self, content: &str, replacement: &str) -> String {
+        let mut position = 0;
+        self.re
+            .replace_all(content, |caps: &regex::Captures| {
+                position += 1;
+                let mut replaced = String::new();
+                match &self.occurrences {
+                    Some(occurrences) if !occurrences.contains(position) => {
+                        replaced.push_str(&caps[0])
+                    }
+                    _ => caps.expand(replacement, &mut replaced),
+                }
+                replaced
+            })
+            .into_owned()
+    }
+}
+
+impl GroupMapping {
+    fn apply(&self, groups: &mut [Vec<String>]) {
+        for value in groups.iter_mut().filter_map(|g| g.get_mut(self.group)) {
+            *value = self.map(value);
+        }
+    }
+
+    fn map(&self, value: &str) -> String {
+        match (self.values.get(value), &self.default) {
+            (Some(mapped), _) | (None, Some(mapped)) => mapped.clone(),
+            (None, None) => value.to_string(),
+        }
+    }
+}
+
+impl SearchPattern {
+    /// A pattern checked against PRIVMSGs only.
+    pub fn new(pattern: impl Into<String>) -> Self {
+        SearchPattern {
+            pattern: pattern.into(),
+            kinds: Some(vec![MessageKind::Privmsg]),
+            require_status: SenderStatus::Any,
+            mapping: None,
+            occurrences: None,
+            replacement: None,
+            channels: None,
+            server: None,
+        }
+    }
+}
+
+/// A compiled search pattern. Each pattern has its own cooldown.
+struct Pattern {
     re: Regex,
+    /// Everything about the rule the pattern was compiled from, which its state is kept by.
+    rule: String,
+    /// Where the pattern's own publisher sends matches, which its state is also kept by.
+    destination: String,
+    /// Identifies the pattern's state in `HandlerState`. See `bind_patterns`.
+    key: String,
+    state: Arc<PatternState>,
+    require_status: SenderStatus,
+    mapping: Option<GroupMapping>,
+    occurrences: Option<OccurrenceFilter>,
+    replacement: Option<String>,
+    /// Lowercased.
+    channels: Option<HashSet<String>>,
+    /// Lowercased.
+    server: Option<String>,
+    /// Used instead of the handler's publisher when set.
+    publisher: Option<Arc<dyn Publisher>>,
+}
+
+/// What the search pattern is run against.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MatchTarget {
+    /// The message text, e.g. `hello world` for `PRIVMSG #channel :hello world`. Only PRIVMSGs
+    /// and NOTICEs have any.
+    #[default]
+    Content,
+    /// The whole raw IRC line without the trailing CRLF, in the form
+    /// `[:<prefix> ]<command> <params...>[ :<trailing>]`, e.g.
+    /// `:nick!user@host PRIVMSG #channel :hello world`.
+    FullLine,
+}
+
+/// How the matched text is interpreted beyond the search pattern.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ContentFormat {
+    #[default]
+    Text,
+    /// JSON, e.g. from a bridge bot. Fields are available to templates as `${json:path.to.field}`
+    /// and text that doesn't parse is skipped.
+    Json,
+}
+
+impl FromStr for ContentFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ContentFormat::Text),
+            "json" => Ok(ContentFormat::Json),
+            other => Err(format!("unknown content format: {}", other)),
+        }
+    }
+}
+
+/// Settings for suppressing matches that arrive too soon after the last published one. The
+/// cooldown adapts to traffic: an exponential moving average of the interval between matches is
+/// converted to a rate in matches/hour, and the cooldown is halved (down to `min_cooldown`) when
+/// the rate drops below `low_rate_threshold` or doubled (up to `max_cooldown`) when it rises
+/// above `high_rate_threshold`.
+#[derive(Clone)]
+pub struct CooldownConfig {
+    pub cooldown: Duration,
+    pub min_cooldown: Duration,
+    pub max_cooldown: Duration,
+    pub low_rate_threshold: Option<f64>,
+    pub high_rate_threshold: Option<f64>,
+    pub ema_alpha: f64,
+}
+
+struct Cooldown {
+    config: CooldownConfig,
+    current: Duration,
+    ema_interval_secs: Option<f64>,
+    last_match: Option<Instant>,
+    last_published: Option<Instant>,
+}
+
+impl Cooldown {
+    fn new(config: CooldownConfig) -> Self {
+        Cooldown {
+            current: config.cooldown,
+            config,
+            ema_interval_secs: None,
+            last_match: None,
+            last_published: None,
+        }
+    }
+
+    /// Records a match at `now` and returns whether it may be published.
+    fn allow(&mut self, now: Instant) -> bool {
+        if let Some(last_match) = self.last_match {
+            let interval = now.duration_since(last_match).as_secs_f64();
+            let ema = match self.ema_interval_secs {
+                Some(ema) => self.config.ema_alpha * interval + (1.0 - self.config.ema_alpha) * ema,
+                None => interval,
+            };
+            self.ema_interval_secs = Some(ema);
+            self.adapt(ema);
+        }
+        self.last_match = Some(now);
+
+        if let Some(last_published) = self.last_published {
+            if now.duration_since(last_published) < self.current {
+                return false;
+            }
+        }
+        self.last_published = Some(now);
+
+        true
+    }
+
+    /// Switches to `config`, keeping the current cooldown within its bounds.
+    fn reconfigure(&mut self, config: CooldownConfig) {
+        self.current = self
+            .current
+            .min(config.max_cooldown)
+            .max(config.min_cooldown);
+        self.config = config;
+    }
+
+    fn adapt(&mut self, ema_interval_secs: f64) {
+        let rate_per_hour = 3600.0 / ema_interval_secs.max(f64::EPSILON);
+
+        if matches!(self.config.low_rate_threshold, Some(low) if rate_per_hour < low) {
+            self.current = (self.current / 2).max(self.config.min_cooldown);
+        } else if matches!(self.config.high_rate_threshold, Some(high) if rate_per_hour > high) {
+            self.current = (self.current * 2).min(self.config.max_cooldown);
+        }
+    }
 }
 
-impl MessageHandler {
-    pub fn new(
-        search_pattern: &str,
-        message_publisher: webhook_publisher::WebhookPublisher,
-    ) -> Self {
-        MessageHandler {
-            message_publisher,
-            re: Regex::new(search_pattern).unwrap(),
+/// What became of a match passed to `publish_match`.
+#[derive(Debug, PartialEq)]
+enum PublishOutcome {
+    Published,
+    /// Failed in a way that may succeed later, e.g. while the endpoint is down.
+    Retryable,
+    /// Failed in a way retrying won't fix, e.g. a 4xx or a render error.
+    Dropped,
+}
+
+impl MessageHandler {
+    pub fn new(
+        search_patterns: &[SearchPattern],
+        message_publisher: Arc<dyn Publisher>,
+        match_target: MatchTarget,
+        cooldown: Option<CooldownConfig>,
+        watch_numerics: &[u16],
+        sender: Option<Arc<Sender>>,
+    ) -> Result<Self, regex::Error> {
+        let statuses = search_patterns
+            .iter()
+            .any(|p| p.require_status != SenderStatus::Any)
+            .then(|| Mutex::new(ChannelStatus::default()));
+        let state = Arc::new(HandlerState::default());
+
+        Ok(MessageHandler {
+            message_publisher,
+            patterns: compile_patterns(search_patterns, &cooldown, &state)?,
+            prefilter: compile_prefilter(search_patterns),
+            index: index_patterns(search_patterns),
+            cooldown,
+            match_target,
+            content_format: ContentFormat::default(),
+            statuses,
+            server: None,
+            watch_numerics: watch_numerics.to_vec(),
+            filters: Vec::new(),
+            sender,
+            state,
+            samples: None,
+            capture: None,
+            schedule: None,
+            queue: None,
+            failed: None,
+            flush_format: FlushFormat::default(),
+            mark_first: false,
+            sample_rates: None,
+            dedup: None,
+            max_line_length: outgoing::DEFAULT_MAX_LINE_LENGTH,
+            concat_timeout: None,
+            correlations: Vec::new(),
+        })
+    }
+
+    /// Adds a filter that runs after any already added. Filters don't apply to watched numerics.
+    pub fn add_filter(&mut self, filter: Box<dyn MessageFilter>) {
+        self.filters.push(filter);
+    }
+
+    /// Publishes only a random fraction of matches, at rates that can depend on a capture.
+    pub fn sample_by(&mut self, rates: SampleRates) {
+        self.sample_rates = Some(rates);
+    }
+
+    /// Publishes only the first match of each pattern with the same value of `key_group`, or the
+    /// same content if that's `None`, within `window`. Matches without the group aren't
+    /// deduplicated.
+    pub fn dedup_within(&mut self, window: Duration, key_group: Option<usize>) {
+        self.state.dedup.lock().unwrap().set_window(window);
+        self.dedup = Some((window, key_group));
+    }
+
+    /// Keeps a random sample of up to `size` matched messages instead of publishing them, for
+    /// checking what a pattern matches on live traffic.
+    pub fn sample_matches(&mut self, size: usize) {
+        self.samples = Some(Mutex::new(Reservoir::new(size)));
+    }
+
+    /// Parses the matched text as `format` before checking it against the search patterns.
+    pub fn parse_content_as(&mut self, format: ContentFormat) {
+        self.content_format = format;
+    }
+
+    /// Only publishes matches during `schedule`'s windows. Matches outside them are still counted.
+    pub fn publish_only_during(&mut self, schedule: Schedule) {
+        self.schedule = Some(schedule);
+    }
+
+    /// Queues up to `capacity` matches that arrive while the schedule is closed, instead of
+    /// dropping them, for `flush_queued` to publish as `format` once it opens. Giving the handler
+    /// that replaces this one the same queue, e.g. after a reconnect, keeps what's queued.
+    pub fn queue_outside_schedule(&mut self, queue: Arc<Mutex<MatchQueue>>, format: FlushFormat) {
+        self.queue = Some(queue);
+        self.flush_format = format;
+    }
+
+    /// Joins consecutive lines from the same sender in the same channel, with newlines between
+    /// them, and matches them together once the sender has been quiet for `timeout`. Other
+    /// senders' lines in between don't end the run, so a bot's multi-line output can be matched
+    /// even with chatter interleaved. `flush_buffered` should be called at least every `timeout`
+    /// to match runs no further line has ended. Only applies when matching content.
+    pub fn concat_sender_lines(&mut self, timeout: Duration) {
+        self.state.line_buffers.lock().unwrap().set_timeout(timeout);
+        self.concat_timeout = Some(timeout);
+    }
+
+    /// Matches the joined lines of every sender that has been quiet for the timeout.
+    pub async fn flush_buffered(&self) {
+        if self.concat_timeout.is_none() {
+            return;
+        }
+        let now = Instant::now();
+        let runs = self.state.line_buffers.lock().unwrap().expired(now);
+        for run in runs {
+            self.match_content(&run.last, Some((run.target, run.content)), now)
+                .await;
+        }
+    }
+
+    /// Publishes the lines matching each rule's start and complete patterns as one match, once
+    /// both have been seen with the same key in the same channel. The match is the completion's
+    /// groups, with the start's as `${start:N}`, `${elapsed_ms}` between them, and `${correlation}`
+    /// set to `complete`. `expire_correlations` should be called regularly to time out starts that
+    /// aren't completed. Correlations are checked alongside the search patterns, but don't go
+    /// through their sampling, deduplication, schedule, or cooldowns.
+    pub fn correlate(&mut self, rules: &[CorrelationRule]) -> Result<(), regex::Error> {
+        self.correlations = rules
+            .iter()
+            .map(|rule| {
+                Ok(Correlation {
+                    start: Regex::new(&rule.start)?,
+                    complete: Regex::new(&rule.complete)?,
+                    key_group: rule.key_group,
+                    timeout: rule.timeout,
+                    on_timeout: rule.on_timeout,
+                    pending: self
+                        .state
+                        .pending(&rule.start, &rule.complete, rule.timeout),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    /// Drops or publishes the starts that have waited their rule's timeout for a completion.
+    /// Published ones have the start's groups, as both `${N}` and `${start:N}`, and
+    /// `${correlation}` set to `timeout`.
+    pub async fn expire_correlations(&self) {
+        let now = Instant::now();
+        for correlation in &self.correlations {
+            let expired = correlation.pending.lock().unwrap().expired(now);
+            for started in expired {
+                tracing::info!(
+                    channel = started.channel,
+                    pattern = correlation.start.as_str(),
+                    "correlated event timed out"
+                );
+                if correlation.on_timeout == TimeoutAction::Publish {
+                    let elapsed = now.saturating_duration_since(started.at);
+                    self.publish_correlated(
+                        &started,
+                        started.groups.clone(),
+                        "timeout",
+                        elapsed,
+                        now,
+                    )
+                    .await;
+                }
+            }
+        }
+    }
+
+    /// Keeps matches that fail to publish in `queue`, with the variables they were published with,
+    /// for `retry_failed` to publish again. Like the schedule queue, it can be shared with the
+    /// handler that replaces this one.
+    pub fn keep_failed(&mut self, queue: Arc<Mutex<MatchQueue>>) {
+        self.failed = Some(queue);
+    }
+
+    /// Publishes the matches that failed to publish again, oldest first, through the publisher
+    /// of the rule that matched them. Once one fails in a way that can be retried its endpoint is
+    /// assumed to still be down, so it and the rest going through the same publisher are kept for
+    /// the next call without being sent, while those for other publishers are still sent. One
+    /// that fails for good, e.g. with a 4xx, is dropped and the rest are still sent.
+    pub async fn retry_failed(&self) {
+        let Some(failed) = &self.failed else {
+            return;
+        };
+        let (items, dropped) = failed.lock().unwrap().take();
+        if dropped > 0 {
+            tracing::warn!(
+                dropped,
+                "dropped failed matches to make room for newer ones"
+            );
+        }
+        if items.is_empty() {
+            return;
+        }
+        tracing::info!("retrying {} failed matches", items.len());
+
+        let mut down = HashSet::new();
+        let mut kept = Vec::new();
+        for item in items {
+            let endpoint = self
+                .own_publisher(item.rule.as_deref())
+                .map(|p| p.key.as_str());
+            if down.contains(&endpoint) {
+                kept.push(item);
+                continue;
+            }
+            let vars: Vec<(&str, String)> = item
+                .vars
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.clone()))
+                .collect();
+            let outcome = self
+                .publish_match(
+                    item.rule.as_deref(),
+                    &item.channel,
+                    &item.content,
+                    item.groups.clone(),
+                    &vars,
+                    Instant::now(),
+                )
+                .await;
+            if outcome == PublishOutcome::Retryable {
+                down.insert(endpoint);
+            }
+        }
+        let mut failed = failed.lock().unwrap();
+        for item in kept {
+            failed.push(item);
+        }
+    }
+
+    /// Publishes any queued matches if the schedule is open, through the publisher of the rule
+    /// that matched them. As a digest, each publisher gets one of its own matches, and the first
+    /// one sent has the number dropped from the queue.
+    pub async fn flush_queued(&self) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        if self.schedule.as_ref().is_some_and(|s| !s.is_open()) {
+            return;
+        }
+        let (items, dropped) = queue.lock().unwrap().take();
+        if items.is_empty() {
+            return;
+        }
+        tracing::info!(
+            dropped,
+            "publishing {} matches queued outside schedule",
+            items.len()
+        );
+
+        match self.flush_format {
+            FlushFormat::Digest => {
+                let mut digests: Vec<(Option<&str>, Vec<QueuedMatch>)> = Vec::new();
+                for item in items {
+                    let endpoint = self
+                        .own_publisher(item.rule.as_deref())
+                        .map(|p| p.key.as_str());
+                    match digests.iter_mut().find(|(e, _)| *e == endpoint) {
+                        Some((_, items)) => items.push(item),
+                        None => digests.push((endpoint, vec![item])),
+                    }
+                }
+                let mut dropped = dropped;
+                for (endpoint, items) in digests {
+                    let digest = match_queue::digest(&items);
+                    let group = vec![digest.clone(), items.len().to_string()];
+                    self.publish_match(
+                        endpoint,
+                        "",
+                        &digest,
+                        vec![group],
+                        &[("dropped", std::mem::take(&mut dropped).to_string())],
+                        Instant::now(),
+                    )
+                    .await;
+                }
+            }
+            FlushFormat::Each => {
+                for item in items {
+                    let seq = self.state.seq.fetch_add(1, Ordering::Relaxed) + 1;
+                    let mut vars: Vec<(&str, String)> = item
+                        .vars
+                        .iter()
+                        .map(|(name, value)| (name.as_str(), value.clone()))
+                        .collect();
+                    vars.push(("seq", seq.to_string()));
+                    self.publish_match(
+                        item.rule.as_deref(),
+                        &item.channel,
+                        &item.content,
+                        item.groups,
+                        &vars,
+                        Instant::now(),
+                    )
+                    .await;
+                }
+            }
+        }
+    }
+
+    /// Publishes matches of the pattern at `index` in the search patterns through `publisher`
+    /// rather than the handler's, e.g. to deliver them with different retries. `destination`
+    /// describes where and how `publisher` sends them, such as its URL and body template. It's
+    /// part of what the pattern's state is kept by, so a reload that changes it starts the
+    /// pattern's counts, cooldown, and `${first}` over. Setting new patterns clears this.
+    pub fn publish_pattern_with(
+        &mut self,
+        index: usize,
+        publisher: Arc<dyn Publisher>,
+        destination: &str,
+    ) {
+        if let Some(pattern) = self.patterns.get_mut(index) {
+            pattern.publisher = Some(publisher);
+            pattern.destination = destination.to_string();
+            bind_patterns(&mut self.patterns, &self.cooldown, &self.state);
+        }
+    }
+
+    /// Adds a `${first}` variable to each match, `true` for the first one each pattern publishes
+    /// and `false` after that.
+    pub fn mark_first_matches(&mut self) {
+        self.mark_first = true;
+    }
+
+    /// Records every handled message and the patterns it matched in `log`.
+    pub fn capture_to(&mut self, log: CaptureLog) {
+        self.capture = Some(log);
+    }
+
+    /// The sampled matches and the total number of matches they were drawn from, if sampling.
+    pub fn match_samples(&self) -> Option<(Vec<String>, u64)> {
+        let samples = self.samples.as_ref()?.lock().unwrap();
+        Some((samples.items().to_vec(), samples.seen()))
+    }
+
+    /// Replaces the publisher, e.g. when a reload only changed the webhook settings. Requests
+    /// already sent by the old publisher are left to finish.
+    pub fn set_publisher(&mut self, publisher: Arc<dyn Publisher>) {
+        self.message_publisher = publisher;
+    }
+
+    /// Replaces the search patterns, leaving everything else as it is. Patterns that were already
+    /// set keep their match counts and cooldowns. Sender statuses are only known for channels
+    /// joined after the first pattern that needs them is set.
+    pub fn set_patterns(&mut self, search_patterns: &[SearchPattern]) -> Result<(), regex::Error> {
+        self.patterns = compile_patterns(search_patterns, &self.cooldown, &self.state)?;
+        self.prefilter = compile_prefilter(search_patterns);
+        self.index = index_patterns(search_patterns);
+        if search_patterns
+            .iter()
+            .any(|p| p.require_status != SenderStatus::Any)
+        {
+            self.statuses.get_or_insert_with(Default::default);
+        }
+
+        Ok(())
+    }
+
+    /// Keeps the counters and match state in `state` rather than the handler's own, so they
+    /// carry on from the handler this one replaces.
+    pub fn share_state(&mut self, state: Arc<HandlerState>) {
+        bind_patterns(&mut self.patterns, &self.cooldown, &state);
+        for correlation in &mut self.correlations {
+            correlation.pending = state.pending(
+                correlation.start.as_str(),
+                correlation.complete.as_str(),
+                correlation.timeout,
+            );
+        }
+        if let Some((window, _)) = self.dedup {
+            state.dedup.lock().unwrap().set_window(window);
+        }
+        if let Some(timeout) = self.concat_timeout {
+            state.line_buffers.lock().unwrap().set_timeout(timeout);
+        }
+        self.state = state;
+    }
+
+    /// The number of messages handled, whether or not they matched.
+    pub fn processed_count(&self) -> u64 {
+        self.state.processed.load(Ordering::Relaxed)
+    }
+
+    /// Each search pattern with the number of messages it has matched, including ones suppressed
+    /// by a cooldown.
+    pub fn match_counts(&self) -> Vec<(&str, u64)> {
+        self.patterns
+            .iter()
+            .map(|p| (p.re.as_str(), p.state.matches.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// The IRC sender for replying to messages or querying the server, if the handler is
+    /// attached to a connection.
+    pub fn sender(&self) -> Option<&Sender> {
+        self.sender.as_deref()
+    }
+
+    /// Sets the server the handler's messages come from, for patterns that are scoped to one.
+    pub fn connected_to(&mut self, server: &str) {
+        self.server = Some(server.to_lowercase());
+    }
+
+    /// Sets the line length, in bytes, that messages sent with `send_privmsg` are split to fit.
+    pub fn set_max_line_length(&mut self, max_line_length: usize) {
+        self.max_line_length = max_line_length;
+    }
+
+    /// Sends `text` to `target`, split into as many PRIVMSGs as it takes to keep each line
+    /// within the maximum length. Anything the bot says should go through this rather than the
+    /// sender, so long text isn't truncated or rejected by the server. Does nothing if the
+    /// handler isn't attached to a connection.
+    pub fn send_privmsg(&self, target: &str, text: &str) -> Result<(), irc::error::Error> {
+        let Some(sender) = &self.sender else {
+            return Ok(());
+        };
+        for line in outgoing::split_message(target, text, self.max_line_length) {
+            sender.send_privmsg(target, &line)?;
+        }
+        Ok(())
+    }
+
+    pub async fn handle_msg(&self, msg: Message) {
+        // Publishing latency and deadlines count from here.
+        let received = Instant::now();
+        self.state.processed.fetch_add(1, Ordering::Relaxed);
+        if let Some(statuses) = &self.statuses {
+            statuses.lock().unwrap().update(&msg);
+        }
+
+        if let Some(group) = numeric_group(&msg, &self.watch_numerics) {
+            tracing::info!(numeric = group[0], "watched numeric reply");
+            self.publish("", vec![group], &[], received).await;
+        }
+
+        if !self.filters.iter().all(|f| f.should_process(&msg)) {
+            self.capture(&msg, &[]);
+            return;
+        }
+
+        let content = match self.match_target {
+            MatchTarget::Content => get_content(&msg),
+            MatchTarget::FullLine => Some((
+                get_target(&msg).unwrap_or_default().to_string(),
+                msg.to_string().trim_end().to_string(),
+            )),
+        };
+
+        if let (Some(_), MatchTarget::Content, Some((target, line)), Some(nick)) = (
+            self.concat_timeout,
+            self.match_target,
+            &content,
+            msg.source_nickname(),
+        ) {
+            let ended = self.state.line_buffers.lock().unwrap().push(
+                target,
+                nick,
+                line.clone(),
+                msg.clone(),
+                received,
+            );
+            for run in ended {
+                self.match_content(&run.last, Some((run.target, run.content)), received)
+                    .await;
+            }
+            return;
+        }
+
+        self.match_content(&msg, content, received).await;
+    }
+}
+
+impl MessageHandler {
+    /// Matches `content`, the target and text of `msg` or of a run of lines ending with it,
+    /// against the patterns and publishes the matches, counting as matched at `matched_at`.
+    async fn match_content(
+        &self,
+        msg: &Message,
+        content: Option<(String, String)>,
+        matched_at: Instant,
+    ) {
+        let mut matched = Vec::new();
+        if let Some((target, content)) = content {
+            // The irc crate decodes lines lossily, so invalid UTF-8 arrives as U+FFFD.
+            if content.contains(char::REPLACEMENT_CHARACTER) {
+                tracing::warn!(content, "message contained invalid UTF-8");
+            }
+            let Some(fields) = self.content_vars(&content) else {
+                tracing::debug!(content, "skipping content that isn't valid JSON");
+                self.capture(msg, &matched);
+                return;
+            };
+            tracing::debug!(msg = content, "checking for matches");
+            let context = MessageContext::new(msg);
+            let target = context.channel.clone().unwrap_or(target);
+            self.match_correlations(&target, &content, matched_at).await;
+
+            let candidates = self.prefilter.as_ref().map(|set| set.matches(&content));
+            let lowercase_target = target.to_lowercase();
+            let applicable = self.index.get(&message_kind(msg)).into_iter().flatten();
+            for (idx, pattern) in applicable.map(|&idx| (idx, &self.patterns[idx])) {
+                let is_match = match &candidates {
+                    Some(candidates) => candidates.matched(idx),
+                    None => pattern.re.is_match(&content),
+                };
+                let in_channel = pattern
+                    .channels
+                    .as_ref()
+                    .is_none_or(|channels| channels.contains(&lowercase_target));
+                let on_server = pattern
+                    .server
+                    .as_ref()
+                    .is_none_or(|server| self.server.as_ref() == Some(server));
+                if !is_match
+                    || !in_channel
+                    || !on_server
+                    || !self.sender_has_status(msg, pattern.require_status)
+                {
+                    continue;
+                }
+                let groups = pattern.groups(&content);
+                if groups.is_empty() {
+                    tracing::debug!(content, "none of the selected occurrences matched");
+                    continue;
+                }
+                let mut fields = fields.clone();
+                fields.extend(pattern.named_groups(&content));
+                tracing::info!(content, pattern = pattern.re.as_str(), "matched");
+                pattern.state.matches.fetch_add(1, Ordering::Relaxed);
+                matched.push(pattern.re.as_str());
+
+                if let Some(samples) = &self.samples {
+                    samples.lock().unwrap().add(content.clone());
+                    continue;
+                }
+
+                if self.sample_rates.as_ref().is_some_and(|r| !r.keep(&groups)) {
+                    tracing::debug!(content, "match dropped by sampling");
+                    continue;
+                }
+
+                if let Some((_, key_group)) = &self.dedup {
+                    let key = match key_group {
+                        Some(group) => groups.first().and_then(|g| g.get(*group)),
+                        None => Some(&content),
+                    };
+                    if let Some(key) = key {
+                        let key = format!("{}\0{}", pattern.key, key);
+                        if !self.state.dedup.lock().unwrap().first_seen(key) {
+                            tracing::debug!(content, "duplicate match, not publishing");
+                            continue;
+                        }
+                    }
+                }
+
+                if self.schedule.as_ref().is_some_and(|s| !s.is_open()) {
+                    match &self.queue {
+                        Some(queue) => {
+                            tracing::info!(content, "match outside schedule, queueing");
+                            queue.lock().unwrap().push(QueuedMatch {
+                                rule: Some(pattern.key.clone()),
+                                channel: target.clone(),
+                                content: content.clone(),
+                                groups,
+                                vars: fields.iter().cloned().chain(context.vars()).collect(),
+                            });
+                        }
+                        None => tracing::info!(content, "match outside schedule, not publishing"),
+                    }
+                    continue;
+                }
+
+                if let Some(cooldown) = pattern.state.cooldown.lock().unwrap().as_mut() {
+                    if !cooldown.allow(matched_at) {
+                        tracing::debug!(content, "match suppressed by cooldown");
+                        continue;
+                    }
+                }
+
+                let seq = self.state.seq.fetch_add(1, Ordering::Relaxed) + 1;
+                let mut vars: Vec<(&str, String)> = fields
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.clone()))
+                    .collect();
+                vars.push(("seq", seq.to_string()));
+                let first = !pattern.state.seen_any.swap(true, Ordering::Relaxed);
+                if self.mark_first {
+                    vars.push(("first", first.to_string()));
+                }
+                let context_vars = context.vars();
+                vars.extend(
+                    context_vars
+                        .iter()
+                        .map(|(name, value)| (name.as_str(), value.clone())),
+                );
+                self.publish_match(
+                    Some(&pattern.key),
+                    &target,
+                    &content,
+                    groups,
+                    &vars,
+                    matched_at,
+                )
+                .await;
+            }
+        }
+
+        self.capture(msg, &matched);
+    }
+
+    /// Records the starts in `content` and publishes the events it completes.
+    async fn match_correlations(&self, channel: &str, content: &str, now: Instant) {
+        for correlation in &self.correlations {
+            let first = |re: &Regex| match_groups(re, content, None).into_iter().next();
+            let key = |groups: &[String]| {
+                let key = groups.get(correlation.key_group)?;
+                Some(format!("{}\0{}", channel, key))
+            };
+
+            // Checked first, so a line that ends one event and starts the next completes the
+            // earlier one rather than itself.
+            if let Some(groups) = first(&correlation.complete) {
+                let started = key(&groups)
+                    .and_then(|key| correlation.pending.lock().unwrap().complete(&key, now));
+                match started {
+                    Some((started, elapsed)) => {
+                        tracing::info!(content, "correlated event completed");
+                        self.publish_correlated(&started, groups, "complete", elapsed, now)
+                            .await;
+                    }
+                    None => tracing::debug!(content, "completion without a pending start"),
+                }
+            }
+
+            if let Some(groups) = first(&correlation.start) {
+                let Some(key) = key(&groups) else {
+                    tracing::debug!(content, "correlation start without a key");
+                    continue;
+                };
+                let started = Started {
+                    channel: channel.to_string(),
+                    groups,
+                    at: now,
+                };
+                if !correlation.pending.lock().unwrap().start(key, started, now) {
+                    tracing::warn!(content, "too many pending correlations, dropping start");
+                }
+            }
+        }
+    }
+
+    async fn publish_correlated(
+        &self,
+        started: &Started,
+        groups: Vec<String>,
+        outcome: &str,
+        elapsed: Duration,
+        matched_at: Instant,
+    ) {
+        let names: Vec<String> = (0..started.groups.len())
+            .map(|i| format!("start:{}", i))
+            .collect();
+        let seq = self.state.seq.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut vars: Vec<(&str, String)> = names
+            .iter()
+            .map(String::as_str)
+            .zip(started.groups.iter().cloned())
+            .collect();
+        vars.push(("correlation", outcome.to_string()));
+        vars.push(("elapsed_ms", elapsed.as_millis().to_string()));
+        vars.push(("seq", seq.to_string()));
+
+        let content = groups.first().cloned().unwrap_or_default();
+        self.publish_match(
+            None,
+            &started.channel,
+            &content,
+            vec![groups],
+            &vars,
+            matched_at,
+        )
+        .await;
+    }
+
+    async fn publish(
+        &self,
+        channel: &str,
+        groups: Vec<Vec<String>>,
+        vars: &[(&str, String)],
+        matched_at: Instant,
+    ) {
+        publish_to(
+            self.message_publisher.as_ref(),
+            channel,
+            groups,
+            vars,
+            matched_at,
+        )
+        .await;
+    }
+
+    /// Publishes a match of the pattern with the key `rule`, if any, through that pattern's
+    /// publisher, keeping it for `retry_failed` if that fails in a way that can be retried and
+    /// failed matches are kept.
+    async fn publish_match(
+        &self,
+        rule: Option<&str>,
+        channel: &str,
+        content: &str,
+        groups: Vec<Vec<String>>,
+        vars: &[(&str, String)],
+        matched_at: Instant,
+    ) -> PublishOutcome {
+        let kept = self.failed.as_ref().map(|failed| (failed, groups.clone()));
+        let publisher = self.publisher_for(rule);
+        let Err(e) = publisher.publish(channel, groups, vars, matched_at).await else {
+            return PublishOutcome::Published;
+        };
+        tracing::error!("publish error: {}", e);
+        if !retryable(&e) {
+            return PublishOutcome::Dropped;
+        }
+        if let Some((failed, groups)) = kept {
+            failed.lock().unwrap().push(QueuedMatch {
+                rule: rule.map(String::from),
+                channel: channel.to_string(),
+                content: content.to_string(),
+                groups,
+                vars: vars
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.clone()))
+                    .collect(),
+            });
+        }
+        PublishOutcome::Retryable
+    }
+
+    /// The publisher for matches of the pattern with the key `rule`: its own if it has one, or
+    /// the handler's if it doesn't, there's no rule, or the rule is gone after a reload.
+    fn publisher_for(&self, rule: Option<&str>) -> &dyn Publisher {
+        self.own_publisher(rule)
+            .and_then(|pattern| pattern.publisher.as_deref())
+            .unwrap_or(self.message_publisher.as_ref())
+    }
+
+    /// The pattern with the key `rule`, if it publishes through its own publisher.
+    fn own_publisher(&self, rule: Option<&str>) -> Option<&Pattern> {
+        rule.and_then(|rule| self.patterns.iter().find(|p| p.key == rule))
+            .filter(|pattern| pattern.publisher.is_some())
+    }
+
+    fn capture(&self, msg: &Message, matched: &[&str]) {
+        if let Some(capture) = &self.capture {
+            capture.record(get_target(msg), msg.to_string().trim_end(), matched);
+        }
+    }
+
+    /// Template variables taken from the content itself, or `None` if it isn't valid in the
+    /// configured format.
+    fn content_vars(&self, content: &str) -> Option<Vec<(String, String)>> {
+        match self.content_format {
+            ContentFormat::Text => Some(Vec::new()),
+            ContentFormat::Json => serde_json::from_str(content).ok().map(|v| json_vars(&v)),
+        }
+    }
+
+    fn sender_has_status(&self, msg: &Message, required: SenderStatus) -> bool {
+        if required == SenderStatus::Any {
+            return true;
+        }
+        let (Some(statuses), Some(channel), Some(nick)) =
+            (&self.statuses, get_target(msg), msg.source_nickname())
+        else {
+            return false;
+        };
+
+        statuses.lock().unwrap().has_status(channel, nick, required)
+    }
+}
+
+async fn publish_to(
+    publisher: &dyn Publisher,
+    channel: &str,
+    groups: Vec<Vec<String>>,
+    vars: &[(&str, String)],
+    matched_at: Instant,
+) {
+    if let Err(e) = publisher.publish(channel, groups, vars, matched_at).await {
+        tracing::error!("publish error: {}", e);
+    }
+}
+
+fn compile_patterns(
+    search_patterns: &[SearchPattern],
+    cooldown: &Option<CooldownConfig>,
+    state: &HandlerState,
+) -> Result<Vec<Pattern>, regex::Error> {
+    let mut patterns = search_patterns
+        .iter()
+        .map(|pattern| {
+            Ok(Pattern {
+                re: Regex::new(&pattern.pattern)?,
+                rule: format!("{:?}", pattern),
+                destination: String::new(),
+                key: String::new(),
+                state: Arc::default(),
+                require_status: pattern.require_status,
+                mapping: pattern.mapping.clone(),
+                occurrences: pattern.occurrences.clone(),
+                replacement: pattern.replacement.clone(),
+                channels: pattern
+                    .channels
+                    .as_ref()
+                    .map(|channels| channels.iter().map(|c| c.to_lowercase()).collect()),
+                server: pattern.server.as_ref().map(|s| s.to_lowercase()),
+                publisher: None,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    bind_patterns(&mut patterns, cooldown, state);
+    Ok(patterns)
+}
+
+/// Gives each pattern its state in `state`, keyed on the whole rule it was compiled from and where
+/// it publishes. Rules that are the same in every way are told apart by how many came before them.
+fn bind_patterns(
+    patterns: &mut [Pattern],
+    cooldown: &Option<CooldownConfig>,
+    state: &HandlerState,
+) {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for pattern in patterns {
+        let rule = format!("{}\0{}", pattern.rule, pattern.destination);
+        let count = seen.entry(rule.clone()).or_default();
+        *count += 1;
+        pattern.key = format!("{}\0{}", rule, count);
+        pattern.state = state.pattern(&pattern.key, cooldown);
+    }
+}
+
+/// The default cache for a set's lazy DFA is too small for a large set, which then spends most of
+/// its time rebuilding states and ends up far slower than checking each pattern. This gives each
+/// pattern a share of the cache instead.
+const PREFILTER_CACHE_PER_PATTERN: usize = 256 * 1024;
+const PREFILTER_MIN_CACHE: usize = 2 * 1024 * 1024;
+
+fn compile_prefilter(search_patterns: &[SearchPattern]) -> Option<RegexSet> {
+    let cache = (search_patterns.len() * PREFILTER_CACHE_PER_PATTERN).max(PREFILTER_MIN_CACHE);
+    match RegexSetBuilder::new(search_patterns.iter().map(|p| &p.pattern))
+        .dfa_size_limit(cache)
+        .build()
+    {
+        Ok(set) => Some(set),
+        Err(e) => {
+            tracing::warn!(%e, "couldn't combine patterns, checking each one separately");
+            None
+        }
+    }
+}
+
+/// Maps each kind of message to the indexes of the patterns checked against it.
+fn index_patterns(search_patterns: &[SearchPattern]) -> HashMap<Option<MessageKind>, Vec<usize>> {
+    let kinds = [
+        Some(MessageKind::Privmsg),
+        Some(MessageKind::Notice),
+        Some(MessageKind::Action),
+        None,
+    ];
+
+    kinds
+        .into_iter()
+        .map(|kind| {
+            let applicable = search_patterns
+                .iter()
+                .enumerate()
+                .filter(|(_, pattern)| match (&pattern.kinds, kind) {
+                    (None, _) => true,
+                    (Some(kinds), Some(kind)) => kinds.contains(&kind),
+                    (Some(_), None) => false,
+                })
+                .map(|(idx, _)| idx)
+                .collect();
+            (kind, applicable)
+        })
+        .collect()
+}
+
+/// Parses the contents of a pattern file: one pattern per line, ignoring surrounding whitespace,
+/// blank lines, and lines starting with `#`.
+pub fn parse_pattern_file(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// The target and text of a PRIVMSG or NOTICE. An ACTION's text is what follows `ACTION`, without
+/// the CTCP framing, so `/me deploys api` is `deploys api`. Other commands have no content.
+fn get_content(msg: &Message) -> Option<(String, String)> {
+    match &msg.command {
+        Command::PRIVMSG(target, body) | Command::NOTICE(target, body) => {
+            let body = match body.strip_prefix("\x01ACTION") {
+                Some(action) => action.strip_suffix('\x01').unwrap_or(action),
+                None => body,
+            };
+            Some((target.clone(), body.trim().to_string()))
+        }
+        _ => None,
+    }
+}
+
+fn message_kind(msg: &Message) -> Option<MessageKind> {
+    match &msg.command {
+        Command::PRIVMSG(_, text) if text.starts_with("\x01ACTION") => Some(MessageKind::Action),
+        Command::PRIVMSG(_, _) => Some(MessageKind::Privmsg),
+        Command::NOTICE(_, _) => Some(MessageKind::Notice),
+        _ => None,
+    }
+}
+
+/// The command of `msg` as it appears on the wire, e.g. `PRIVMSG` or `353`.
+fn command_name(msg: &Message) -> String {
+    let name = match &msg.command {
+        Command::PRIVMSG(..) => "PRIVMSG",
+        Command::NOTICE(..) => "NOTICE",
+        Command::JOIN(..) => "JOIN",
+        Command::PART(..) => "PART",
+        Command::QUIT(..) => "QUIT",
+        Command::NICK(..) => "NICK",
+        Command::KICK(..) => "KICK",
+        Command::ChannelMODE(..) => "MODE",
+        Command::PING(..) => "PING",
+        Command::PONG(..) => "PONG",
+        Command::Response(response, _) => return format!("{:03}", *response as u16),
+        Command::Raw(command, _) => return command.to_uppercase(),
+        // Anything rarer serializes with the command as its first word.
+        command => {
+            return String::from(command)
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_uppercase()
+        }
+    };
+    name.to_string()
+}
+
+/// Whether a publish that failed with `e` may succeed later. Errors from publishers other than
+/// `WebhookPublisher` are assumed to be.
+fn retryable(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<PublishError>()
+        .is_none_or(PublishError::is_retryable)
+}
+
+fn get_target(msg: &Message) -> Option<&str> {
+    match &msg.command {
+        Command::PRIVMSG(target, _) | Command::NOTICE(target, _) => Some(target),
+        _ => None,
+    }
+}
+
+/// For a numeric reply listed in `watch_numerics`, returns the zero-padded numeric followed by its
+/// params, so templates can refer to the numeric as `${0}` and the params as `${1}`, `${2}`, ...
+fn numeric_group(msg: &Message, watch_numerics: &[u16]) -> Option<Vec<String>> {
+    match &msg.command {
+        Command::Response(response, args) if watch_numerics.contains(&(*response as u16)) => {
+            let mut group = vec![format!("{:03}", *response as u16)];
+            group.extend(args.iter().cloned());
+            Some(group)
+        }
+        _ => None,
+    }
+}
+
+/// Flattens `value` into `json:<path>` variables, one for every object field and array element
+/// with path segments joined by `.`, e.g. `json:user.names.0`. Strings are substituted as-is and
+/// anything else as its JSON text.
+fn json_vars(value: &serde_json::Value) -> Vec<(String, String)> {
+    fn walk(path: &str, value: &serde_json::Value, vars: &mut Vec<(String, String)>) {
+        let children: Vec<(String, &serde_json::Value)> = match value {
+            serde_json::Value::Object(fields) => {
+                fields.iter().map(|(k, v)| (k.clone(), v)).collect()
+            }
+            serde_json::Value::Array(items) => items
+                .iter()
+                .enumerate()
+                .map(|(i, v)| (i.to_string(), v))
+                .collect(),
+            _ => Vec::new(),
+        };
+        for (key, child) in children {
+            let child_path = match path {
+                "" => key,
+                _ => format!("{}.{}", path, key),
+            };
+            let rendered = match child {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            vars.push((format!("json:{}", child_path), rendered));
+            walk(&child_path, child, vars);
+        }
+    }
+
+    let mut vars = Vec::new();
+    walk("", value, &mut vars);
+    vars
+}
+
+/// The capture groups of each match in `content`, or only the selected ones with `occurrences`.
+fn match_groups(
+    re: &regex::Regex,
+    content: &str,
+    occurrences: Option<&OccurrenceFilter>,
+) -> Vec<Vec<String>> {
+    re.captures_iter(content)
+        .enumerate()
+        .filter(|(idx, _)| occurrences.is_none_or(|o| o.contains(idx + 1)))
+        .map(|(_, group)| {
+            group
+                .iter()
+                .filter_map(|mat| Some(mat?.as_str().to_string()))
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webhook_publisher;
+    use async_trait::async_trait;
+    use httptest::{all_of, matchers::*, responders::*, Expectation, Server};
+
+    /// A channel, its groups, and the extra template variables.
+    type Published = (String, Vec<Vec<String>>, Vec<(String, String)>);
+
+    /// Records what would have been published, or fails while `down` is set. Matches in
+    /// `rejected` fail with a 400.
+    #[derive(Default)]
+    struct MockPublisher {
+        published: Mutex<Vec<Published>>,
+        down: AtomicBool,
+        rejected: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Publisher for MockPublisher {
+        async fn publish(
+            &self,
+            channel: &str,
+            groups: Vec<Vec<String>>,
+            vars: &[(&str, String)],
+            _matched_at: Instant,
+        ) -> anyhow::Result<()> {
+            if self.down.load(Ordering::Relaxed) {
+                anyhow::bail!("endpoint is down");
+            }
+            if groups
+                .iter()
+                .any(|group| self.rejected.lock().unwrap().contains(&group[0]))
+            {
+                return Err(webhook_publisher::PublishError {
+                    failures: vec![webhook_publisher::RequestError::Status {
+                        status: reqwest::StatusCode::BAD_REQUEST,
+                        body: String::new(),
+                    }],
+                    total: groups.len(),
+                }
+                .into());
+            }
+            let vars = vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect();
+            self.published
+                .lock()
+                .unwrap()
+                .push((channel.to_string(), groups, vars));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_get_content() {
+        let msg = |prefix, command, args| Message::new(prefix, command, args).unwrap();
+        let content = |target: &str, body: &str| Some((target.to_string(), body.to_string()));
+
+        assert_eq!(
+            get_content(&msg(
+                Some("nick!user@host"),
+                "PRIVMSG",
+                vec!["#channel", "Hello this is a message"]
+            )),
+            content("#channel", "Hello this is a message")
+        );
+        assert_eq!(
+            get_content(&msg(Some("irc.example.com"), "NOTICE", vec!["hook", "hi"])),
+            content("hook", "hi")
+        );
+        // Colons in the prefix or the body don't confuse it.
+        assert_eq!(
+            get_content(&msg(
+                Some("nick!user@2001:db8::1"),
+                "PRIVMSG",
+                vec!["#ops", "deploy: done"]
+            )),
+            content("#ops", "deploy: done")
+        );
+        assert_eq!(
+            get_content(&msg(None, "PRIVMSG", vec!["#ops", "no prefix"])),
+            content("#ops", "no prefix")
+        );
+        assert_eq!(
+            get_content(&msg(
+                Some("nick!user@host"),
+                "PRIVMSG",
+                vec!["#ops", "\x01ACTION waves\x01"]
+            )),
+            content("#ops", "waves")
+        );
+    }
+
+    #[test]
+    fn test_get_content_other_commands() {
+        let msg = |command, args| Message::new(Some("irc.example.com"), command, args).unwrap();
+        assert_eq!(get_content(&msg("QUIT", vec!["bye"])), None);
+        assert_eq!(get_content(&msg("PING", vec!["irc.example.com"])), None);
+        assert_eq!(get_content(&msg("001", vec!["hook", "Welcome"])), None);
+    }
+
+    #[test]
+    fn test_json_vars() {
+        let value = serde_json::json!({
+            "user": {"nick": "alice", "id": 7},
+            "tags": ["a", "b"],
+        });
+        let vars: HashMap<String, String> = json_vars(&value).into_iter().collect();
+
+        assert_eq!(vars["json:user.nick"], "alice");
+        assert_eq!(vars["json:user.id"], "7");
+        assert_eq!(vars["json:tags.1"], "b");
+        assert_eq!(vars["json:user"], r#"{"id":7,"nick":"alice"}"#);
+    }
+
+    #[test]
+    fn test_group_mapping() {
+        let mut mapping = GroupMapping {
+            group: 1,
+            values: HashMap::from([("OK".to_string(), ":white_check_mark:".to_string())]),
+            default: None,
+        };
+        let group = |status: &str| vec![format!("build {}", status), status.to_string()];
+
+        let mut groups = vec![group("OK"), group("FAIL")];
+        mapping.apply(&mut groups);
+        assert_eq!(
+            groups,
+            vec![
+                vec!["build OK".to_string(), ":white_check_mark:".to_string()],
+                group("FAIL")
+            ]
+        );
+
+        mapping.default = Some(":x:".to_string());
+        let mut groups = vec![group("FAIL")];
+        mapping.apply(&mut groups);
+        assert_eq!(groups[0][1], ":x:");
+    }
+
+    #[test]
+    fn test_get_target() {
+        let msg = Message::new(Some("user"), "PRIVMSG", vec!["#channel", "hello"]).unwrap();
+        assert_eq!(get_target(&msg), Some("#channel"));
+
+        let msg = Message::new(Some("server.example.com"), "PING", vec!["server"]).unwrap();
+        assert_eq!(get_target(&msg), None);
+    }
+
+    #[tokio::test]
+    async fn test_patterns_limited_to_channels() {
+        let publisher = Arc::new(MockPublisher::default());
+        let in_channels = |pattern: &str, channels: &[&str]| SearchPattern {
+            channels: Some(channels.iter().map(|c| c.to_string()).collect()),
+            ..SearchPattern::new(pattern)
+        };
+        let handler = MessageHandler::new(
+            &[
+                in_channels(r"build (\d+) failed", &["#ci"]),
+                in_channels(r"(\w+) failed", &["#ci", "#ops"]),
+            ],
+            publisher.clone(),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+
+        for (channel, content) in [
+            ("#ops", "build 7 failed"),
+            ("#CI", "build 8 failed"),
+            ("#dev", "build 9 failed"),
+        ] {
+            let msg = Message::new(Some("ci"), "PRIVMSG", vec![channel, content]).unwrap();
+            handler.handle_msg(msg).await;
         }
+
+        let published: Vec<_> = publisher
+            .published
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(channel, groups, _)| (channel.clone(), groups[0][1].clone()))
+            .collect();
+        let expected = [("#ops", "7"), ("#CI", "8"), ("#CI", "8")];
+        assert_eq!(
+            published,
+            expected.map(|(channel, group)| (channel.to_string(), group.to_string()))
+        );
     }
 
-    pub async fn handle_msg(&mut self, msg: Message) {
-        if let Some(content) = get_content(&msg.to_string()) {
-            tracing::debug!(msg = content, "checking for matches");
-            if !self.re.is_match(&content) {
-                return;
-            }
-            tracing::info!(content, "matched");
+    #[tokio::test]
+    async fn test_patterns_limited_to_server() {
+        let publisher = Arc::new(MockPublisher::default());
+        let on_server = |pattern: &str, server: &str| SearchPattern {
+            server: Some(server.to_string()),
+            ..SearchPattern::new(pattern)
+        };
+        let mut handler = MessageHandler::new(
+            &[
+                on_server(r"libera: (\w+)", "irc.libera.chat"),
+                on_server(r"oftc: (\w+)", "irc.oftc.net"),
+                SearchPattern::new(r"\w+: (\w+)"),
+            ],
+            publisher.clone(),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+        handler.connected_to("IRC.Libera.Chat");
 
-            let groups = match_groups(&self.re, &content);
-            self.message_publisher.publish(groups).await;
+        for content in ["libera: api", "oftc: web"] {
+            let msg = Message::new(Some("ci"), "PRIVMSG", vec!["#ops", content]).unwrap();
+            handler.handle_msg(msg).await;
         }
+
+        let counts: Vec<u64> = handler.match_counts().iter().map(|(_, n)| *n).collect();
+        assert_eq!(counts, vec![1, 0, 2]);
+        assert_eq!(publisher.published.lock().unwrap().len(), 3);
     }
-}
 
-fn get_content(m: &str) -> Option<String> {
-    // Skip the first character, which is always a :, then find the next :
-    let mut chrs = m.chars().skip(1);
+    #[tokio::test]
+    async fn test_interleaved_channels_are_tagged_per_message() {
+        let server = Server::run();
+        for body in ["#one: a 1", "#two: b 2", "#one: c 3", "#two: d 4"] {
+            server.expect(
+                Expectation::matching(all_of![
+                    request::method_path("POST", "/endpoint"),
+                    request::body(body),
+                ])
+                .respond_with(status_code(200)),
+            );
+        }
+
+        let publisher = webhook_publisher::WebhookPublisher::new(
+            server.url("/endpoint"),
+            "${channel}: ${1} ${seq}".to_string(),
+            std::collections::HashMap::new(),
+            webhook_publisher::PublisherOptions::default(),
+        );
+        let handler = MessageHandler::new(
+            &[SearchPattern::new(r#"match (\w)"#)],
+            Arc::new(publisher),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+
+        for (channel, capture) in [("#one", "a"), ("#two", "b"), ("#one", "c"), ("#two", "d")] {
+            let content = format!("match {}", capture);
+            let msg = Message::new(Some("user"), "PRIVMSG", vec![channel, &content]).unwrap();
+            handler.handle_msg(msg).await;
+        }
 
-    if let Some(_) = chrs.find(|&c| c == ':') {
-        // Make sure the trailing newline is removed.
-        Some(chrs.collect::<String>().trim().to_string())
-    } else {
-        None
+        assert_eq!(handler.processed_count(), 4);
+        assert_eq!(handler.match_counts(), vec![(r#"match (\w)"#, 4)]);
     }
-}
 
-fn match_groups(re: &regex::Regex, content: &str) -> Vec<Vec<String>> {
-    re.captures_iter(content)
-        .map(|group| {
-            group
-                .iter()
-                .filter_map(|mat| Some(mat?.as_str().to_string()))
-                .collect()
-        })
-        .collect()
-}
+    #[tokio::test]
+    async fn test_publishes_through_publisher() {
+        let publisher = Arc::new(MockPublisher::default());
+        let handler = MessageHandler::new(
+            &[SearchPattern::new(r"deploy (\w+)")],
+            publisher.clone(),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        for content in ["deploy api", "hello"] {
+            let msg = Message::new(Some("user"), "PRIVMSG", vec!["#ops", content]).unwrap();
+            handler.handle_msg(msg).await;
+        }
+
+        let mut published = publisher.published.lock().unwrap();
+        // The timestamp changes from run to run.
+        published[0].2.retain(|(name, _)| name != "timestamp");
+        assert_eq!(
+            *published,
+            vec![(
+                "#ops".to_string(),
+                vec![vec!["deploy api".to_string(), "api".to_string()]],
+                vec![
+                    ("seq".to_string(), "1".to_string()),
+                    ("nick".to_string(), "user".to_string())
+                ]
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mark_first_matches() {
+        let publisher = Arc::new(MockPublisher::default());
+        let mut handler = MessageHandler::new(
+            &[SearchPattern::new("deploy"), SearchPattern::new("rollback")],
+            publisher.clone(),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+        handler.mark_first_matches();
+
+        for content in ["deploy", "deploy", "rollback"] {
+            let msg = Message::new(Some("user"), "PRIVMSG", vec!["#ops", content]).unwrap();
+            handler.handle_msg(msg).await;
+        }
+
+        let first: Vec<String> = publisher
+            .published
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, _, vars)| vars.iter().find(|(k, _)| k == "first").unwrap().1.clone())
+            .collect();
+        assert_eq!(first, ["true", "false", "true"]);
+    }
+
+    #[tokio::test]
+    async fn test_set_patterns() {
+        let publisher = webhook_publisher::WebhookPublisher::new(
+            "http://example.com/unused".parse().unwrap(),
+            "${0}".to_string(),
+            std::collections::HashMap::new(),
+            webhook_publisher::PublisherOptions::default(),
+        );
+        let mut handler = MessageHandler::new(
+            &[SearchPattern::new("old")],
+            Arc::new(publisher),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+        handler.sample_matches(10);
+
+        assert!(handler.set_patterns(&[SearchPattern::new("(")]).is_err());
+        assert_eq!(handler.match_counts(), vec![("old", 0)]);
+
+        handler.set_patterns(&[SearchPattern::new("new")]).unwrap();
+        for content in ["old", "new"] {
+            let msg = Message::new(Some("user"), "PRIVMSG", vec!["#rust", content]).unwrap();
+            handler.handle_msg(msg).await;
+        }
+        assert_eq!(handler.match_counts(), vec![("new", 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_utf8_is_replaced() {
+        let publisher = webhook_publisher::WebhookPublisher::new(
+            "http://example.com/unused".parse().unwrap(),
+            "${0}".to_string(),
+            std::collections::HashMap::new(),
+            webhook_publisher::PublisherOptions::default(),
+        );
+        let mut handler = MessageHandler::new(
+            &[SearchPattern::new("deploy (.*)")],
+            Arc::new(publisher),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+        handler.sample_matches(10);
+
+        let content = String::from_utf8_lossy(b"deploy \xff\xfebroken");
+        let msg = Message::new(Some("user"), "PRIVMSG", vec!["#rust", &content]).unwrap();
+        handler.handle_msg(msg).await;
+
+        let (samples, _) = handler.match_samples().unwrap();
+        assert_eq!(samples, vec!["deploy \u{FFFD}\u{FFFD}broken".to_string()]);
+    }
 
     #[test]
-    fn test_get_content() {
-        let input = ":first part:Hello this is a message".to_string();
+    fn test_numeric_group() {
+        let msg = Message::new(
+            Some("server.example.com"),
+            "353",
+            vec!["nick", "=", "#channel", "alice bob"],
+        )
+        .unwrap();
+
+        assert_eq!(
+            numeric_group(&msg, &[5, 353]),
+            Some(vec![
+                "353".to_string(),
+                "nick".to_string(),
+                "=".to_string(),
+                "#channel".to_string(),
+                "alice bob".to_string()
+            ])
+        );
+        assert_eq!(numeric_group(&msg, &[5]), None);
+    }
+
+    #[test]
+    fn test_filters() {
+        let privmsg = Message::new(Some("Alice!a@host"), "PRIVMSG", vec!["#Rust", "hi"]).unwrap();
+        let notice = Message::new(Some("bob!b@host"), "NOTICE", vec!["#other", "hi"]).unwrap();
+
+        let nicks = NickAllowlistFilter::new(&["alice"]);
+        assert!(nicks.should_process(&privmsg));
+        assert!(!nicks.should_process(&notice));
+
+        let nicks = NickDenylistFilter::new(&["ALICE"]);
+        assert!(!nicks.should_process(&privmsg));
+        assert!(nicks.should_process(&notice));
+
+        // Without a prefix there's no sender to allow, or to deny.
+        let unsourced = Message::new(None, "PRIVMSG", vec!["#rust", "hi"]).unwrap();
+        assert!(!NickAllowlistFilter::new(&["alice"]).should_process(&unsourced));
+        assert!(NickDenylistFilter::new(&["alice"]).should_process(&unsourced));
+
+        let channels = ChannelFilter::new(&["#rust"]);
+        assert!(channels.should_process(&privmsg));
+        assert!(!channels.should_process(&notice));
+
+        let types = MessageTypeFilter::new(&["notice"]);
+        assert!(!types.should_process(&privmsg));
+        assert!(types.should_process(&notice));
+    }
+
+    #[test]
+    fn test_message_kind() {
+        let msg = |command, text| Message::new(Some("user"), command, vec!["#c", text]).unwrap();
+
+        assert_eq!(
+            message_kind(&msg("PRIVMSG", "hi")),
+            Some(MessageKind::Privmsg)
+        );
+        assert_eq!(
+            message_kind(&msg("PRIVMSG", "\x01ACTION waves\x01")),
+            Some(MessageKind::Action)
+        );
+        assert_eq!(
+            message_kind(&msg("NOTICE", "hi")),
+            Some(MessageKind::Notice)
+        );
+        assert_eq!(message_kind(&msg("PART", "bye")), None);
+    }
+
+    #[tokio::test]
+    async fn test_patterns_are_indexed_by_kind() {
+        let publisher = webhook_publisher::WebhookPublisher::new(
+            "http://example.com/hook".parse().unwrap(),
+            "${0}".to_string(),
+            std::collections::HashMap::new(),
+            webhook_publisher::PublisherOptions::default(),
+        );
+        let handler = MessageHandler::new(
+            &[
+                SearchPattern::new("chat"),
+                SearchPattern {
+                    pattern: "announce".to_string(),
+                    kinds: Some(vec![MessageKind::Notice, MessageKind::Action]),
+                    require_status: SenderStatus::Any,
+                    mapping: None,
+                    occurrences: None,
+                    replacement: None,
+                    channels: None,
+                    server: None,
+                },
+                SearchPattern {
+                    pattern: "any".to_string(),
+                    kinds: None,
+                    require_status: SenderStatus::Any,
+                    mapping: None,
+                    occurrences: None,
+                    replacement: None,
+                    channels: None,
+                    server: None,
+                },
+            ],
+            Arc::new(publisher),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(handler.index[&Some(MessageKind::Privmsg)], vec![0, 2]);
+        assert_eq!(handler.index[&Some(MessageKind::Notice)], vec![1, 2]);
+        assert_eq!(handler.index[&Some(MessageKind::Action)], vec![1, 2]);
+        assert_eq!(handler.index[&None], vec![2]);
+    }
+
+    #[tokio::test]
+    async fn test_require_sender_status() {
+        let publisher = webhook_publisher::WebhookPublisher::new(
+            "http://example.com/hook".parse().unwrap(),
+            "${0}".to_string(),
+            std::collections::HashMap::new(),
+            webhook_publisher::PublisherOptions {
+                publish_deadline: Some(Duration::ZERO),
+                ..webhook_publisher::PublisherOptions::default()
+            },
+        );
+        let handler = MessageHandler::new(
+            &[SearchPattern {
+                require_status: SenderStatus::Op,
+                ..SearchPattern::new("deploy")
+            }],
+            Arc::new(publisher),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+
+        let names = vec!["me", "=", "#ops", "@alice bob"];
+        handler
+            .handle_msg(Message::new(Some("irc.example.com"), "353", names).unwrap())
+            .await;
+        for nick in ["alice!a@host", "bob!b@host"] {
+            let msg = Message::new(Some(nick), "PRIVMSG", vec!["#ops", "deploy"]).unwrap();
+            handler.handle_msg(msg).await;
+        }
+
+        assert_eq!(handler.match_counts(), vec![("deploy", 1)]);
+    }
+
+    #[test]
+    fn test_parse_pattern_file() {
+        let contents = "# deploys\ndeployed (\\w+)\n\n  # failures\n  build (\\d+) failed  \r\n";
+
         assert_eq!(
-            get_content(&input),
-            Some("Hello this is a message".to_string())
+            parse_pattern_file(contents),
+            vec![r#"deployed (\w+)"#, r#"build (\d+) failed"#]
         );
     }
 
+    #[test]
+    fn test_handler_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<MessageHandler>();
+    }
+
     #[test]
     fn test_match_groups() {
         let content = r#"Main message 1capture match2 text 1another match2"#;
 
         let search_pattern = r#"\d(.+?)\d"#;
-        let re = Regex::new(&search_pattern).unwrap();
+        let re = Regex::new(search_pattern).unwrap();
 
-        let got = match_groups(&re, content);
+        let got = match_groups(&re, content, None);
 
         assert_eq!(
             got,
@@ -86,4 +2263,788 @@ mod tests {
             ]
         )
     }
+
+    #[test]
+    fn test_occurrence_filter() {
+        let re = Regex::new(r"\d").unwrap();
+        let positions = |filter: &str| -> Vec<String> {
+            match_groups(&re, "1 2 3 4 5", Some(&filter.parse().unwrap()))
+                .into_iter()
+                .map(|mut g| g.remove(0))
+                .collect()
+        };
+
+        assert_eq!(positions("2,3"), ["2", "3"]);
+        assert_eq!(positions("2-4"), ["2", "3", "4"]);
+        assert_eq!(positions("1, 4-"), ["1", "4", "5"]);
+        // Only the selected matches that exist are kept.
+        assert_eq!(positions("5-9"), ["5"]);
+        assert!(positions("6").is_empty());
+
+        for invalid in ["0", "3-2", "x", "", "1,,2"] {
+            assert!(invalid.parse::<OccurrenceFilter>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_regex_replacement() {
+        let publisher = Arc::new(MockPublisher::default());
+        let handler = MessageHandler::new(
+            &[SearchPattern {
+                replacement: Some("$user:${id}$$".to_string()),
+                occurrences: Some("2-".parse().unwrap()),
+                ..SearchPattern::new(r"(?P<user>\w+)#(?P<id>\d+)")
+            }],
+            publisher.clone(),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+
+        let msg = Message::new(Some("u"), "PRIVMSG", vec!["#ops", "a#1 b#2 c#3"]).unwrap();
+        handler.handle_msg(msg).await;
+
+        let published = publisher.published.lock().unwrap();
+        assert_eq!(published[0].1, vec![vec!["a#1 b:2$ c:3$".to_string()]]);
+    }
+
+    #[tokio::test]
+    async fn test_sample_rates() {
+        let rates = SampleRates {
+            group: 1,
+            rates: HashMap::from([("error".to_string(), 1.0), ("info".to_string(), 0.0)]),
+            default: 0.5,
+        };
+        let groups = |level: &str| vec![vec![format!("{}: x", level), level.to_string()]];
+        assert_eq!(rates.rate(&groups("error")), 1.0);
+        assert_eq!(rates.rate(&groups("warn")), 0.5);
+        assert_eq!(rates.rate(&[vec!["no group".to_string()]]), 0.5);
+
+        let publisher = Arc::new(MockPublisher::default());
+        let mut handler = MessageHandler::new(
+            &[SearchPattern::new(r"^(\w+):")],
+            publisher.clone(),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+        handler.sample_by(rates);
+        for content in ["info: a", "error: b", "info: c", "error: d"] {
+            let msg = Message::new(Some("u"), "PRIVMSG", vec!["#ops", content]).unwrap();
+            handler.handle_msg(msg).await;
+        }
+
+        let published: Vec<_> = publisher
+            .published
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, groups, _)| groups[0][0].clone())
+            .collect();
+        assert_eq!(published, ["error:", "error:"]);
+        // Dropped matches are still counted.
+        assert_eq!(handler.match_counts(), vec![(r"^(\w+):", 4)]);
+    }
+
+    #[tokio::test]
+    async fn test_concat_sender_lines() {
+        let publisher = Arc::new(MockPublisher::default());
+        let mut handler = MessageHandler::new(
+            &[SearchPattern::new(r"(?s)panicked at (\S+).*\n\s*0: (\S+)")],
+            publisher.clone(),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+        handler.concat_sender_lines(Duration::from_millis(20));
+
+        for (nick, content) in [
+            ("ci", "thread 'main' panicked at src/main.rs:3"),
+            ("alice", "is the build broken?"),
+            ("ci", "   0: irc_hook::main"),
+        ] {
+            let msg = Message::new(Some(nick), "PRIVMSG", vec!["#ci", content]).unwrap();
+            handler.handle_msg(msg).await;
+        }
+        handler.flush_buffered().await;
+        assert!(publisher.published.lock().unwrap().is_empty());
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        handler.flush_buffered().await;
+        let published = publisher.published.lock().unwrap();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].1[0][1..], ["src/main.rs:3", "irc_hook::main"]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_matches_survive_reconnect() {
+        let failed = Arc::new(Mutex::new(MatchQueue::new(10)));
+        let connect = |publisher: Arc<MockPublisher>| {
+            let mut handler = MessageHandler::new(
+                &[SearchPattern::new(r"deploy (\w+)")],
+                publisher,
+                MatchTarget::Content,
+                None,
+                &[],
+                None,
+            )
+            .unwrap();
+            handler.keep_failed(failed.clone());
+            handler
+        };
+
+        let publisher = Arc::new(MockPublisher::default());
+        publisher.down.store(true, Ordering::Relaxed);
+        let handler = connect(publisher.clone());
+        for content in ["deploy api", "deploy web"] {
+            let msg = Message::new(Some("ci"), "PRIVMSG", vec!["#ops", content]).unwrap();
+            handler.handle_msg(msg).await;
+        }
+        // Still down, so nothing is lost by retrying.
+        handler.retry_failed().await;
+        // The connection drops and the next one gets a new handler.
+        drop(handler);
+
+        publisher.down.store(false, Ordering::Relaxed);
+        let handler = connect(publisher.clone());
+        handler.retry_failed().await;
+
+        let published = publisher.published.lock().unwrap();
+        let published: Vec<_> = published
+            .iter()
+            .map(|(channel, groups, vars)| (channel.as_str(), groups[0][1].as_str(), &vars[0]))
+            .collect();
+        assert_eq!(
+            published,
+            [
+                ("#ops", "api", &("seq".to_string(), "1".to_string())),
+                ("#ops", "web", &("seq".to_string(), "2".to_string())),
+            ]
+        );
+        assert_eq!(failed.lock().unwrap().take(), (Vec::new(), 0));
+    }
+
+    #[tokio::test]
+    async fn test_failed_match_is_retried_through_its_rules_publisher() {
+        let default_publisher = Arc::new(MockPublisher::default());
+        let rule_publisher = Arc::new(MockPublisher::default());
+        let mut handler = MessageHandler::new(
+            &[
+                SearchPattern::new(r"deploy (\w+)"),
+                SearchPattern::new(r"alert (\w+)"),
+            ],
+            default_publisher.clone(),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+        handler.publish_pattern_with(1, rule_publisher.clone(), "http://alerts.example.com");
+        let failed = Arc::new(Mutex::new(MatchQueue::new(10)));
+        handler.keep_failed(failed.clone());
+
+        rule_publisher.down.store(true, Ordering::Relaxed);
+        for content in ["alert disk", "deploy api"] {
+            let msg = Message::new(Some("ci"), "PRIVMSG", vec!["#ops", content]).unwrap();
+            handler.handle_msg(msg).await;
+        }
+        rule_publisher.down.store(false, Ordering::Relaxed);
+        handler.retry_failed().await;
+
+        let groups = |publisher: &MockPublisher| -> Vec<String> {
+            let published = publisher.published.lock().unwrap();
+            published
+                .iter()
+                .map(|(_, groups, _)| groups[0][1].clone())
+                .collect()
+        };
+        assert_eq!(groups(&default_publisher), ["api"]);
+        assert_eq!(groups(&rule_publisher), ["disk"]);
+        assert_eq!(failed.lock().unwrap().take(), (Vec::new(), 0));
+    }
+
+    #[tokio::test]
+    async fn test_retries_continue_past_a_publisher_that_is_down() {
+        let default_publisher = Arc::new(MockPublisher::default());
+        let rule_publisher = Arc::new(MockPublisher::default());
+        let mut handler = MessageHandler::new(
+            &[
+                SearchPattern::new(r"deploy (\w+)"),
+                SearchPattern::new(r"alert (\w+)"),
+            ],
+            default_publisher.clone(),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+        handler.publish_pattern_with(1, rule_publisher.clone(), "http://alerts.example.com");
+        let failed = Arc::new(Mutex::new(MatchQueue::new(10)));
+        handler.keep_failed(failed.clone());
+
+        default_publisher.down.store(true, Ordering::Relaxed);
+        rule_publisher.down.store(true, Ordering::Relaxed);
+        for content in ["deploy api", "alert disk", "deploy web"] {
+            let msg = Message::new(Some("ci"), "PRIVMSG", vec!["#ops", content]).unwrap();
+            handler.handle_msg(msg).await;
+        }
+        // Only the rule's endpoint comes back.
+        rule_publisher.down.store(false, Ordering::Relaxed);
+        handler.retry_failed().await;
+
+        assert_eq!(rule_publisher.published.lock().unwrap().len(), 1);
+        let (items, _) = failed.lock().unwrap().take();
+        let kept: Vec<_> = items.iter().map(|item| item.content.as_str()).collect();
+        assert_eq!(kept, ["deploy api", "deploy web"]);
+    }
+
+    #[tokio::test]
+    async fn test_queued_matches_are_flushed_through_their_rules_publisher() {
+        for format in [FlushFormat::Digest, FlushFormat::Each] {
+            let default_publisher = Arc::new(MockPublisher::default());
+            let rule_publisher = Arc::new(MockPublisher::default());
+            let mut handler = MessageHandler::new(
+                &[
+                    SearchPattern::new(r"deploy (\w+)"),
+                    SearchPattern::new(r"alert (\w+)"),
+                ],
+                default_publisher.clone(),
+                MatchTarget::Content,
+                None,
+                &[],
+                None,
+            )
+            .unwrap();
+            handler.publish_pattern_with(1, rule_publisher.clone(), "http://alerts.example.com");
+            handler.publish_only_during(Schedule::new(Vec::new(), chrono_tz::UTC));
+            handler.queue_outside_schedule(Arc::new(Mutex::new(MatchQueue::new(10))), format);
+
+            for content in ["deploy api", "alert disk", "alert cpu"] {
+                let msg = Message::new(Some("ci"), "PRIVMSG", vec!["#ops", content]).unwrap();
+                handler.handle_msg(msg).await;
+            }
+            // The schedule opens.
+            handler.schedule = None;
+            handler.flush_queued().await;
+
+            let contents = |publisher: &MockPublisher| -> Vec<String> {
+                let published = publisher.published.lock().unwrap();
+                published
+                    .iter()
+                    .map(|(_, groups, _)| groups[0][0].clone())
+                    .collect()
+            };
+            match format {
+                FlushFormat::Digest => {
+                    assert_eq!(contents(&default_publisher), ["#ops: deploy api"]);
+                    assert_eq!(
+                        contents(&rule_publisher),
+                        ["#ops: alert disk\n#ops: alert cpu"]
+                    );
+                }
+                FlushFormat::Each => {
+                    assert_eq!(contents(&default_publisher), ["deploy api"]);
+                    assert_eq!(contents(&rule_publisher), ["alert disk", "alert cpu"]);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_state_survives_reconnect() {
+        let publisher = Arc::new(MockPublisher::default());
+        let state = Arc::new(HandlerState::default());
+        let connect = || {
+            let mut handler = MessageHandler::new(
+                &[SearchPattern::new(r"deploy (\w+)")],
+                publisher.clone(),
+                MatchTarget::Content,
+                None,
+                &[],
+                None,
+            )
+            .unwrap();
+            handler.mark_first_matches();
+            handler.dedup_within(Duration::from_secs(60), Some(1));
+            handler.share_state(state.clone());
+            handler
+        };
+
+        for content in ["deploy api", "deploy web", "deploy api"] {
+            // The connection drops before each message and the next one gets a new handler.
+            let handler = connect();
+            let msg = Message::new(Some("ci"), "PRIVMSG", vec!["#ops", content]).unwrap();
+            handler.handle_msg(msg).await;
+        }
+
+        let handler = connect();
+        assert_eq!(handler.processed_count(), 3);
+        assert_eq!(handler.match_counts(), [(r"deploy (\w+)", 3)]);
+        let published = publisher.published.lock().unwrap();
+        let published: Vec<_> = published
+            .iter()
+            .map(|(_, groups, vars)| (groups[0][1].as_str(), &vars[..2]))
+            .collect();
+        let vars = |seq: &str, first: &str| {
+            [
+                ("seq".to_string(), seq.to_string()),
+                ("first".to_string(), first.to_string()),
+            ]
+        };
+        // The repeat is still deduplicated, and `${seq}` and `${first}` carry on.
+        assert_eq!(
+            published,
+            [
+                ("api", &vars("1", "true")[..]),
+                ("web", &vars("2", "false")[..]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rules_sharing_a_regex_keep_their_own_state() {
+        let publisher = Arc::new(MockPublisher::default());
+        let in_channel = |channel: &str| SearchPattern {
+            channels: Some(vec![channel.to_string()]),
+            ..SearchPattern::new(r"deploy (\w+)")
+        };
+        let mut handler = MessageHandler::new(
+            &[in_channel("#ci"), in_channel("#ops")],
+            publisher.clone(),
+            MatchTarget::Content,
+            Some(cooldown_config()),
+            &[],
+            None,
+        )
+        .unwrap();
+        handler.mark_first_matches();
+
+        for channel in ["#ci", "#ops"] {
+            let msg = Message::new(Some("ci"), "PRIVMSG", vec![channel, "deploy api"]).unwrap();
+            handler.handle_msg(msg).await;
+        }
+
+        assert_eq!(
+            handler.match_counts(),
+            [(r"deploy (\w+)", 1), (r"deploy (\w+)", 1)]
+        );
+        // Neither rule's cooldown or first match is the other's.
+        let published = publisher.published.lock().unwrap();
+        let first: Vec<_> = published
+            .iter()
+            .map(|(channel, _, vars)| (channel.as_str(), vars[1].1.as_str()))
+            .collect();
+        assert_eq!(first, [("#ci", "true"), ("#ops", "true")]);
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_state_of_unchanged_patterns() {
+        let publisher = Arc::new(MockPublisher::default());
+        let state = Arc::new(HandlerState::default());
+        let load = |patterns: &[&str]| {
+            let patterns: Vec<_> = patterns.iter().map(|&p| SearchPattern::new(p)).collect();
+            let mut handler = MessageHandler::new(
+                &patterns,
+                publisher.clone(),
+                MatchTarget::Content,
+                None,
+                &[],
+                None,
+            )
+            .unwrap();
+            handler.share_state(state.clone());
+            handler
+        };
+
+        let handler = load(&[r"deploy (\w+)"]);
+        let msg = Message::new(Some("ci"), "PRIVMSG", vec!["#ops", "deploy api"]).unwrap();
+        handler.handle_msg(msg.clone()).await;
+
+        let handler = load(&[r"deploy (\w+)", r"rollback (\w+)"]);
+        handler.handle_msg(msg).await;
+        assert_eq!(handler.processed_count(), 2);
+        assert_eq!(
+            handler.match_counts(),
+            [(r"deploy (\w+)", 2), (r"rollback (\w+)", 0)]
+        );
+        let published = publisher.published.lock().unwrap();
+        assert_eq!(published[1].2[0], ("seq".to_string(), "2".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_reload_starts_rules_with_a_new_webhook_over() {
+        let publisher = Arc::new(MockPublisher::default());
+        let state = Arc::new(HandlerState::default());
+        let load = |destination: &str| {
+            let mut handler = MessageHandler::new(
+                &[SearchPattern::new(r"deploy (\w+)")],
+                publisher.clone(),
+                MatchTarget::Content,
+                None,
+                &[],
+                None,
+            )
+            .unwrap();
+            handler.share_state(state.clone());
+            handler.publish_pattern_with(0, publisher.clone(), destination);
+            handler.mark_first_matches();
+            handler
+        };
+        let msg = Message::new(Some("ci"), "PRIVMSG", vec!["#ops", "deploy api"]).unwrap();
+
+        for destination in [
+            "http://a.example.com",
+            "http://a.example.com",
+            "http://b.example.com",
+        ] {
+            load(destination).handle_msg(msg.clone()).await;
+        }
+
+        assert_eq!(
+            load("http://b.example.com").match_counts(),
+            [(r"deploy (\w+)", 1)]
+        );
+        let published = publisher.published.lock().unwrap();
+        let first: Vec<_> = published
+            .iter()
+            .map(|(_, _, vars)| vars[1].1.as_str())
+            .collect();
+        assert_eq!(first, ["true", "false", "true"]);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_match_is_not_kept() {
+        let publisher = Arc::new(MockPublisher::default());
+        publisher
+            .rejected
+            .lock()
+            .unwrap()
+            .push("deploy bad".to_string());
+        let mut handler = MessageHandler::new(
+            &[SearchPattern::new(r"deploy (\w+)")],
+            publisher.clone(),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+        let failed = Arc::new(Mutex::new(MatchQueue::new(10)));
+        handler.keep_failed(failed.clone());
+
+        let msg = Message::new(Some("ci"), "PRIVMSG", vec!["#ops", "deploy bad"]).unwrap();
+        handler.handle_msg(msg).await;
+
+        assert!(publisher.published.lock().unwrap().is_empty());
+        assert_eq!(failed.lock().unwrap().take(), (Vec::new(), 0));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_match_does_not_block_retries() {
+        let publisher = Arc::new(MockPublisher::default());
+        let mut handler = MessageHandler::new(
+            &[SearchPattern::new(r"deploy (\w+)")],
+            publisher.clone(),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+        let failed = Arc::new(Mutex::new(MatchQueue::new(10)));
+        handler.keep_failed(failed.clone());
+
+        publisher.down.store(true, Ordering::Relaxed);
+        for content in ["deploy bad", "deploy api"] {
+            let msg = Message::new(Some("ci"), "PRIVMSG", vec!["#ops", content]).unwrap();
+            handler.handle_msg(msg).await;
+        }
+        publisher.down.store(false, Ordering::Relaxed);
+        publisher
+            .rejected
+            .lock()
+            .unwrap()
+            .push("deploy bad".to_string());
+        handler.retry_failed().await;
+
+        let published = publisher.published.lock().unwrap();
+        let published: Vec<_> = published
+            .iter()
+            .map(|(_, groups, _)| groups[0][1].as_str())
+            .collect();
+        assert_eq!(published, ["api"]);
+        assert_eq!(failed.lock().unwrap().take(), (Vec::new(), 0));
+    }
+
+    #[tokio::test]
+    async fn test_dedup_by_group() {
+        let publisher = Arc::new(MockPublisher::default());
+        let mut handler = MessageHandler::new(
+            &[SearchPattern::new(r"build (\d+)")],
+            publisher.clone(),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+        handler.dedup_within(Duration::from_secs(60), Some(1));
+
+        for content in ["build 7 started", "build 7 passed", "build 8 started"] {
+            let msg = Message::new(Some("ci"), "PRIVMSG", vec!["#ci", content]).unwrap();
+            handler.handle_msg(msg).await;
+        }
+
+        let builds: Vec<_> = publisher
+            .published
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, groups, _)| groups[0][1].clone())
+            .collect();
+        assert_eq!(builds, ["7", "8"]);
+    }
+
+    #[tokio::test]
+    async fn test_named_groups_are_template_vars() {
+        let publisher = Arc::new(MockPublisher::default());
+        let handler = MessageHandler::new(
+            &[SearchPattern {
+                mapping: Some(GroupMapping {
+                    group: 2,
+                    values: HashMap::from([("+o".to_string(), "op".to_string())]),
+                    default: None,
+                }),
+                ..SearchPattern::new(
+                    r"(?P<nick>\w+) set mode (?P<mode>\S+)(?: on (?P<target>\w+))?",
+                )
+            }],
+            publisher.clone(),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+
+        let msg = Message::new(Some("ci"), "PRIVMSG", vec!["#ops", "alice set mode +o"]).unwrap();
+        handler.handle_msg(msg).await;
+
+        let published = publisher.published.lock().unwrap();
+        let (_, groups, vars) = &published[0];
+        assert_eq!(groups[0], ["alice set mode +o", "alice", "op"]);
+        let var = |name: &str| {
+            vars.iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(var("nick"), Some("alice"));
+        assert_eq!(var("mode"), Some("op"));
+        assert_eq!(var("target"), None);
+    }
+
+    #[tokio::test]
+    async fn test_action_is_matched_without_ctcp_framing() {
+        let publisher = Arc::new(MockPublisher::default());
+        let handler = MessageHandler::new(
+            &[SearchPattern {
+                kinds: Some(vec![MessageKind::Action]),
+                ..SearchPattern::new(r"^deploys (\w+)$")
+            }],
+            publisher.clone(),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+
+        let msg = Message::new(
+            Some("alice!a@host"),
+            "PRIVMSG",
+            vec!["#ops", "\x01ACTION deploys api\x01"],
+        )
+        .unwrap();
+        handler.handle_msg(msg).await;
+
+        let published = publisher.published.lock().unwrap();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].1[0], ["deploys api", "api"]);
+    }
+
+    #[tokio::test]
+    async fn test_message_context_vars() {
+        let publisher = Arc::new(MockPublisher::default());
+        let handler = MessageHandler::new(
+            &[SearchPattern::new(r"deploy (\w+)")],
+            publisher.clone(),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+
+        let msg = Message::new(
+            Some("alice!a@example.com"),
+            "PRIVMSG",
+            vec!["#ops", "deploy ok"],
+        )
+        .unwrap();
+        assert_eq!(MessageContext::new(&msg).nick.as_deref(), Some("alice"));
+        handler.handle_msg(msg).await;
+
+        let published = publisher.published.lock().unwrap();
+        let (channel, _, vars) = &published[0];
+        assert_eq!(channel, "#ops");
+        let var = |name: &str| {
+            vars.iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(var("nick"), Some("alice"));
+        assert!(var("timestamp").is_some_and(|t| t.parse::<u64>().unwrap() > 0));
+    }
+
+    #[tokio::test]
+    async fn test_correlate_start_and_complete() {
+        let publisher = Arc::new(MockPublisher::default());
+        let mut handler = MessageHandler::new(
+            &[SearchPattern::new("deploy failed")],
+            publisher.clone(),
+            MatchTarget::Content,
+            None,
+            &[],
+            None,
+        )
+        .unwrap();
+        let rule = CorrelationRule {
+            start: r"job (\d+) started by (\w+)".to_string(),
+            complete: r"job (\d+) result: (\w+)".to_string(),
+            key_group: 1,
+            timeout: Duration::from_secs(60),
+            on_timeout: TimeoutAction::Drop,
+        };
+        handler.correlate(std::slice::from_ref(&rule)).unwrap();
+
+        for (channel, content) in [
+            ("#ci", "job 7 started by alice"),
+            ("#ops", "job 7 result: failed"),
+            ("#ci", "job 8 result: ok"),
+            ("#ci", "job 7 result: ok"),
+        ] {
+            let msg = Message::new(Some("ci"), "PRIVMSG", vec![channel, content]).unwrap();
+            handler.handle_msg(msg).await;
+        }
+
+        let published = std::mem::take(&mut *publisher.published.lock().unwrap());
+        assert_eq!(published.len(), 1);
+        let (channel, groups, vars) = &published[0];
+        assert_eq!(channel, "#ci");
+        assert_eq!(groups, &[vec!["job 7 result: ok", "7", "ok"]]);
+        let var = |name: &str| {
+            vars.iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(var("start:2"), Some("alice"));
+        assert_eq!(var("correlation"), Some("complete"));
+
+        // Starts that time out are published on their own when configured to be.
+        handler
+            .correlate(&[CorrelationRule {
+                timeout: Duration::ZERO,
+                on_timeout: TimeoutAction::Publish,
+                ..rule
+            }])
+            .unwrap();
+        let msg = Message::new(Some("ci"), "PRIVMSG", vec!["#ci", "job 9 started by bob"]).unwrap();
+        handler.handle_msg(msg).await;
+        handler.expire_correlations().await;
+
+        let published = publisher.published.lock().unwrap();
+        assert_eq!(published.len(), 1);
+        let (_, groups, vars) = &published[0];
+        assert_eq!(groups[0][1], "9");
+        assert!(vars.contains(&("correlation".to_string(), "timeout".to_string())));
+    }
+
+    fn cooldown_config() -> CooldownConfig {
+        CooldownConfig {
+            cooldown: Duration::from_secs(60),
+            min_cooldown: Duration::from_secs(15),
+            max_cooldown: Duration::from_secs(240),
+            low_rate_threshold: Some(1.0),
+            high_rate_threshold: Some(60.0),
+            ema_alpha: 0.1,
+        }
+    }
+
+    #[test]
+    fn test_cooldown_suppresses_within_period() {
+        let mut cooldown = Cooldown::new(CooldownConfig {
+            low_rate_threshold: None,
+            high_rate_threshold: None,
+            ..cooldown_config()
+        });
+        let start = Instant::now();
+
+        assert!(cooldown.allow(start));
+        assert!(!cooldown.allow(start + Duration::from_secs(30)));
+        assert!(cooldown.allow(start + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn test_cooldown_doubles_on_high_rate() {
+        let mut cooldown = Cooldown::new(cooldown_config());
+        let start = Instant::now();
+
+        // One match every 10 seconds is 360 matches/hour.
+        for i in 0..4 {
+            cooldown.allow(start + Duration::from_secs(i * 10));
+        }
+
+        assert_eq!(cooldown.current, Duration::from_secs(240));
+    }
+
+    #[test]
+    fn test_cooldown_halves_on_low_rate() {
+        let mut cooldown = Cooldown::new(cooldown_config());
+        let start = Instant::now();
+
+        // One match every two hours is 0.5 matches/hour.
+        cooldown.allow(start);
+        cooldown.allow(start + Duration::from_secs(7200));
+
+        assert_eq!(cooldown.current, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_cooldown_recovers_from_minimum() {
+        let mut cooldown = Cooldown::new(CooldownConfig {
+            ema_alpha: 1.0,
+            ..cooldown_config()
+        });
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // A long quiet stretch halves the cooldown down to the minimum and no further.
+        for i in 0..5 {
+            cooldown.allow(at(i * 7200));
+        }
+        assert_eq!(cooldown.current, Duration::from_secs(15));
+
+        // One match every 10 seconds doubles it back up.
+        cooldown.allow(at(4 * 7200 + 10));
+        cooldown.allow(at(4 * 7200 + 20));
+        assert_eq!(cooldown.current, Duration::from_secs(60));
+    }
 }
@@ -0,0 +1,63 @@
+/// The line length limit from RFC 1459, including the trailing CRLF.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 512;
+
+/// Room left for the `:nick!user@host ` prefix the server adds when relaying our messages. We
+/// can't know its exact length, so this covers a long nick and a full-length hostname.
+const SOURCE_RESERVE: usize = 110;
+
+/// Splits `text` into PRIVMSG bodies for `target` whose lines, once relayed, fit in
+/// `max_line_length` bytes. Newlines always start a new message, and long lines are broken at the
+/// last space that fits, or mid-word if there isn't one, but never inside a character.
+pub fn split_message(target: &str, text: &str, max_line_length: usize) -> Vec<String> {
+    let overhead = SOURCE_RESERVE + "PRIVMSG ".len() + target.len() + " :\r\n".len();
+    // Always leave room for at least one character so long targets can't stall the split.
+    let budget = max_line_length.saturating_sub(overhead).max(4);
+
+    let mut messages = Vec::new();
+    for line in text.lines() {
+        let mut rest = line.trim_end_matches('\r');
+        while rest.len() > budget {
+            let mut end = budget;
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            let (head, tail) = match rest[..end].rfind(' ') {
+                Some(space) if space > 0 => (&rest[..space], &rest[space + 1..]),
+                _ => (&rest[..end], &rest[end..]),
+            };
+            messages.push(head.to_string());
+            rest = tail;
+        }
+        if !rest.is_empty() {
+            messages.push(rest.to_string());
+        }
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_message() {
+        let overhead = SOURCE_RESERVE + "PRIVMSG #ops :\r\n".len();
+        let max = overhead + 20;
+
+        assert_eq!(split_message("#ops", "deploy ok", max), ["deploy ok"]);
+        assert_eq!(
+            split_message(
+                "#ops",
+                "deploy of build 4821 finished\nall checks passed",
+                max
+            ),
+            ["deploy of build", "4821 finished", "all checks passed"]
+        );
+        // No space to break at, and a multi-byte character straddling the limit.
+        assert_eq!(
+            split_message("#ops", "aaaaaaaaaaaaaaaaaaaéb", max),
+            ["aaaaaaaaaaaaaaaaaaa", "éb"]
+        );
+        assert!(split_message("#ops", "", max).is_empty());
+    }
+}
@@ -0,0 +1,77 @@
+use irc::client::prelude::*;
+use std::sync::{Arc, RwLock};
+
+/// The bot's nickname as the server currently knows it. It starts as the configured nick and
+/// follows the server from then on: the nick confirmed by the welcome reply, and any NICK change
+/// of ours, including ones the server forces with SANICK or after a collision. Clones share the
+/// same nick.
+#[derive(Clone)]
+pub struct OwnNick(Arc<RwLock<String>>);
+
+impl OwnNick {
+    pub fn new(nick: impl Into<String>) -> Self {
+        OwnNick(Arc::new(RwLock::new(nick.into())))
+    }
+
+    pub fn get(&self) -> String {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Returns true if `nick` is ours, compared case-insensitively.
+    pub fn is(&self, nick: &str) -> bool {
+        self.0.read().unwrap().eq_ignore_ascii_case(nick)
+    }
+
+    /// Follows `msg` if it changes our nick, returning the new one when it does.
+    pub fn update(&self, msg: &Message) -> Option<String> {
+        let new_nick = match &msg.command {
+            // RPL_WELCOME's first argument is the nick we registered with.
+            Command::Response(Response::RPL_WELCOME, args) => args.first()?,
+            Command::NICK(new_nick) if self.is(msg.source_nickname()?) => new_nick,
+            _ => return None,
+        };
+
+        let mut nick = self.0.write().unwrap();
+        if *nick == *new_nick {
+            return None;
+        }
+        *nick = new_nick.clone();
+        Some(new_nick.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_handler::{IgnoreSelfFilter, MessageFilter};
+
+    fn msg(prefix: &str, command: &str, args: Vec<&str>) -> Message {
+        Message::new(Some(prefix), command, args).unwrap()
+    }
+
+    #[test]
+    fn test_follows_forced_nick_changes() {
+        let nick = OwnNick::new("hook");
+        let filter = IgnoreSelfFilter::new(nick.clone());
+        let from_us = |n: &str| msg(&format!("{}!bot@host", n), "PRIVMSG", vec!["#ops", "hi"]);
+
+        // The server truncated or otherwise changed the nick during registration.
+        let welcome = msg("irc.example.com", "001", vec!["hook_", "Welcome"]);
+        assert_eq!(nick.update(&welcome), Some("hook_".to_string()));
+        assert!(!filter.should_process(&from_us("hook_")));
+        assert!(filter.should_process(&from_us("hook")));
+
+        // Someone else changing nick doesn't affect ours.
+        assert_eq!(
+            nick.update(&msg("alice!a@host", "NICK", vec!["hook2"])),
+            None
+        );
+        assert_eq!(nick.get(), "hook_");
+
+        // A forced rename, e.g. by SANICK.
+        let renamed = msg("Hook_!bot@host", "NICK", vec!["Guest123"]);
+        assert_eq!(nick.update(&renamed), Some("Guest123".to_string()));
+        assert!(!filter.should_process(&from_us("guest123")));
+        assert!(filter.should_process(&from_us("hook_")));
+    }
+}
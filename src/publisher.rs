@@ -0,0 +1,18 @@
+use async_trait::async_trait;
+use std::time::Instant;
+
+/// Sends the capture groups of a match somewhere. The message handler publishes through this
+/// trait, so other destinations (or a mock in tests) can stand in for `WebhookPublisher`.
+#[async_trait]
+pub trait Publisher: Send + Sync {
+    /// Publishes each group in `groups`, matched in `channel` at `matched_at`. `vars` are extra
+    /// `${name}` values for the publisher's templates, such as `seq` or the values of the
+    /// pattern's named groups.
+    async fn publish(
+        &self,
+        channel: &str,
+        groups: Vec<Vec<String>>,
+        vars: &[(&str, String)],
+        matched_at: Instant,
+    ) -> anyhow::Result<()>;
+}
@@ -0,0 +1,86 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const WINDOW: Duration = Duration::from_secs(1);
+const WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Tracks the number of requests sent to an endpoint over the trailing second and warns, at most
+/// once every ten seconds, when it exceeds `threshold` requests/second.
+pub struct RateMonitor {
+    threshold: f64,
+    state: Mutex<RateState>,
+}
+
+struct RateState {
+    recent: VecDeque<Instant>,
+    last_warned: Option<Instant>,
+}
+
+impl RateMonitor {
+    pub fn new(threshold: f64) -> Self {
+        RateMonitor {
+            threshold,
+            state: Mutex::new(RateState {
+                recent: VecDeque::new(),
+                last_warned: None,
+            }),
+        }
+    }
+
+    pub fn record_request(&self, endpoint: &http::Uri) {
+        if let Some(rate) = self.record(Instant::now()) {
+            tracing::warn!(endpoint = %endpoint, rate, "webhook rate limit approaching");
+        }
+    }
+
+    /// Records a request at `now` and returns the current rate if a warning is due.
+    fn record(&self, now: Instant) -> Option<usize> {
+        let mut state = self.state.lock().unwrap();
+
+        state.recent.push_back(now);
+        while let Some(&oldest) = state.recent.front() {
+            if now.duration_since(oldest) < WINDOW {
+                break;
+            }
+            state.recent.pop_front();
+        }
+
+        let rate = state.recent.len();
+        if (rate as f64) <= self.threshold {
+            return None;
+        }
+        if matches!(state.last_warned, Some(last) if now.duration_since(last) < WARN_INTERVAL) {
+            return None;
+        }
+        state.last_warned = Some(now);
+
+        Some(rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warns_above_threshold_once_per_interval() {
+        let monitor = RateMonitor::new(2.0);
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+
+        assert_eq!(monitor.record(ms(0)), None);
+        assert_eq!(monitor.record(ms(100)), None);
+        assert_eq!(monitor.record(ms(200)), Some(3));
+        assert_eq!(monitor.record(ms(300)), None);
+
+        // The earlier requests have left the window.
+        assert_eq!(monitor.record(ms(5000)), None);
+
+        assert_eq!(monitor.record(ms(10_100)), None);
+        assert_eq!(monitor.record(ms(10_150)), None);
+        assert_eq!(monitor.record(ms(10_200)), Some(3));
+    }
+}
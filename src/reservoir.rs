@@ -0,0 +1,70 @@
+use rand::Rng;
+
+/// Keeps a uniform random sample of up to `capacity` items from a stream of unknown length
+/// (Algorithm R): once full, the nth item replaces a random slot with probability capacity/n.
+pub struct Reservoir<T> {
+    capacity: usize,
+    seen: u64,
+    items: Vec<T>,
+}
+
+impl<T> Reservoir<T> {
+    pub fn new(capacity: usize) -> Self {
+        Reservoir {
+            capacity,
+            seen: 0,
+            items: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn add(&mut self, item: T) {
+        self.add_with(item, &mut rand::thread_rng());
+    }
+
+    fn add_with(&mut self, item: T, rng: &mut impl Rng) {
+        self.seen += 1;
+
+        if self.items.len() < self.capacity {
+            self.items.push(item);
+            return;
+        }
+        let slot = rng.gen_range(0..self.seen);
+        if let Some(existing) = self.items.get_mut(slot as usize) {
+            *existing = item;
+        }
+    }
+
+    /// The number of items offered so far, including ones not kept.
+    pub fn seen(&self) -> u64 {
+        self.seen
+    }
+
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_keeps_at_most_capacity() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut reservoir = Reservoir::new(3);
+
+        for i in 0..2 {
+            reservoir.add_with(i, &mut rng);
+        }
+        assert_eq!(reservoir.items(), &[0, 1]);
+
+        for i in 2..1000 {
+            reservoir.add_with(i, &mut rng);
+        }
+        assert_eq!(reservoir.items().len(), 3);
+        assert_eq!(reservoir.seen(), 1000);
+        // Early items are very unlikely to all survive 1000 offers.
+        assert_ne!(reservoir.items(), &[0, 1, 2]);
+    }
+}
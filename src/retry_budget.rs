@@ -0,0 +1,76 @@
+use std::{sync::Mutex, time::Instant};
+
+/// A token bucket that bounds the rate of webhook retries. Publishers share one budget through an
+/// `Arc`, so retry traffic stays within `rate` retries/second (with bursts of up to `burst`)
+/// however many patterns are retrying against a failing endpoint.
+pub struct RetryBudget {
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RetryBudget {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        RetryBudget {
+            rate,
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token if one is available, returning whether a retry may be sent.
+    pub fn try_acquire(&self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        let elapsed = now.saturating_duration_since(state.refilled).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.burst);
+        state.refilled = now;
+
+        if state.tokens < 1.0 {
+            return false;
+        }
+        state.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_budget_refills_at_rate() {
+        let start = Instant::now();
+        let budget = RetryBudget::new(2.0, 3.0);
+
+        for _ in 0..3 {
+            assert!(budget.try_acquire_at(start));
+        }
+        assert!(!budget.try_acquire_at(start));
+
+        // Half a second at 2 retries/second is one more token.
+        let later = start + Duration::from_millis(500);
+        assert!(budget.try_acquire_at(later));
+        assert!(!budget.try_acquire_at(later));
+
+        // The bucket never holds more than the burst.
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(budget.try_acquire_at(much_later));
+        }
+        assert!(!budget.try_acquire_at(much_later));
+    }
+}
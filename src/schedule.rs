@@ -0,0 +1,107 @@
+use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+
+/// A daily time range, optionally limited to some days of the week. A range whose end is before
+/// its start runs past midnight into the next day.
+#[derive(Clone, Debug)]
+pub struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+    /// Every day when `None`.
+    days: Option<Vec<Weekday>>,
+}
+
+impl Window {
+    /// Parses `start` and `end` as `HH:MM` and `days` as weekday names like `mon` or `Monday`.
+    pub fn parse(start: &str, end: &str, days: Option<&[String]>) -> Result<Self, String> {
+        let time = |s: &str| {
+            NaiveTime::parse_from_str(s, "%H:%M").map_err(|_| format!("invalid time: {}", s))
+        };
+        let days = days
+            .map(|days| {
+                days.iter()
+                    .map(|d| d.parse().map_err(|_| format!("invalid weekday: {}", d)))
+                    .collect::<Result<Vec<Weekday>, String>>()
+            })
+            .transpose()?;
+
+        Ok(Window {
+            start: time(start)?,
+            end: time(end)?,
+            days,
+        })
+    }
+
+    fn on(&self, day: Weekday) -> bool {
+        self.days.as_ref().is_none_or(|days| days.contains(&day))
+    }
+
+    fn contains(&self, day: Weekday, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.on(day) && self.start <= time && time < self.end
+        } else {
+            (self.on(day) && time >= self.start) || (self.on(day.pred()) && time < self.end)
+        }
+    }
+}
+
+/// The times matches are published. Outside every window they're still counted but not sent.
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    windows: Vec<Window>,
+    timezone: Tz,
+}
+
+impl Schedule {
+    pub fn new(windows: Vec<Window>, timezone: Tz) -> Self {
+        Schedule { windows, timezone }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.is_open_at(Utc::now())
+    }
+
+    fn is_open_at(&self, now: DateTime<Utc>) -> bool {
+        let local = now.with_timezone(&self.timezone);
+        self.windows
+            .iter()
+            .any(|w| w.contains(local.weekday(), local.time()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_is_open_at() {
+        let weekdays: Vec<String> = ["mon", "tue", "wed", "thu", "fri"]
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        let schedule = Schedule::new(
+            vec![
+                Window::parse("09:00", "17:30", Some(&weekdays)).unwrap(),
+                Window::parse("22:00", "02:00", Some(&["sat".to_string()])).unwrap(),
+            ],
+            chrono_tz::Europe::Berlin,
+        );
+        // Berlin is UTC+2 in June.
+        let utc = |d, h, m| Utc.with_ymd_and_hms(2024, 6, d, h, m, 0).unwrap();
+
+        // Monday 3 June.
+        assert!(schedule.is_open_at(utc(3, 7, 0)));
+        assert!(!schedule.is_open_at(utc(3, 6, 59)));
+        assert!(!schedule.is_open_at(utc(3, 15, 30)));
+
+        // Saturday night runs into Sunday morning, but Sunday night doesn't.
+        assert!(schedule.is_open_at(utc(8, 20, 0)));
+        assert!(schedule.is_open_at(utc(8, 23, 59)));
+        assert!(!schedule.is_open_at(utc(9, 0, 0)));
+        assert!(!schedule.is_open_at(utc(9, 20, 0)));
+
+        assert!(Window::parse("9am", "17:00", None).is_err());
+        assert!(Window::parse("09:00", "17:00", Some(&["someday".to_string()])).is_err());
+    }
+}
@@ -1,16 +1,420 @@
-use http::HeaderMap;
-use std::{collections::HashMap, sync::Arc};
+use crate::{
+    backoff::RetryConfig,
+    concurrency_limiter::{ConcurrencyConfig, ConcurrencyLimiter},
+    delivery::DeliveryConfig,
+    latency_tracker::LatencyTracker,
+    publisher::Publisher,
+    rate_monitor::RateMonitor,
+    retry_budget::RetryBudget,
+};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
+use http::{
+    header::{HeaderName, HeaderValue, CONTENT_TYPE},
+    HeaderMap,
+};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use tokio::task;
 
+#[derive(Clone)]
 pub struct WebhookPublisher {
     client: Arc<reqwest::Client>,
     config: Arc<EndpointConfig>,
+    /// Counts publishes to pick the next endpoint in rotation.
+    next_endpoint: Arc<AtomicUsize>,
     template: String,
     headers: HashMap<&'static str, String>,
+    /// Sent as extra lines after `headers`, even when the name is already there.
+    appended_headers: Vec<(&'static str, String)>,
+    capture_headers: Option<CaptureHeaders>,
+    json_types: HashMap<usize, JsonType>,
+    multipart_parts: Vec<(String, String)>,
+    expected_status: Option<Arc<[http::StatusCode]>>,
+    signing: Option<SigningConfig>,
+    limiter: Option<Arc<ConcurrencyLimiter>>,
+    body_encoding: BodyEncoding,
+    on_missing_group: MissingGroup,
+    publish_deadline: Option<Duration>,
+    retry: Option<Arc<RetryConfig>>,
+    /// Bounds each request, including each retry, separately.
+    timeout: Option<Duration>,
+    retry_budget: Option<Arc<RetryBudget>>,
+    rate_monitor: Option<Arc<RateMonitor>>,
+    allowed_hosts: Option<Arc<Vec<String>>>,
+    multi_match: MultiMatchMode,
+    counters: Arc<Counters>,
 }
 
+/// Delivery latencies kept for percentiles.
+const LATENCY_SAMPLES: usize = 1024;
+
+/// What a publisher has delivered, shed, and failed to deliver. Publishers can share one, so the
+/// publisher a reconnect or reload replaces another with keeps counting where it left off.
+pub struct Counters {
+    shed: AtomicU64,
+    published: AtomicU64,
+    failed: AtomicU64,
+    /// From match to a 2xx response, including time spent queued, rate limited, and retrying.
+    delivery: LatencyTracker,
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Counters {
+            shed: AtomicU64::new(0),
+            published: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            delivery: LatencyTracker::new(LATENCY_SAMPLES),
+        }
+    }
+}
+
+/// Optional publishing behaviour. The default sends unsigned raw bodies without any limits.
+#[derive(Clone, Default)]
+pub struct PublisherOptions {
+    pub signing: Option<SigningConfig>,
+    pub concurrency: Option<ConcurrencyConfig>,
+    pub body_encoding: BodyEncoding,
+    pub on_missing_group: MissingGroup,
+    /// Matches that can't start publishing within this long of being matched are dropped.
+    pub publish_deadline: Option<Duration>,
+    pub retry: Option<RetryConfig>,
+    /// How long each request may take before it fails.
+    pub timeout: Option<Duration>,
+    /// Limits retries across every publisher sharing the budget.
+    pub retry_budget: Option<Arc<RetryBudget>>,
+    /// Requests/second above which a warning is logged.
+    pub warn_rate_threshold: Option<f64>,
+    /// Hosts to connect to at a fixed address instead of resolving them with DNS.
+    pub resolve: Vec<(String, SocketAddr)>,
+    /// Replaces `${instance}` in the body and header templates.
+    pub instance: Option<String>,
+    /// When set, requests (including redirects) to any other host are refused.
+    pub allowed_hosts: Option<Vec<String>>,
+    pub multi_match: MultiMatchMode,
+    /// Headers added alongside any of the same name instead of replacing them, for headers like
+    /// `Cookie` that may repeat. Templated like the other headers.
+    pub appended_headers: Vec<(&'static str, String)>,
+    /// When set, each capture group is also sent as a header.
+    pub capture_headers: Option<CaptureHeaders>,
+    /// `(name, template)` for each part of a `multipart` body, in order.
+    pub multipart_parts: Vec<(String, String)>,
+    /// The statuses that count as delivered. Any 2xx when unset.
+    pub expected_status: Option<Vec<http::StatusCode>>,
+    /// Groups sent as JSON numbers or booleans instead of strings in the `json` body encoding,
+    /// by group index.
+    pub json_types: HashMap<usize, JsonType>,
+}
+
+/// The JSON type a group is sent as when its value parses as one. Values that don't are still
+/// sent as strings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JsonType {
+    Number,
+    Bool,
+}
+
+impl FromStr for JsonType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "number" => Ok(JsonType::Number),
+            "bool" => Ok(JsonType::Bool),
+            other => Err(format!("unknown JSON type: {}", other)),
+        }
+    }
+}
+
+/// `group` as a JSON array, with the groups in `types` as numbers or booleans where their values
+/// are valid JSON numbers or `true`/`false`.
+fn json_group(group: &[String], types: &HashMap<usize, JsonType>) -> serde_json::Value {
+    group
+        .iter()
+        .enumerate()
+        .map(|(idx, value)| {
+            let typed = match types.get(&idx) {
+                Some(JsonType::Number) => value
+                    .parse::<serde_json::Number>()
+                    .ok()
+                    .map(serde_json::Value::Number),
+                Some(JsonType::Bool) => value.parse::<bool>().ok().map(serde_json::Value::Bool),
+                None => None,
+            };
+            typed.unwrap_or_else(|| serde_json::Value::String(value.clone()))
+        })
+        .collect()
+}
+
+/// Sends group N as the header `<prefix>N`, e.g. `x-capture-1`, for up to `max` groups starting
+/// from the whole match. Control characters in the values are replaced with spaces.
+#[derive(Clone, Debug)]
+pub struct CaptureHeaders {
+    pub prefix: String,
+    pub max: usize,
+}
+
+impl CaptureHeaders {
+    fn add(&self, group: &[String], headers: &mut HeaderMap) {
+        for (idx, value) in group.iter().enumerate().take(self.max) {
+            let sanitized: String = value
+                .chars()
+                .map(|c| if c.is_control() && c != '\t' { ' ' } else { c })
+                .collect();
+            let name = HeaderName::from_bytes(format!("{}{}", self.prefix, idx).as_bytes());
+            match (name, HeaderValue::from_str(&sanitized)) {
+                (Ok(name), Ok(value)) => {
+                    headers.insert(name, value);
+                }
+                _ => tracing::warn!(idx, "skipping capture that can't be sent as a header"),
+            }
+        }
+    }
+}
+
+/// How a message that matches more than once is published.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MultiMatchMode {
+    /// A request for each match.
+    #[default]
+    PerMatch,
+    /// One request whose body is a JSON array with an element per match.
+    SingleArray,
+    /// A request for the first match only.
+    FirstOnly,
+}
+
+impl FromStr for MultiMatchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "per_match" => Ok(MultiMatchMode::PerMatch),
+            "single_array" => Ok(MultiMatchMode::SingleArray),
+            "first_only" => Ok(MultiMatchMode::FirstOnly),
+            other => Err(format!("unknown multi_match_mode: {}", other)),
+        }
+    }
+}
+
+/// How the request body is built from the body template or the capture groups.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BodyEncoding {
+    /// The rendered template is sent as-is.
+    #[default]
+    Raw,
+    /// The rendered template is base64 and is decoded into raw bytes before sending.
+    Base64,
+    /// The groups are sent as `group_0=...&group_1=...` without using the template.
+    Form,
+    /// The groups are sent as a JSON array of strings without using the template.
+    Json,
+    /// The configured multipart parts are sent as `multipart/form-data`, each a text part rendered
+    /// from its own template. The body template isn't used.
+    Multipart,
+}
+
+impl FromStr for BodyEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(BodyEncoding::Raw),
+            "base64" => Ok(BodyEncoding::Base64),
+            "form" => Ok(BodyEncoding::Form),
+            "json" => Ok(BodyEncoding::Json),
+            "multipart" => Ok(BodyEncoding::Multipart),
+            other => Err(format!("unknown body encoding: {}", other)),
+        }
+    }
+}
+
+/// What to do with `${N}` or `${name}` placeholders that are still in the rendered body, e.g. when
+/// the template refers to a group the pattern doesn't have.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MissingGroup {
+    /// Send the placeholders as-is.
+    #[default]
+    Leave,
+    /// Log the placeholders and replace them with an empty string.
+    Empty,
+    /// Log the placeholders and don't send the request.
+    Error,
+}
+
+impl FromStr for MissingGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "leave" => Ok(MissingGroup::Leave),
+            "empty" => Ok(MissingGroup::Empty),
+            "error" => Ok(MissingGroup::Error),
+            other => Err(format!("unknown missing group behavior: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RenderError {
+    InvalidBase64(base64::DecodeError),
+    UnresolvedPlaceholders(Vec<String>),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::InvalidBase64(e) => write!(f, "rendered body is not valid base64: {}", e),
+            RenderError::UnresolvedPlaceholders(placeholders) => write!(
+                f,
+                "rendered body has unresolved placeholders: {}",
+                placeholders.join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+/// Why a single webhook request wasn't delivered.
+#[derive(Debug)]
+pub enum RequestError {
+    Render(RenderError),
+    /// The endpoint's host isn't in `allowed_webhook_hosts`.
+    BlockedHost(http::Uri),
+    /// The request missed its publish deadline and was dropped.
+    Shed,
+    /// The request couldn't be sent or didn't get a response, after any retries.
+    Request(reqwest::Error),
+    /// The endpoint responded with a status other than the expected ones, after any retries.
+    /// `body` is the start of the response body.
+    Status {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    /// The task sending the request panicked or was cancelled.
+    Task(task::JoinError),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Render(e) => write!(f, "failed to render webhook request: {}", e),
+            RequestError::BlockedHost(endpoint) => {
+                write!(f, "blocked webhook to disallowed host: {}", endpoint)
+            }
+            RequestError::Shed => write!(f, "dropped webhook that missed its publish deadline"),
+            RequestError::Request(e) => write!(f, "webhook POST error: {}", e),
+            RequestError::Status { status, body } => {
+                write!(f, "webhook POST got status {}: {:?}", status, body)
+            }
+            RequestError::Task(e) => write!(f, "webhook task failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl RequestError {
+    /// Whether sending the request again later may succeed: it failed in transit or got a 5xx
+    /// or 429. Shed requests aren't, since they're dropped on purpose.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            RequestError::Request(_) => true,
+            RequestError::Status { status, .. } => retryable_status(*status),
+            _ => false,
+        }
+    }
+}
+
+/// Returned by `publish` when any of a match's requests weren't delivered. Requests are sent
+/// independently, so the rest may have been.
+#[derive(Debug)]
+pub struct PublishError {
+    pub failures: Vec<RequestError>,
+    /// The number of requests the match was published as.
+    pub total: usize,
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} webhook requests failed",
+            self.failures.len(),
+            self.total
+        )?;
+        for failure in &self.failures {
+            write!(f, "; {}", failure)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PublishError {}
+
+impl PublishError {
+    /// Whether publishing the match again later may succeed.
+    pub fn is_retryable(&self) -> bool {
+        self.failures.iter().any(RequestError::is_retryable)
+    }
+}
+
+/// Each published request goes to the next endpoint in turn.
 struct EndpointConfig {
-    endpoint: http::Uri,
+    endpoints: Vec<http::Uri>,
+}
+
+/// A request that `publish` would have sent, as returned by `dry_publish`.
+#[derive(Debug, PartialEq)]
+pub struct DryRunRequest {
+    pub url: String,
+    pub method: String,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+/// HMAC-SHA256 request signing. The signed payload is built from `payload_template`, where
+/// `${timestamp}` is the unix time in seconds and `${body}` is the rendered request body, e.g.
+/// `${timestamp}.${body}` for Stripe-style signatures.
+#[derive(Clone)]
+pub struct SigningConfig {
+    pub secret: String,
+    pub signature_header: HeaderName,
+    pub timestamp_header: Option<HeaderName>,
+    pub payload_template: String,
+}
+
+impl SigningConfig {
+    fn sign(&self, body: &[u8], timestamp: u64) -> String {
+        let payload = self
+            .payload_template
+            .replace("${timestamp}", &timestamp.to_string());
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC can take a key of any size");
+        // The body may be binary, so it's spliced into the payload as bytes.
+        for (idx, part) in payload.split("${body}").enumerate() {
+            if idx > 0 {
+                mac.update(body);
+            }
+            mac.update(part.as_bytes());
+        }
+
+        hex::encode(mac.finalize().into_bytes())
+    }
 }
 
 impl WebhookPublisher {
@@ -18,67 +422,1417 @@ impl WebhookPublisher {
         endpoint: http::Uri,
         template: String,
         headers: HashMap<&'static str, String>,
+        options: PublisherOptions,
     ) -> Self {
+        let allowed_hosts = options.allowed_hosts.map(Arc::new);
+        let mut builder = options
+            .resolve
+            .iter()
+            .fold(reqwest::Client::builder(), |builder, (host, addr)| {
+                builder.resolve(host, *addr)
+            });
+        if let Some(allowed) = allowed_hosts.clone() {
+            builder = builder.redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                if host_allowed(&allowed, attempt.url().host_str()) {
+                    attempt.follow()
+                } else {
+                    tracing::warn!(url = %attempt.url(), "blocked webhook redirect to disallowed host");
+                    attempt.stop()
+                }
+            }));
+        }
+        let client = builder.build().expect("failed to build HTTP client");
+
+        // The instance never changes, so it's filled in once rather than on every publish.
+        let (template, headers, appended_headers) = match &options.instance {
+            Some(instance) => (
+                template.replace("${instance}", instance),
+                headers
+                    .into_iter()
+                    .map(|(k, v)| (k, v.replace("${instance}", instance)))
+                    .collect(),
+                options
+                    .appended_headers
+                    .into_iter()
+                    .map(|(k, v)| (k, v.replace("${instance}", instance)))
+                    .collect(),
+            ),
+            None => (template, headers, options.appended_headers),
+        };
+
         WebhookPublisher {
-            client: Arc::new(reqwest::Client::new()),
-            config: Arc::new(EndpointConfig { endpoint }),
+            client: Arc::new(client),
+            config: Arc::new(EndpointConfig {
+                endpoints: vec![endpoint],
+            }),
+            next_endpoint: Arc::new(AtomicUsize::new(0)),
             template,
             headers,
+            appended_headers,
+            capture_headers: options.capture_headers,
+            json_types: options.json_types,
+            multipart_parts: options.multipart_parts,
+            expected_status: options.expected_status.map(Arc::from),
+            signing: options.signing,
+            limiter: options
+                .concurrency
+                .map(|c| Arc::new(ConcurrencyLimiter::new(c))),
+            body_encoding: options.body_encoding,
+            on_missing_group: options.on_missing_group,
+            publish_deadline: options.publish_deadline,
+            retry: options.retry.map(Arc::new),
+            timeout: options.timeout,
+            retry_budget: options.retry_budget,
+            rate_monitor: options
+                .warn_rate_threshold
+                .map(|threshold| Arc::new(RateMonitor::new(threshold))),
+            allowed_hosts,
+            multi_match: options.multi_match,
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    /// Replaces the HTTP client, e.g. to set timeouts or proxies or to send through a test
+    /// transport. All publishing, retries, and keepalive pings go through this client.
+    pub fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = Arc::new(client);
+        self
+    }
+
+    /// Counts deliveries in `counters` instead of the publisher's own.
+    pub fn with_counters(mut self, counters: Arc<Counters>) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    /// A publisher that shares this one's endpoints, concurrency limit, retry budget, and counters
+    /// but retries and times out requests as `delivery` says, for rules with their own settings.
+    pub fn with_delivery(&self, delivery: DeliveryConfig) -> Self {
+        WebhookPublisher {
+            retry: delivery.retry.map(Arc::new),
+            timeout: delivery.timeout,
+            ..self.clone()
+        }
+    }
+
+    /// Replaces the body template. Unlike the one passed to `new`, `${instance}` isn't filled in.
+    pub fn with_template(mut self, template: String) -> Self {
+        self.template = template;
+        self
+    }
+
+    /// Sends requests to each of `endpoints` in rotation instead of the single endpoint passed to
+    /// `new`. Does nothing if `endpoints` is empty.
+    pub fn with_round_robin(mut self, endpoints: Vec<http::Uri>) -> Self {
+        if !endpoints.is_empty() {
+            self.config = Arc::new(EndpointConfig { endpoints });
+        }
+        self
+    }
+
+    /// Sends a request for each of `matched_groups` and waits for them all, returning an error
+    /// if any weren't delivered.
+    pub async fn publish(
+        &self,
+        channel: &str,
+        matched_groups: Vec<Vec<String>>,
+    ) -> Result<(), PublishError> {
+        self.publish_with_vars(channel, matched_groups, &[], Instant::now())
+            .await
+    }
+
+    /// Like `publish`, also substituting each `(name, value)` in `vars` for `${name}` in the body
+    /// and header templates. Delivery latency and the publish deadline count from `matched_at`.
+    pub async fn publish_with_vars(
+        &self,
+        channel: &str,
+        matched_groups: Vec<Vec<String>>,
+        vars: &[(&str, String)],
+        matched_at: Instant,
+    ) -> Result<(), PublishError> {
+        let mut failures = Vec::new();
+        let mut tasks = Vec::new();
+        for rendered in self.render_all(channel, &matched_groups, vars) {
+            match rendered {
+                Ok((body, headers)) => tasks.push(self.spawn_send(body, headers, matched_at)),
+                Err(e) => failures.push(RequestError::Render(e)),
+            }
+        }
+        let total = failures.len() + tasks.len();
+
+        for result in futures::future::join_all(tasks).await {
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => failures.push(e),
+                Err(e) => failures.push(RequestError::Task(e)),
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(PublishError { failures, total })
+        }
+    }
+
+    /// The number of matches dropped for missing their publish deadline.
+    pub fn shed_count(&self) -> u64 {
+        self.counters.shed.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests that got a 2xx response.
+    pub fn published_count(&self) -> u64 {
+        self.counters.published.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests that failed or got a non-2xx response after any retries.
+    pub fn failed_count(&self) -> u64 {
+        self.counters.failed.load(Ordering::Relaxed)
+    }
+
+    /// The delivery latency at percentile `p` of recent successful publishes, measured from when
+    /// the match was published to when its webhook got a 2xx response.
+    pub fn delivery_latency(&self, p: f64) -> Option<Duration> {
+        self.counters.delivery.percentile(p)
+    }
+
+    /// Sends `body` to `url` with the configured headers, using the same client as `publish`.
+    /// Used to check that an endpoint is reachable and to send the shutdown report.
+    pub async fn send_test(
+        &self,
+        url: &http::Uri,
+        body: String,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        self.client
+            .post(url.to_string())
+            .headers(to_headers(
+                &self.headers,
+                &self.appended_headers,
+                "",
+                &[],
+                &[],
+            ))
+            .body(body)
+            .send()
+            .await
+    }
+
+    /// Posts an empty body to a dead man's switch at `url`, using the same client as `publish`
+    /// but none of the configured headers, so webhook credentials aren't sent to the monitor.
+    pub fn spawn_heartbeat(&self, url: &http::Uri) -> task::JoinHandle<()> {
+        let request = self.client.post(url.to_string());
+
+        task::spawn(async move {
+            match request.send().await {
+                Ok(r) if r.status().is_success() => {
+                    tracing::debug!(status = %r.status(), "heartbeat sent")
+                }
+                Ok(r) => tracing::warn!(status = %r.status(), "heartbeat was rejected"),
+                Err(e) => tracing::warn!("heartbeat error: {}", e),
+            }
+        })
+    }
+
+    /// Sends a HEAD request to `path` on each webhook host every `interval` so the pooled
+    /// connections aren't closed for being idle between bursts of matches.
+    pub fn spawn_keepalive(
+        &self,
+        path: &str,
+        interval: Duration,
+    ) -> Result<task::JoinHandle<()>, http::Error> {
+        let mut urls = self
+            .config
+            .endpoints
+            .iter()
+            .map(|endpoint| keepalive_url(endpoint, path))
+            .collect::<Result<Vec<_>, _>>()?;
+        urls.dedup();
+        let client = self.client.clone();
+
+        Ok(task::spawn(async move {
+            let mut ticks =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                ticks.tick().await;
+                for url in &urls {
+                    match client.head(url.to_string()).send().await {
+                        Ok(r) => tracing::debug!(status = %r.status(), "keepalive ping"),
+                        Err(e) => tracing::warn!("keepalive ping error: {}", e),
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Renders the requests for the matched groups without sending anything.
+    pub fn dry_publish(
+        &self,
+        channel: &str,
+        matched_groups: Vec<Vec<String>>,
+    ) -> Result<Vec<DryRunRequest>, RenderError> {
+        let next = self.next_endpoint.load(Ordering::Relaxed);
+
+        self.render_all(channel, &matched_groups, &[])
+            .into_iter()
+            .enumerate()
+            .map(|(idx, rendered)| {
+                let (body, headers) = rendered?;
+
+                Ok(DryRunRequest {
+                    url: self.endpoint(next + idx).to_string(),
+                    method: http::Method::POST.to_string(),
+                    headers: headers
+                        .iter()
+                        .map(|(k, v)| {
+                            (
+                                k.to_string(),
+                                String::from_utf8_lossy(v.as_bytes()).to_string(),
+                            )
+                        })
+                        .collect(),
+                    body: String::from_utf8_lossy(&body).to_string(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn publish_group(
+        &self,
+        channel: &str,
+        group: Vec<String>,
+        vars: &[(&str, String)],
+    ) -> Result<task::JoinHandle<Result<(), RequestError>>, RenderError> {
+        let (body, headers) = self.render(channel, &group, vars)?;
+        Ok(self.spawn_send(body, headers, Instant::now()))
+    }
+
+    /// Renders the requests for `matched_groups` according to the multi-match mode.
+    fn render_all(
+        &self,
+        channel: &str,
+        matched_groups: &[Vec<String>],
+        vars: &[(&str, String)],
+    ) -> Vec<Result<(Vec<u8>, HeaderMap), RenderError>> {
+        match self.multi_match {
+            MultiMatchMode::PerMatch => matched_groups
+                .iter()
+                .map(|group| self.render(channel, group, vars))
+                .collect(),
+            MultiMatchMode::SingleArray if matched_groups.is_empty() => Vec::new(),
+            MultiMatchMode::SingleArray => vec![self.render_array(channel, matched_groups, vars)],
+            MultiMatchMode::FirstOnly => matched_groups
+                .iter()
+                .take(1)
+                .map(|group| self.render(channel, group, vars))
+                .collect(),
         }
     }
 
-    pub async fn publish(&self, matched_groups: Vec<Vec<String>>) {
-        let tasks = matched_groups
+    /// Renders a single request for all of `groups` whose body is a JSON array with an element
+    /// per group: the group itself with the `json` body encoding and the rendered template
+    /// otherwise.
+    fn render_array(
+        &self,
+        channel: &str,
+        groups: &[Vec<String>],
+        vars: &[(&str, String)],
+    ) -> Result<(Vec<u8>, HeaderMap), RenderError> {
+        let elements = groups
             .iter()
-            .map(|g| self.publish_group(g.to_vec()))
-            .collect::<Vec<task::JoinHandle<()>>>();
+            .map(|group| match self.body_encoding {
+                BodyEncoding::Json => Ok(json_group(group, &self.json_types).to_string()),
+                _ => self.render_template(&self.template, channel, group, vars),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let body = format!("[{}]", elements.join(",")).into_bytes();
+
+        let first = groups.first().map(Vec::as_slice).unwrap_or_default();
+        let mut headers = to_headers(&self.headers, &self.appended_headers, channel, first, vars);
+        headers
+            .entry(CONTENT_TYPE)
+            .or_insert(HeaderValue::from_static("application/json"));
+        self.sign(&body, &mut headers);
 
-        let result = futures::future::join_all(tasks).await;
-        println!("{:?}", result); // TODO: Error propagation.
+        Ok((body, headers))
     }
 
-    pub fn publish_group(&self, group: Vec<String>) -> task::JoinHandle<()> {
-        let body = templ_replace(&self.template, &group);
-        let headers = to_headers(&self.headers, &group);
+    fn spawn_send(
+        &self,
+        body: Vec<u8>,
+        headers: HeaderMap,
+        matched_at: Instant,
+    ) -> task::JoinHandle<Result<(), RequestError>> {
+        let deadline = self.publish_deadline.map(|d| matched_at + d);
 
         let client = self.client.clone();
-        let config = self.config.clone();
+        let endpoint = self
+            .endpoint(self.next_endpoint.fetch_add(1, Ordering::Relaxed))
+            .clone();
+        let limiter = self.limiter.clone();
+        let counters = self.counters.clone();
+        let retry = self.retry.clone();
+        let timeout = self.timeout;
+        let retry_budget = self.retry_budget.clone();
+        let rate_monitor = self.rate_monitor.clone();
+        let expected_status = self.expected_status.clone();
+        let blocked = self
+            .allowed_hosts
+            .as_ref()
+            .is_some_and(|allowed| !host_allowed(allowed, endpoint.host()));
 
-        let join = task::spawn({
+        task::spawn({
             async move {
-                let res = client
-                    .post(config.endpoint.to_string())
-                    .body(body)
-                    .headers(headers)
-                    .send()
-                    .await;
+                if blocked {
+                    counters.failed.fetch_add(1, Ordering::Relaxed);
+                    return Err(RequestError::BlockedHost(endpoint));
+                }
+                let in_time = match (&limiter, deadline) {
+                    (Some(limiter), Some(deadline)) => {
+                        tokio::time::timeout_at(deadline.into(), limiter.acquire())
+                            .await
+                            .is_ok()
+                    }
+                    (Some(limiter), None) => {
+                        limiter.acquire().await;
+                        true
+                    }
+                    (None, Some(deadline)) => Instant::now() < deadline,
+                    (None, None) => true,
+                };
+                if !in_time {
+                    counters.shed.fetch_add(1, Ordering::Relaxed);
+                    return Err(RequestError::Shed);
+                }
+                let started = Instant::now();
+                if let Some(rate_monitor) = &rate_monitor {
+                    rate_monitor.record_request(&endpoint);
+                }
+
+                let res = send_with_retry(
+                    &client,
+                    &endpoint,
+                    body,
+                    headers,
+                    retry.as_deref(),
+                    timeout,
+                    retry_budget.as_deref(),
+                )
+                .await;
+
+                let success = matches!(&res, Ok(r) if status_expected(expected_status.as_deref(), r.status()));
+                let latency = matched_at.elapsed();
+                if success {
+                    counters.published.fetch_add(1, Ordering::Relaxed);
+                    counters.delivery.record(latency);
+                } else {
+                    counters.failed.fetch_add(1, Ordering::Relaxed);
+                }
+                if let Some(limiter) = &limiter {
+                    limiter.release(started.elapsed(), success);
+                }
 
                 match res {
-                    Ok(r) => tracing::info!(post_response = ?r),
-                    Err(e) => tracing::error!("webhook POST error: {}", e),
+                    Ok(r) if success => {
+                        tracing::info!(post_response = ?r, latency_ms = latency.as_millis());
+                        Ok(())
+                    }
+                    Ok(r) => {
+                        let status = r.status();
+                        let body = r.text().await.unwrap_or_default();
+                        Err(RequestError::Status {
+                            status,
+                            body: snippet(&body, ERROR_BODY_SNIPPET_LEN),
+                        })
+                    }
+                    Err(e) => Err(RequestError::Request(e)),
                 }
             }
-        });
+        })
+    }
 
-        return join;
+    fn endpoint(&self, count: usize) -> &http::Uri {
+        &self.config.endpoints[count % self.config.endpoints.len()]
+    }
+
+    fn render(
+        &self,
+        channel: &str,
+        group: &[String],
+        vars: &[(&str, String)],
+    ) -> Result<(Vec<u8>, HeaderMap), RenderError> {
+        let mut headers = to_headers(&self.headers, &self.appended_headers, channel, group, vars);
+        let mut content_type = |value| {
+            headers
+                .entry(CONTENT_TYPE)
+                .or_insert(HeaderValue::from_static(value));
+        };
+
+        let body = match self.body_encoding {
+            BodyEncoding::Raw => self
+                .render_template(&self.template, channel, group, vars)?
+                .into_bytes(),
+            BodyEncoding::Base64 => {
+                let rendered = self.render_template(&self.template, channel, group, vars)?;
+                let body = BASE64
+                    .decode(rendered.trim())
+                    .map_err(RenderError::InvalidBase64)?;
+                content_type("application/octet-stream");
+                body
+            }
+            BodyEncoding::Form => {
+                content_type("application/x-www-form-urlencoded");
+                form_urlencoded::Serializer::new(String::new())
+                    .extend_pairs(
+                        group
+                            .iter()
+                            .enumerate()
+                            .map(|(idx, value)| (format!("group_{}", idx), value)),
+                    )
+                    .finish()
+                    .into_bytes()
+            }
+            BodyEncoding::Json => {
+                content_type("application/json");
+                json_group(group, &self.json_types).to_string().into_bytes()
+            }
+            BodyEncoding::Multipart => {
+                let parts = self
+                    .multipart_parts
+                    .iter()
+                    .map(|(name, templ)| {
+                        Ok((
+                            name.as_str(),
+                            self.render_template(templ, channel, group, vars)?,
+                        ))
+                    })
+                    .collect::<Result<Vec<_>, RenderError>>()?;
+                let boundary = format!(
+                    "irc-hook-{:016x}{:016x}",
+                    rand::random::<u64>(),
+                    rand::random::<u64>()
+                );
+                // Always set, since a configured Content-Type wouldn't have this boundary.
+                headers.insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_str(&format!("multipart/form-data; boundary={}", boundary))
+                        .unwrap(),
+                );
+                multipart_body(&parts, &boundary)
+            }
+        };
+        if let Some(capture_headers) = &self.capture_headers {
+            capture_headers.add(group, &mut headers);
+        }
+
+        self.sign(&body, &mut headers);
+
+        Ok((body, headers))
+    }
+
+    fn sign(&self, body: &[u8], headers: &mut HeaderMap) {
+        if let Some(signing) = &self.signing {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("system clock is before the unix epoch")
+                .as_secs();
+
+            headers.insert(
+                signing.signature_header.clone(),
+                signing.sign(body, timestamp).parse().unwrap(),
+            );
+            if let Some(timestamp_header) = &signing.timestamp_header {
+                headers.insert(timestamp_header.clone(), timestamp.into());
+            }
+        }
+    }
+
+    fn render_template(
+        &self,
+        templ: &str,
+        channel: &str,
+        group: &[String],
+        vars: &[(&str, String)],
+    ) -> Result<String, RenderError> {
+        if self.on_missing_group != MissingGroup::Leave {
+            let unresolved = unresolved_placeholders(templ, channel, group, vars);
+            if !unresolved.is_empty() {
+                tracing::warn!(?unresolved, "template has unresolved placeholders");
+                if self.on_missing_group == MissingGroup::Error {
+                    return Err(RenderError::UnresolvedPlaceholders(unresolved));
+                }
+                return Ok(fill(templ, channel, group, vars, true));
+            }
+        }
+
+        Ok(render(templ, channel, group, vars))
     }
 }
 
-fn templ_replace(templ: &str, group: &[String]) -> String {
-    group
-        .iter()
-        .enumerate()
-        .fold(templ.to_string().clone(), |body, (idx, repl)| {
-            let repl_idx = format!("${{{}}}", idx);
-            body.replace(&repl_idx, repl)
+/// Sends the request, retrying transport errors and 5xx responses as configured by `retry` for as
+/// long as `budget` has retries to spare.
+async fn send_with_retry(
+    client: &reqwest::Client,
+    endpoint: &http::Uri,
+    body: Vec<u8>,
+    headers: HeaderMap,
+    retry: Option<&RetryConfig>,
+    timeout: Option<Duration>,
+    budget: Option<&RetryBudget>,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let mut attempt = 0;
+    let mut backoff = retry.map(RetryConfig::backoff);
+
+    loop {
+        let mut request = client
+            .post(endpoint.to_string())
+            .body(body.clone())
+            .headers(headers.clone());
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        let res = request.send().await;
+
+        let retryable = match &res {
+            Ok(r) => retryable_status(r.status()),
+            Err(_) => true,
+        };
+
+        match (retry, &mut backoff) {
+            (Some(retry), Some(backoff)) if retryable && attempt < retry.max_attempts => {
+                if budget.is_some_and(|budget| !budget.try_acquire()) {
+                    tracing::warn!(attempt, "retry budget exhausted, giving up on webhook POST");
+                    return res;
+                }
+                attempt += 1;
+                let delay = backoff.next_delay();
+                match &res {
+                    Ok(r) => {
+                        tracing::debug!(attempt, ?delay, status = %r.status(), "retrying webhook POST")
+                    }
+                    Err(e) => tracing::debug!(attempt, ?delay, error = %e, "retrying webhook POST"),
+                }
+                tokio::time::sleep(delay).await;
+            }
+            _ => return res,
+        }
+    }
+}
+
+#[async_trait]
+impl Publisher for WebhookPublisher {
+    async fn publish(
+        &self,
+        channel: &str,
+        groups: Vec<Vec<String>>,
+        vars: &[(&str, String)],
+        matched_at: Instant,
+    ) -> anyhow::Result<()> {
+        Ok(self
+            .publish_with_vars(channel, groups, vars, matched_at)
+            .await?)
+    }
+}
+
+/// A `multipart/form-data` body of text parts. Part names are checked when the config is loaded,
+/// and the boundary is random, so neither needs escaping.
+fn multipart_body(parts: &[(&str, String)], boundary: &str) -> Vec<u8> {
+    let mut body = String::new();
+    for (name, value) in parts {
+        body.push_str(&format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+            boundary, name, value
+        ));
+    }
+    body.push_str(&format!("--{}--\r\n", boundary));
+    body.into_bytes()
+}
+
+/// Server errors and rate limiting are worth retrying; other statuses will get the same response.
+fn retryable_status(status: http::StatusCode) -> bool {
+    status.is_server_error() || status == http::StatusCode::TOO_MANY_REQUESTS
+}
+
+/// How much of an error response's body is kept for the error.
+const ERROR_BODY_SNIPPET_LEN: usize = 256;
+
+/// Whether `status` counts as delivered: one of `expected` if given, otherwise any 2xx.
+fn status_expected(expected: Option<&[http::StatusCode]>, status: http::StatusCode) -> bool {
+    match expected {
+        Some(expected) => expected.contains(&status),
+        None => status.is_success(),
+    }
+}
+
+/// The first `max` bytes of `s`, cut back to a character boundary, with `...` if any was cut.
+fn snippet(s: &str, max: usize) -> String {
+    if s.len() <= max {
+        return s.to_string();
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &s[..end])
+}
+
+/// Returns true if `host` is one of `allowed`, ignoring case.
+pub fn host_allowed(allowed: &[String], host: Option<&str>) -> bool {
+    host.is_some_and(|host| allowed.iter().any(|a| a.eq_ignore_ascii_case(host)))
+}
+
+/// Parses a static host mapping in curl's `--resolve` format, `<host>:<port>:<address>`, e.g.
+/// `hooks.example.com:443:10.0.0.5`. Note that reqwest uses the address for every port on the host.
+pub fn parse_resolve(entry: &str) -> Result<(String, SocketAddr), String> {
+    let invalid = || format!("resolve entry must be <host>:<port>:<address>: {}", entry);
+
+    let mut parts = entry.splitn(3, ':');
+    let (Some(host), Some(port), Some(addr)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    let port: u16 = port.parse().map_err(|_| invalid())?;
+    let addr: IpAddr = addr
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .map_err(|_| invalid())?;
+    if host.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok((host.to_string(), SocketAddr::new(addr, port)))
+}
+
+/// The URL for keepalive pings: `path` on the same scheme and host as the webhook endpoint.
+fn keepalive_url(endpoint: &http::Uri, path: &str) -> Result<http::Uri, http::Error> {
+    let mut url = http::Uri::builder().path_and_query(path);
+    if let Some(scheme) = endpoint.scheme() {
+        url = url.scheme(scheme.clone());
+    }
+    if let Some(authority) = endpoint.authority() {
+        url = url.authority(authority.clone());
+    }
+    url.build()
+}
+
+/// Renders a body or header template the way the publisher does, without constructing one:
+/// `${channel}` becomes `channel`, `${N}` the Nth capture group in `group` (`${0}` being the whole
+/// match), and `${name}` the value paired with `name` in `vars`. A `channel` in `vars`, such as a
+/// named group called `channel`, takes precedence over the channel. Placeholders with nothing to
+/// fill them are left as they are. The template is filled in one pass, so placeholders in the
+/// inserted values are never expanded.
+///
+/// ```
+/// use irc_hook::webhook_publisher::render;
+///
+/// let group = vec!["deploy api".to_string(), "api".to_string()];
+/// assert_eq!(
+///     render("${channel}: deployed ${1} (${0})", "#ops", &group, &[]),
+///     "#ops: deployed api (deploy api)"
+/// );
+///
+/// let vars = [("seq", "7".to_string()), ("env", "prod".to_string())];
+/// assert_eq!(
+///     render("#${seq} ${1} to ${env}, ${2}", "#ops", &group, &vars),
+///     "#7 api to prod, ${2}"
+/// );
+/// ```
+pub fn render(templ: &str, channel: &str, group: &[String], vars: &[(&str, String)]) -> String {
+    fill(templ, channel, group, vars, false)
+}
+
+/// Renders a body or header template, replacing `${channel}` and positional `${N}` placeholders
+/// with the channel name and the capture groups of a match. Placeholders are replaced in one pass,
+/// so `${1}` never touches `${10}` and captures containing placeholders are left as they are.
+/// Indexes past the last group are left untouched.
+pub fn templ_replace(templ: &str, channel: &str, group: &[String]) -> String {
+    fill(templ, channel, group, &[], false)
+}
+
+/// Matches `${...}` placeholders whose names are made up of letters, digits, underscores, and the
+/// `:` and `.` of `${json:path.to.field}`.
+fn placeholder() -> &'static regex::Regex {
+    static PLACEHOLDER: OnceLock<regex::Regex> = OnceLock::new();
+    PLACEHOLDER.get_or_init(|| regex::Regex::new(r"\$\{([A-Za-z0-9_:.]+)\}").unwrap())
+}
+
+/// The value of the placeholder named `name`, if there's one to fill it. Variables come before
+/// the channel, so a named group called `channel` can be referenced.
+fn resolve<'a>(
+    name: &str,
+    channel: &'a str,
+    group: &'a [String],
+    vars: &'a [(&str, String)],
+) -> Option<&'a str> {
+    name.parse::<usize>()
+        .ok()
+        .and_then(|idx| group.get(idx))
+        .or_else(|| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value)
         })
+        .map(String::as_str)
+        .or_else(|| (name == "channel").then_some(channel))
 }
 
-fn to_headers(headers: &HashMap<&'static str, String>, group: &[String]) -> HeaderMap {
-    headers
-        .iter()
-        .fold(http::HeaderMap::new(), |mut accum, (&k, v)| {
-            accum.insert(k, templ_replace(v, group).parse().unwrap());
-            accum
+/// Fills every placeholder in `templ` in a single pass. Unresolved ones are left as they are, or
+/// removed when `empty_unresolved` is set.
+fn fill(
+    templ: &str,
+    channel: &str,
+    group: &[String],
+    vars: &[(&str, String)],
+    empty_unresolved: bool,
+) -> String {
+    placeholder()
+        .replace_all(templ, |caps: &regex::Captures| {
+            match resolve(&caps[1], channel, group, vars) {
+                Some(value) => value.to_string(),
+                None if empty_unresolved => String::new(),
+                None => caps[0].to_string(),
+            }
         })
+        .into_owned()
+}
+
+/// Returns the placeholders in `templ` that nothing in `group` or `vars` fills, in order of first
+/// appearance.
+fn unresolved_placeholders(
+    templ: &str,
+    channel: &str,
+    group: &[String],
+    vars: &[(&str, String)],
+) -> Vec<String> {
+    let mut placeholders: Vec<String> = Vec::new();
+    for caps in placeholder().captures_iter(templ) {
+        if resolve(&caps[1], channel, group, vars).is_none()
+            && !placeholders.iter().any(|p| p == &caps[0])
+        {
+            placeholders.push(caps[0].to_string());
+        }
+    }
+
+    placeholders
+}
+
+/// Renders the configured headers. Captures come from whoever sent the message, so a header
+/// whose rendered value isn't a valid header value (e.g. it contains a newline) is left out with a
+/// warning rather than sent or allowed to fail the request. `appended` headers are added as
+/// separate lines after `headers`, so they never replace one.
+fn to_headers(
+    headers: &HashMap<&'static str, String>,
+    appended: &[(&'static str, String)],
+    channel: &str,
+    group: &[String],
+    vars: &[(&str, String)],
+) -> HeaderMap {
+    let render_value = |k: &str, v: &str| {
+        let value = render(v, channel, group, vars);
+        HeaderValue::from_str(&value)
+            .map_err(|_| tracing::warn!(header = k, value, "skipping invalid header value"))
+            .ok()
+    };
+
+    let mut accum = http::HeaderMap::new();
+    for (&k, v) in headers {
+        if let Some(value) = render_value(k, v) {
+            accum.insert(k, value);
+        }
+    }
+    for &(k, ref v) in appended {
+        if let Some(value) = render_value(k, v) {
+            accum.append(k, value);
+        }
+    }
+    accum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signing_config(payload_template: &str) -> SigningConfig {
+        SigningConfig {
+            secret: "secret".to_string(),
+            signature_header: HeaderName::from_static("x-signature"),
+            timestamp_header: Some(HeaderName::from_static("x-timestamp")),
+            payload_template: payload_template.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_templ_replace_channel() {
+        let group = vec!["full match".to_string(), "capture".to_string()];
+
+        assert_eq!(
+            templ_replace("${channel}: ${1} (${0})", "#rust", &group),
+            "#rust: capture (full match)"
+        );
+
+        // A named group called channel is used instead of the channel.
+        let vars = [("channel", "prod".to_string())];
+        assert_eq!(
+            render("${channel}: ${1}", "#rust", &group, &vars),
+            "prod: capture"
+        );
+    }
+
+    #[test]
+    fn test_templ_replace_two_digit_groups() {
+        let group: Vec<String> = (0..=12).map(|i| format!("g{}", i)).collect();
+        let templ: String = (0..=13).map(|i| format!("${{{}}} ", i)).collect();
+
+        assert_eq!(
+            templ_replace(&templ, "#rust", &group),
+            "g0 g1 g2 g3 g4 g5 g6 g7 g8 g9 g10 g11 g12 ${13} "
+        );
+
+        // A capture that looks like a placeholder is sent as it is.
+        let group = vec!["${1}".to_string(), "${channel}".to_string()];
+        assert_eq!(
+            templ_replace("${0} ${1}", "#rust", &group),
+            "${1} ${channel}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_client() {
+        use httptest::{all_of, matchers::*, responders::*, Expectation, Server};
+
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("POST", "/hook"),
+                request::headers(contains(("x-client", "custom"))),
+            ])
+            .respond_with(status_code(200)),
+        );
+
+        let client = reqwest::Client::builder()
+            .default_headers(HeaderMap::from_iter([(
+                HeaderName::from_static("x-client"),
+                HeaderValue::from_static("custom"),
+            )]))
+            .build()
+            .unwrap();
+        let publisher = WebhookPublisher::new(
+            server.url("/hook"),
+            "${0}".to_string(),
+            HashMap::new(),
+            PublisherOptions::default(),
+        )
+        .with_client(client);
+
+        publisher
+            .publish("#rust", vec![vec!["a".to_string()]])
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_status_expected() {
+        let accepted = [http::StatusCode::ACCEPTED];
+
+        assert!(status_expected(None, http::StatusCode::OK));
+        assert!(status_expected(None, http::StatusCode::NO_CONTENT));
+        assert!(!status_expected(None, http::StatusCode::NOT_FOUND));
+        assert!(status_expected(Some(&accepted), http::StatusCode::ACCEPTED));
+        assert!(!status_expected(Some(&accepted), http::StatusCode::OK));
+
+        assert!(retryable_status(http::StatusCode::SERVICE_UNAVAILABLE));
+        assert!(retryable_status(http::StatusCode::TOO_MANY_REQUESTS));
+        assert!(!retryable_status(http::StatusCode::NOT_FOUND));
+
+        assert_eq!(snippet("not found", 20), "not found");
+        assert_eq!(snippet("ééé", 3), "é...");
+    }
+
+    #[test]
+    fn test_host_allowed() {
+        let allowed = vec!["hooks.example.com".to_string()];
+
+        assert!(host_allowed(&allowed, Some("HOOKS.example.com")));
+        assert!(!host_allowed(&allowed, Some("169.254.169.254")));
+        assert!(!host_allowed(&allowed, None));
+    }
+
+    #[test]
+    fn test_parse_resolve() {
+        assert_eq!(
+            parse_resolve("hooks.example.com:443:10.0.0.5"),
+            Ok((
+                "hooks.example.com".to_string(),
+                "10.0.0.5:443".parse().unwrap()
+            ))
+        );
+        assert_eq!(
+            parse_resolve("hooks.example.com:8443:[::1]"),
+            Ok((
+                "hooks.example.com".to_string(),
+                "[::1]:8443".parse().unwrap()
+            ))
+        );
+        assert!(parse_resolve("hooks.example.com:10.0.0.5").is_err());
+        assert!(parse_resolve(":443:10.0.0.5").is_err());
+    }
+
+    #[test]
+    fn test_keepalive_url() {
+        let endpoint = "https://hooks.example.com:8443/hook?token=abc"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            keepalive_url(&endpoint, "/health").unwrap(),
+            "https://hooks.example.com:8443/health"
+        );
+    }
+
+    #[test]
+    fn test_dry_publish() {
+        let publisher = WebhookPublisher::new(
+            "http://example.com/hook".parse().unwrap(),
+            "${channel}: ${1}".to_string(),
+            HashMap::from([("x-capture", "${0}".to_string())]),
+            PublisherOptions::default(),
+        );
+
+        let got = publisher
+            .dry_publish("#rust", vec![vec!["1a2".to_string(), "a".to_string()]])
+            .unwrap();
+
+        assert_eq!(
+            got,
+            vec![DryRunRequest {
+                url: "http://example.com/hook".to_string(),
+                method: "POST".to_string(),
+                headers: HashMap::from([("x-capture".to_string(), "1a2".to_string())]),
+                body: "#rust: a".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let publisher = WebhookPublisher::new(
+            "http://example.com/unused".parse().unwrap(),
+            "${0}".to_string(),
+            HashMap::new(),
+            PublisherOptions::default(),
+        )
+        .with_round_robin(vec![
+            "http://one.example.com/hook".parse().unwrap(),
+            "http://two.example.com/hook".parse().unwrap(),
+        ]);
+
+        let got = publisher
+            .dry_publish(
+                "#rust",
+                vec![
+                    vec!["a".to_string()],
+                    vec!["b".to_string()],
+                    vec!["c".to_string()],
+                ],
+            )
+            .unwrap();
+
+        assert_eq!(
+            got.iter().map(|r| r.url.as_str()).collect::<Vec<_>>(),
+            vec![
+                "http://one.example.com/hook",
+                "http://two.example.com/hook",
+                "http://one.example.com/hook"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_instance() {
+        let publisher = WebhookPublisher::new(
+            "http://example.com/hook".parse().unwrap(),
+            "${instance}: ${0}".to_string(),
+            HashMap::from([("x-instance", "${instance}".to_string())]),
+            PublisherOptions {
+                instance: Some("eu-1".to_string()),
+                ..PublisherOptions::default()
+            },
+        );
+
+        let (body, headers) = publisher.render("#rust", &["a".to_string()], &[]).unwrap();
+        assert_eq!(body, b"eu-1: a");
+        assert_eq!(headers["x-instance"], "eu-1");
+    }
+
+    #[test]
+    fn test_vars() {
+        let publisher = WebhookPublisher::new(
+            "http://example.com/hook".parse().unwrap(),
+            "${seq}: ${0}".to_string(),
+            HashMap::from([("x-seq", "${seq}".to_string())]),
+            PublisherOptions::default(),
+        );
+
+        let (body, headers) = publisher
+            .render("#rust", &["a".to_string()], &[("seq", "7".to_string())])
+            .unwrap();
+        assert_eq!(body, b"7: a");
+        assert_eq!(headers["x-seq"], "7");
+
+        // Variables in a capture are sent as they are.
+        let (body, _) = publisher
+            .render(
+                "#rust",
+                &["${seq} ${channel}".to_string()],
+                &[("seq", "7".to_string())],
+            )
+            .unwrap();
+        assert_eq!(body, b"7: ${seq} ${channel}");
+    }
+
+    #[test]
+    fn test_header_captures_with_special_characters() {
+        let publisher = WebhookPublisher::new(
+            "http://example.com/hook".parse().unwrap(),
+            "${1}".to_string(),
+            HashMap::from([
+                ("x-capture", "${1}".to_string()),
+                ("x-channel", "${channel}".to_string()),
+            ]),
+            PublisherOptions::default(),
+        );
+        let render = |capture: &str| {
+            publisher
+                .render("#rust", &["".to_string(), capture.to_string()], &[])
+                .unwrap()
+        };
+
+        // Control characters would let a sender inject headers, so the header is dropped.
+        for capture in ["a\r\nx-injected: 1", "a\nb", "a\0b", "a\x7fb"] {
+            let (body, headers) = render(capture);
+            assert_eq!(body, capture.as_bytes());
+            assert!(!headers.contains_key("x-capture"));
+            assert!(!headers.contains_key("x-injected"));
+            assert_eq!(headers["x-channel"], "#rust");
+        }
+
+        // Non-ASCII is sent as the raw UTF-8 bytes, and tabs are allowed.
+        let (_, headers) = render("caf\u{e9}\tok");
+        assert_eq!(headers["x-capture"].as_bytes(), "caf\u{e9}\tok".as_bytes());
+    }
+
+    #[test]
+    fn test_appended_headers() {
+        let publisher = WebhookPublisher::new(
+            "http://example.com/hook".parse().unwrap(),
+            "${1}".to_string(),
+            HashMap::from([("cookie", "session=abc".to_string())]),
+            PublisherOptions {
+                appended_headers: vec![
+                    ("cookie", "route=${1}".to_string()),
+                    ("x-route", "${channel}".to_string()),
+                    ("x-route", "default".to_string()),
+                ],
+                ..PublisherOptions::default()
+            },
+        );
+
+        let (_, headers) = publisher
+            .render("#eu", &["".to_string(), "eu-1".to_string()], &[])
+            .unwrap();
+        let values = |name| -> Vec<_> { headers.get_all(name).iter().collect() };
+        assert_eq!(values("cookie"), ["session=abc", "route=eu-1"]);
+        assert_eq!(values("x-route"), ["#eu", "default"]);
+    }
+
+    #[test]
+    fn test_capture_headers() {
+        let publisher = WebhookPublisher::new(
+            "http://example.com/hook".parse().unwrap(),
+            "${0}".to_string(),
+            HashMap::new(),
+            PublisherOptions {
+                capture_headers: Some(CaptureHeaders {
+                    prefix: "X-Capture-".to_string(),
+                    max: 2,
+                }),
+                ..PublisherOptions::default()
+            },
+        );
+
+        let group = ["deploy api\r\nx-evil: 1", "api", "prod"].map(String::from);
+        let (_, headers) = publisher.render("#ops", &group, &[]).unwrap();
+        assert_eq!(headers["x-capture-0"], "deploy api  x-evil: 1");
+        assert_eq!(headers["x-capture-1"], "api");
+        assert!(!headers.contains_key("x-capture-2"));
+        assert!(!headers.contains_key("x-evil"));
+    }
+
+    #[test]
+    fn test_base64_body() {
+        let publisher = WebhookPublisher::new(
+            "http://example.com/hook".parse().unwrap(),
+            "AAE${1}/w==".to_string(),
+            HashMap::new(),
+            PublisherOptions {
+                body_encoding: BodyEncoding::Base64,
+                ..PublisherOptions::default()
+            },
+        );
+
+        let (body, headers) = publisher
+            .render("", &["".to_string(), "C".to_string()], &[])
+            .unwrap();
+        assert_eq!(body, vec![0x00, 0x01, 0x02, 0xff]);
+        assert_eq!(headers[CONTENT_TYPE], "application/octet-stream");
+
+        let invalid = publisher.render("", &["".to_string(), "!".to_string()], &[]);
+        assert!(matches!(invalid, Err(RenderError::InvalidBase64(_))));
+    }
+
+    #[test]
+    fn test_group_body_encodings() {
+        let publisher = |encoding| {
+            WebhookPublisher::new(
+                "http://example.com/hook".parse().unwrap(),
+                "unused ${5}".to_string(),
+                HashMap::new(),
+                PublisherOptions {
+                    body_encoding: encoding,
+                    on_missing_group: MissingGroup::Error,
+                    ..PublisherOptions::default()
+                },
+            )
+        };
+        let group = ["a b&c".to_string(), "\"d\"".to_string()];
+
+        let (body, headers) = publisher(BodyEncoding::Form)
+            .render("", &group, &[])
+            .unwrap();
+        assert_eq!(body, b"group_0=a+b%26c&group_1=%22d%22");
+        assert_eq!(headers[CONTENT_TYPE], "application/x-www-form-urlencoded");
+
+        let (body, headers) = publisher(BodyEncoding::Json)
+            .render("", &group, &[])
+            .unwrap();
+        assert_eq!(body, br#"["a b&c","\"d\""]"#);
+        assert_eq!(headers[CONTENT_TYPE], "application/json");
+    }
+
+    #[test]
+    fn test_multipart_body() {
+        let publisher = WebhookPublisher::new(
+            "http://example.com/hook".parse().unwrap(),
+            "unused".to_string(),
+            HashMap::new(),
+            PublisherOptions {
+                body_encoding: BodyEncoding::Multipart,
+                multipart_parts: vec![
+                    ("channel".to_string(), "${channel}".to_string()),
+                    ("message".to_string(), "${1} failed".to_string()),
+                ],
+                ..PublisherOptions::default()
+            },
+        );
+        let group = vec!["build api".to_string(), "api".to_string()];
+
+        let (body, headers) = publisher.render("#ci", &group, &[]).unwrap();
+        let content_type = headers[CONTENT_TYPE].to_str().unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/form-data; boundary=")
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            format!(
+                "--{b}\r\nContent-Disposition: form-data; name=\"channel\"\r\n\r\n#ci\r\n\
+                 --{b}\r\nContent-Disposition: form-data; name=\"message\"\r\n\r\napi failed\r\n\
+                 --{b}--\r\n",
+                b = boundary
+            )
+        );
+    }
+
+    #[test]
+    fn test_json_types() {
+        let publisher = WebhookPublisher::new(
+            "http://example.com/hook".parse().unwrap(),
+            String::new(),
+            HashMap::new(),
+            PublisherOptions {
+                body_encoding: BodyEncoding::Json,
+                json_types: HashMap::from([
+                    (1, JsonType::Number),
+                    (2, JsonType::Number),
+                    (3, JsonType::Bool),
+                    (4, JsonType::Bool),
+                ]),
+                ..PublisherOptions::default()
+            },
+        );
+        let group: Vec<String> = ["build 42", "42", "-1.5", "true", "yes", "7"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+
+        let (body, _) = publisher.render("", &group, &[]).unwrap();
+        assert_eq!(body, br#"["build 42",42,-1.5,true,"yes","7"]"#);
+
+        // Values that aren't valid JSON numbers stay strings.
+        let group = vec!["x".to_string(), "042".to_string(), "0x1F".to_string()];
+        let (body, _) = publisher.render("", &group, &[]).unwrap();
+        assert_eq!(body, br#"["x","042","0x1F"]"#);
+    }
+
+    #[test]
+    fn test_multi_match_mode() {
+        let publisher = |mode| {
+            WebhookPublisher::new(
+                "http://example.com/hook".parse().unwrap(),
+                r#"{"n": "${1}"}"#.to_string(),
+                HashMap::new(),
+                PublisherOptions {
+                    multi_match: mode,
+                    ..PublisherOptions::default()
+                },
+            )
+        };
+        let groups = || {
+            vec![
+                vec!["x1".to_string(), "1".to_string()],
+                vec!["x2".to_string(), "2".to_string()],
+            ]
+        };
+        let bodies = |mode| {
+            publisher(mode)
+                .dry_publish("#rust", groups())
+                .unwrap()
+                .into_iter()
+                .map(|r| r.body)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            bodies(MultiMatchMode::PerMatch),
+            vec![r#"{"n": "1"}"#, r#"{"n": "2"}"#]
+        );
+        assert_eq!(bodies(MultiMatchMode::FirstOnly), vec![r#"{"n": "1"}"#]);
+        assert_eq!(
+            bodies(MultiMatchMode::SingleArray),
+            vec![r#"[{"n": "1"},{"n": "2"}]"#]
+        );
+
+        let array = publisher(MultiMatchMode::SingleArray)
+            .dry_publish("#rust", groups())
+            .unwrap();
+        assert_eq!(array[0].headers["content-type"], "application/json");
+        assert!(publisher(MultiMatchMode::SingleArray)
+            .dry_publish("#rust", Vec::new())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_on_missing_group() {
+        let publisher = |missing| {
+            WebhookPublisher::new(
+                "http://example.com/hook".parse().unwrap(),
+                "${channel}: ${1} ${5} ${name} ${5}".to_string(),
+                HashMap::new(),
+                PublisherOptions {
+                    on_missing_group: missing,
+                    ..PublisherOptions::default()
+                },
+            )
+        };
+        let group = ["a".to_string(), "b".to_string()];
+
+        let (body, _) = publisher(MissingGroup::Leave)
+            .render("#rust", &group, &[])
+            .unwrap();
+        assert_eq!(body, b"#rust: b ${5} ${name} ${5}");
+
+        let failed = publisher(MissingGroup::Error).render("#rust", &group, &[]);
+        assert!(matches!(
+            failed,
+            Err(RenderError::UnresolvedPlaceholders(p)) if p == vec!["${5}", "${name}"]
+        ));
+
+        let (body, _) = publisher(MissingGroup::Empty)
+            .render("#rust", &group, &[])
+            .unwrap();
+        assert_eq!(body, b"#rust: b   ");
+
+        // A capture that looks like a placeholder isn't one.
+        let group = ["a", "${x}", "2", "3", "4", "5"].map(String::from);
+        let vars = [("name", "n".to_string())];
+        for missing in [MissingGroup::Error, MissingGroup::Empty] {
+            let (body, _) = publisher(missing).render("#rust", &group, &vars).unwrap();
+            assert_eq!(body, b"#rust: ${x} 5 n 5");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_deadline_sheds() {
+        let publisher = WebhookPublisher::new(
+            "http://example.com/hook".parse().unwrap(),
+            "${0}".to_string(),
+            HashMap::new(),
+            PublisherOptions {
+                publish_deadline: Some(Duration::ZERO),
+                ..PublisherOptions::default()
+            },
+        );
+
+        let err = publisher
+            .publish("#rust", vec![vec!["a".to_string()], vec!["b".to_string()]])
+            .await
+            .unwrap_err();
+
+        assert_eq!(publisher.shed_count(), 2);
+        assert_eq!(err.total, 2);
+        assert!(err.failures.iter().all(|e| matches!(e, RequestError::Shed)));
+    }
+
+    #[tokio::test]
+    async fn test_publish_deadline_counts_from_match() {
+        let publisher = WebhookPublisher::new(
+            "http://example.com/hook".parse().unwrap(),
+            "${0}".to_string(),
+            HashMap::new(),
+            PublisherOptions {
+                publish_deadline: Some(Duration::from_millis(20)),
+                ..PublisherOptions::default()
+            },
+        );
+
+        // Matched long enough ago that it went stale waiting to be published.
+        let matched_at = Instant::now();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let err = publisher
+            .publish_with_vars("#rust", vec![vec!["a".to_string()]], &[], matched_at)
+            .await
+            .unwrap_err();
+
+        assert_eq!(publisher.shed_count(), 1);
+        assert!(matches!(err.failures[..], [RequestError::Shed]));
+    }
+
+    #[test]
+    fn test_sign_body() {
+        let signing = signing_config("${body}");
+
+        assert_eq!(
+            signing.sign(b"hello", 1600000000),
+            "88aab3ede8d3adf94d26ab90d3bafd4a2083070c3bcce9c014ee04a443847c0b"
+        );
+    }
+
+    #[test]
+    fn test_sign_timestamp_and_body() {
+        let signing = signing_config("${timestamp}.${body}");
+
+        assert_eq!(
+            signing.sign(b"hello", 1600000000),
+            "8467897bfe066c439626cf886f905f0f1330dc715bc527f82a724835f911d943"
+        );
+    }
 }